@@ -1,3 +1,10 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
 use termion::event::Key;
 
 use crate::Coordinates;
@@ -8,54 +15,433 @@ pub struct CmdAction {
     pub pos: Coordinates,
     pub cur_line: usize,
     pub contents: Vec<Key>,
+    pub time: Instant,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Action {
     Insert,
     Delete,
+    // lines replaced as a whole (by `=`): these are the old lines, the
+    // contents are the new ones
+    Replace(Vec<String>),
 }
 
-#[derive(Default)]
 pub struct ActionStack {
     backward_stack: Vec<CmdAction>,
     forward_stack: Vec<CmdAction>,
+    clock: Box<dyn Fn() -> Instant>,
+    // the text was changed since `take_changed` was last asked
+    changed: bool,
+}
+
+impl Default for ActionStack {
+    fn default() -> Self {
+        Self {
+            backward_stack: vec![],
+            forward_stack: vec![],
+            clock: Box::new(Instant::now),
+            changed: false,
+        }
+    }
+}
+
+// argument of `:earlier` and `:later`
+#[derive(Debug, PartialEq, Eq)]
+pub enum UndoStep {
+    Count(usize),
+    Time(Duration),
+}
+
+impl UndoStep {
+    pub fn parse(arg: &str) -> Option<Self> {
+        if arg.is_empty() {
+            return Some(UndoStep::Count(1));
+        }
+        let unit = arg.chars().last().unwrap();
+        if unit.is_ascii_digit() {
+            return arg.parse().ok().map(UndoStep::Count);
+        }
+        let n: u64 = arg[..arg.len() - unit.len_utf8()].parse().ok()?;
+        let secs = match unit {
+            's' => n,
+            'm' => n * 60,
+            'h' => n * 60 * 60,
+            'd' => n * 60 * 60 * 24,
+            _ => return None,
+        };
+        Some(UndoStep::Time(Duration::from_secs(secs)))
+    }
+}
+
+// `:s/pattern/replacement/flags`, the pattern being plain text. Any char
+// may be used in place of `/`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Substitute {
+    pub pattern: String,
+    pub replacement: String,
+    // `g`, every match of a line instead of the first one
+    pub global: bool,
+    // `c`, ask before each replacement
+    pub confirm: bool,
+}
+
+impl Substitute {
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let mut chars = arg.chars();
+        let Some(delimiter) = chars.next().filter(|c| !c.is_alphanumeric()) else {
+            return Err("E146: Regular expressions can't be delimited by letters".to_string());
+        };
+        let mut parts = chars.as_str().splitn(3, delimiter);
+        let pattern = parts.next().unwrap_or_default();
+        let replacement = parts.next().unwrap_or_default();
+        let flags = parts.next().unwrap_or_default();
+        if pattern.is_empty() {
+            return Err("E35: No previous regular expression".to_string());
+        }
+        if let Some(flag) = flags.chars().find(|c| !matches!(c, 'g' | 'c')) {
+            return Err(format!("E488: Trailing characters: {flag}"));
+        }
+        Ok(Substitute {
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+            global: flags.contains('g'),
+            confirm: flags.contains('c'),
+        })
+    }
+
+    // char column of the first match in `line` at or after column `from`
+    pub fn find(&self, line: &str, from: usize) -> Option<usize> {
+        let start = line.char_indices().nth(from).map(|(i, _)| i)?;
+        let i = line[start..].find(&self.pattern)?;
+        Some(from + line[start..start + i].chars().count())
+    }
+
+    // `line` with the match at char column `col` replaced
+    pub fn replace_at(&self, line: &str, col: usize) -> String {
+        let head: String = line.chars().take(col).collect();
+        let tail: String = line
+            .chars()
+            .skip(col + self.pattern.chars().count())
+            .collect();
+        format!("{head}{}{tail}", self.replacement)
+    }
+
+    // `line` with its first or, when `global`, every match replaced
+    pub fn apply(&self, line: &str) -> String {
+        if self.global {
+            line.replace(&self.pattern, &self.replacement)
+        } else {
+            line.replacen(&self.pattern, &self.replacement, 1)
+        }
+    }
+}
+
+// lines an Ex command like `:3,7d` applies to, both from 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineRange {
+    pub first: usize,
+    pub last: usize,
+}
+
+impl LineRange {
+    // split the range off the start of `command`, `cur` being the line of
+    // the cursor and `len` the number of lines. Without a range the command
+    // is given back as it is
+    pub fn parse(command: &str, cur: usize, len: usize) -> (Option<Self>, &str) {
+        if let Some(rest) = command.strip_prefix('%') {
+            return (
+                Some(LineRange {
+                    first: 1,
+                    last: len,
+                }),
+                rest,
+            );
+        }
+        let Some((first, rest)) = parse_address(command, cur, len) else {
+            return (None, command);
+        };
+        let (last, rest) = match rest.strip_prefix(',') {
+            Some(rest) => parse_address(rest, cur, len).unwrap_or((first, rest)),
+            None => (first, rest),
+        };
+        let (first, last) = (first.min(last).max(1), first.max(last).max(1));
+        (Some(LineRange { first, last }), rest)
+    }
+}
+
+// a line number, `.` or `$` at the start of `s`, clamped to 0..=len, with
+// the rest of `s`
+pub fn parse_address(s: &str, cur: usize, len: usize) -> Option<(usize, &str)> {
+    if let Some(rest) = s.strip_prefix('.') {
+        return Some((cur, rest));
+    }
+    if let Some(rest) = s.strip_prefix('$') {
+        return Some((len, rest));
+    }
+    let digits = s.chars().take_while(|c| c.is_ascii_digit()).count();
+    let line: usize = s[..digits].parse().ok()?;
+    Some((line.min(len), &s[digits..]))
+}
+
+// the history of a file kept by `:w`, like the `undofile` of vim. It's
+// only trusted when the file still has the contents it was written with
+#[derive(Serialize, Deserialize)]
+struct UndoFile {
+    len: usize,
+    hash: u64,
+    backward: Vec<SavedAction>,
+    forward: Vec<SavedAction>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedAction {
+    action: Action,
+    pos: Coordinates,
+    cur_line: usize,
+    contents: Vec<SavedKey>,
+    // milliseconds between the action and the save, `Instant` itself
+    // means nothing to another run of the editor
+    age: u64,
+}
+
+// `Key` of termion can't be serialized
+#[derive(Serialize, Deserialize)]
+enum SavedKey {
+    Backspace,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    BackTab,
+    Delete,
+    Insert,
+    F(u8),
+    Char(char),
+    Alt(char),
+    Ctrl(char),
+    Null,
+    Esc,
+}
+
+impl From<Key> for SavedKey {
+    fn from(key: Key) -> Self {
+        match key {
+            Key::Backspace => SavedKey::Backspace,
+            Key::Left => SavedKey::Left,
+            Key::Right => SavedKey::Right,
+            Key::Up => SavedKey::Up,
+            Key::Down => SavedKey::Down,
+            Key::Home => SavedKey::Home,
+            Key::End => SavedKey::End,
+            Key::PageUp => SavedKey::PageUp,
+            Key::PageDown => SavedKey::PageDown,
+            Key::BackTab => SavedKey::BackTab,
+            Key::Delete => SavedKey::Delete,
+            Key::Insert => SavedKey::Insert,
+            Key::F(n) => SavedKey::F(n),
+            Key::Char(c) => SavedKey::Char(c),
+            Key::Alt(c) => SavedKey::Alt(c),
+            Key::Ctrl(c) => SavedKey::Ctrl(c),
+            Key::Esc => SavedKey::Esc,
+            _ => SavedKey::Null,
+        }
+    }
+}
+
+impl From<SavedKey> for Key {
+    fn from(key: SavedKey) -> Self {
+        match key {
+            SavedKey::Backspace => Key::Backspace,
+            SavedKey::Left => Key::Left,
+            SavedKey::Right => Key::Right,
+            SavedKey::Up => Key::Up,
+            SavedKey::Down => Key::Down,
+            SavedKey::Home => Key::Home,
+            SavedKey::End => Key::End,
+            SavedKey::PageUp => Key::PageUp,
+            SavedKey::PageDown => Key::PageDown,
+            SavedKey::BackTab => Key::BackTab,
+            SavedKey::Delete => Key::Delete,
+            SavedKey::Insert => Key::Insert,
+            SavedKey::F(n) => Key::F(n),
+            SavedKey::Char(c) => Key::Char(c),
+            SavedKey::Alt(c) => Key::Alt(c),
+            SavedKey::Ctrl(c) => Key::Ctrl(c),
+            SavedKey::Null => Key::Null,
+            SavedKey::Esc => Key::Esc,
+        }
+    }
+}
+
+// `dir/name` keeps its history in `dir/.name.vimrs-undo`
+pub fn undo_file_of(file_name: &str) -> PathBuf {
+    let path = Path::new(file_name);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.vimrs-undo"))
+}
+
+// FNV-1a, unlike `DefaultHasher` it's the same in every build
+fn hash_of(contents: &str) -> u64 {
+    contents.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
 }
 
 impl ActionStack {
-    pub fn current(&self) -> Option<CmdAction> {
-        let res = self.backward_stack.last().map(|action| action.clone());
-        res
+    // the history saved for `file_name`, or an empty one when there is
+    // none or the file was changed outside of the editor
+    pub fn from_undo_file(file_name: &str) -> Self {
+        let mut stack = ActionStack::default();
+        if let (Ok(contents), Ok(json)) = (
+            fs::read_to_string(file_name),
+            fs::read_to_string(undo_file_of(file_name)),
+        ) {
+            stack.restore(&json, &contents);
+        }
+        stack
     }
-    pub fn forward(&mut self) -> Option<CmdAction> {
-        let action = self.forward_stack.pop();
-        if action.is_none() {
-            return None;
+
+    // `contents` being what has just been written to `file_name`
+    pub fn write_undo_file(&self, file_name: &str, contents: &str) -> Result<(), String> {
+        let path = undo_file_of(file_name);
+        fs::write(&path, self.save(contents)).map_err(|err| {
+            format!(
+                "E828: Cannot open undo file for writing: {}: {err}",
+                path.display()
+            )
+        })
+    }
+
+    fn save(&self, contents: &str) -> String {
+        let now = (self.clock)();
+        let save_all = |actions: &[CmdAction]| {
+            actions
+                .iter()
+                .map(|action| SavedAction {
+                    action: action.action.clone(),
+                    pos: action.pos,
+                    cur_line: action.cur_line,
+                    contents: action.contents.iter().map(|&key| key.into()).collect(),
+                    age: now.saturating_duration_since(action.time).as_millis() as u64,
+                })
+                .collect()
+        };
+        let undo_file = UndoFile {
+            len: contents.len(),
+            hash: hash_of(contents),
+            backward: save_all(&self.backward_stack),
+            forward: save_all(&self.forward_stack),
+        };
+        serde_json::to_string(&undo_file).unwrap()
+    }
+
+    // false, leaving the history as it is, when `json` isn't the history
+    // of `contents`
+    fn restore(&mut self, json: &str, contents: &str) -> bool {
+        let Ok(undo_file) = serde_json::from_str::<UndoFile>(json) else {
+            return false;
+        };
+        if undo_file.len != contents.len() || undo_file.hash != hash_of(contents) {
+            return false;
         }
+        let now = (self.clock)();
+        let restore_all = |actions: Vec<SavedAction>| {
+            actions
+                .into_iter()
+                .map(|action| CmdAction {
+                    action: action.action,
+                    pos: action.pos,
+                    cur_line: action.cur_line,
+                    contents: action.contents.into_iter().map(Key::from).collect(),
+                    time: now
+                        .checked_sub(Duration::from_millis(action.age))
+                        .unwrap_or(now),
+                })
+                .collect()
+        };
+        self.backward_stack = restore_all(undo_file.backward);
+        self.forward_stack = restore_all(undo_file.forward);
+        true
+    }
 
-        let action = action.unwrap();
+    pub fn current(&self) -> Option<CmdAction> {
+        self.backward_stack.last().cloned()
+    }
+    pub fn forward(&mut self) -> Option<CmdAction> {
+        let action = self.forward_stack.pop()?;
+        self.changed |= !action.contents.is_empty();
         self.backward_stack.push(action.clone());
         Some(action)
     }
 
     pub fn backward(&mut self) -> Option<CmdAction> {
-        let action = self.backward_stack.pop();
-        if action.is_none() {
-            return None;
-        }
-
-        let action = action.unwrap();
+        let action = self.backward_stack.pop()?;
+        self.changed |= !action.contents.is_empty();
         self.forward_stack.push(action.clone());
         Some(action)
     }
 
+    // whether an action recorded, undone or redone since the last call
+    // changed the text
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    // undo every action made later than `duration` before the current state
+    pub fn backward_by(&mut self, duration: Duration) -> Vec<CmdAction> {
+        let mut actions = vec![];
+        let Some(current) = self.backward_stack.last().map(|a| a.time) else {
+            return actions;
+        };
+        while let Some(action) = self.backward_stack.last() {
+            if current.duration_since(action.time) >= duration {
+                break;
+            }
+            actions.extend(self.backward());
+        }
+        actions
+    }
+
+    // redo every action made within `duration` after the current state
+    pub fn forward_by(&mut self, duration: Duration) -> Vec<CmdAction> {
+        let mut actions = vec![];
+        let current = match (self.backward_stack.last(), self.forward_stack.last()) {
+            (Some(action), _) | (None, Some(action)) => action.time,
+            (None, None) => return actions,
+        };
+        while let Some(action) = self.forward_stack.last() {
+            if action.time.duration_since(current) > duration {
+                break;
+            }
+            actions.extend(self.forward());
+        }
+        actions
+    }
+
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Box<dyn Fn() -> Instant>) {
+        self.clock = clock;
+    }
+
     pub fn discard_key_on_top(&mut self) {
-        let idx = self.backward_stack.len() - 1;
-        self.backward_stack[idx].contents.pop();
+        if let Some(action) = self.backward_stack.last_mut() {
+            action.contents.pop();
+            self.changed = true;
+        }
+    }
+    pub fn discard_top(&mut self) {
+        self.backward_stack.pop();
     }
     pub fn append_key_to_top(&mut self, key: Key) {
-        let idx = self.backward_stack.len() - 1;
-        self.backward_stack[idx].contents.push(key)
+        if let Some(action) = self.backward_stack.last_mut() {
+            action.contents.push(key);
+            self.changed = true;
+        }
     }
     pub fn append_string_to_top(&mut self, s: String) {
         for c in s.chars() {
@@ -64,11 +450,129 @@ impl ActionStack {
     }
 
     pub fn add_action(&mut self, action: Action, cur_line: usize, pos: Coordinates) {
+        // the undone actions can't be redone on top of a new one
+        self.forward_stack.clear();
         self.backward_stack.push(CmdAction {
             action,
             cur_line,
             pos,
             contents: vec![],
+            time: (self.clock)(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_undo_step() {
+        assert_eq!(UndoStep::parse(""), Some(UndoStep::Count(1)));
+        assert_eq!(UndoStep::parse("3"), Some(UndoStep::Count(3)));
+        assert_eq!(
+            UndoStep::parse("30s"),
+            Some(UndoStep::Time(Duration::from_secs(30)))
+        );
+        assert_eq!(
+            UndoStep::parse("10m"),
+            Some(UndoStep::Time(Duration::from_secs(600)))
+        );
+        assert_eq!(
+            UndoStep::parse("2h"),
+            Some(UndoStep::Time(Duration::from_secs(7200)))
+        );
+        assert_eq!(UndoStep::parse("5x"), None);
+        assert_eq!(UndoStep::parse("m"), None);
+    }
+
+    #[test]
+    fn new_action_drops_redo() {
+        let pos = Coordinates { x: 1, y: 1 };
+        let mut stack = ActionStack::default();
+        stack.append_key_to_top(Key::Char('a'));
+        stack.discard_key_on_top();
+        stack.add_action(Action::Insert, 1, pos);
+        stack.add_action(Action::Delete, 1, pos);
+        assert!(stack.backward().is_some());
+        stack.add_action(Action::Insert, 1, pos);
+        assert!(stack.forward().is_none());
+        assert!(stack.backward().is_some());
+        assert!(stack.backward().is_some());
+        assert!(stack.backward().is_none());
+    }
+
+    #[test]
+    fn undo_file_round_trip() {
+        let pos = Coordinates { x: 3, y: 2 };
+        let mut stack = ActionStack::default();
+        stack.add_action(Action::Insert, 2, pos);
+        stack.append_string_to_top("ab".to_string());
+        stack.append_key_to_top(Key::Esc);
+        stack.add_action(Action::Replace(vec!["old".to_string()]), 1, pos);
+        stack.append_string_to_top("new".to_string());
+        stack.backward();
+        let json = stack.save("new\nab");
+
+        let mut restored = ActionStack::default();
+        assert!(!restored.restore(&json, "changed\nab"));
+        assert!(restored.current().is_none());
+        assert!(restored.restore(&json, "new\nab"));
+        let insert = restored.current().unwrap();
+        assert!(matches!(insert.action, Action::Insert));
+        assert_eq!((insert.pos, insert.cur_line), (pos, 2));
+        let keys = vec![Key::Char('a'), Key::Char('b'), Key::Esc];
+        assert_eq!(insert.contents, keys);
+        let replace = restored.forward().unwrap();
+        assert!(matches!(replace.action, Action::Replace(old) if old == ["old"]));
+        assert_eq!(replace.contents.len(), 3);
+        assert!(restored.forward().is_none());
+
+        assert_eq!(
+            undo_file_of("src/main.rs"),
+            PathBuf::from("src/.main.rs.vimrs-undo")
+        );
+    }
+
+    #[test]
+    fn parse_substitute() {
+        let sub = Substitute::parse("/foo/bar/gc").unwrap();
+        assert_eq!(sub.pattern, "foo");
+        assert_eq!(sub.replacement, "bar");
+        assert!(sub.global && sub.confirm);
+        let sub = Substitute::parse("#a/b#").unwrap();
+        assert_eq!(
+            (sub.pattern.as_str(), sub.replacement.as_str()),
+            ("a/b", "")
+        );
+        assert!(!sub.global && !sub.confirm);
+        assert!(Substitute::parse("/foo/bar/x").is_err());
+        assert!(Substitute::parse("//bar/").is_err());
+        assert!(Substitute::parse("afooabara").is_err());
+
+        let sub = Substitute::parse("/é/e/").unwrap();
+        assert_eq!(sub.find("aé bé", 0), Some(1));
+        assert_eq!(sub.find("aé bé", 2), Some(4));
+        assert_eq!(sub.find("aé bé", 5), None);
+        assert_eq!(sub.replace_at("aé bé", 4), "aé be");
+        assert_eq!(sub.apply("aé bé"), "ae bé");
+    }
+
+    #[test]
+    fn parse_line_range() {
+        let range = |first, last| Some(LineRange { first, last });
+        assert_eq!(LineRange::parse("3,7d", 1, 10), (range(3, 7), "d"));
+        assert_eq!(LineRange::parse(".,$d", 4, 10), (range(4, 10), "d"));
+        assert_eq!(
+            LineRange::parse("%normal A;", 4, 10),
+            (range(1, 10), "normal A;")
+        );
+        assert_eq!(LineRange::parse("5m10", 1, 10), (range(5, 5), "m10"));
+        assert_eq!(LineRange::parse("7,3d", 1, 10), (range(3, 7), "d"));
+        assert_eq!(LineRange::parse("0,30d", 1, 10), (range(1, 10), "d"));
+        assert_eq!(LineRange::parse("set wrap", 1, 10), (None, "set wrap"));
+        assert_eq!(parse_address("0", 3, 10), Some((0, "")));
+        assert_eq!(parse_address("$", 3, 10), Some((10, "")));
+        assert_eq!(parse_address("m", 3, 10), None);
+    }
+}
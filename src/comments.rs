@@ -0,0 +1,35 @@
+use std::path::Path;
+
+// line comment leader of the language `file_name` is written in
+pub fn comment_leader(file_name: &str) -> Option<&'static str> {
+    let path = Path::new(file_name);
+    // files like `Makefile` have no extension, so go by their name
+    let name = path.extension().or(path.file_name())?.to_str()?;
+    let leader = match name {
+        "rs" | "c" | "h" | "cc" | "cpp" | "hpp" | "go" | "java" | "js" | "ts" | "kt" | "swift"
+        | "scala" | "cs" | "zig" | "dart" => "//",
+        "py" | "sh" | "bash" | "zsh" | "fish" | "rb" | "pl" | "r" | "toml" | "yaml" | "yml"
+        | "conf" | "Makefile" | "Dockerfile" => "#",
+        "lua" | "sql" | "hs" | "elm" => "--",
+        "vim" => "\"",
+        "tex" | "erl" => "%",
+        "el" | "lisp" | "clj" | "scm" => ";",
+        _ => return None,
+    };
+    Some(leader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leader_by_file_type() {
+        assert_eq!(comment_leader("src/main.rs"), Some("//"));
+        assert_eq!(comment_leader("setup.py"), Some("#"));
+        assert_eq!(comment_leader("init.lua"), Some("--"));
+        assert_eq!(comment_leader("Makefile"), Some("#"));
+        assert_eq!(comment_leader("notes.txt"), None);
+        assert_eq!(comment_leader("test_file"), None);
+    }
+}
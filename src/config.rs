@@ -0,0 +1,116 @@
+use std::{env, fs, path::PathBuf};
+
+// keys of the config file and the `:set` options they stand for
+const OPTIONS: [(&str, &str); 17] = [
+    ("tab_width", "tabstop"),
+    ("expandtab", "expandtab"),
+    ("number", "number"),
+    ("relativenumber", "relativenumber"),
+    ("scrolloff", "scrolloff"),
+    ("colorcolumn", "colorcolumn"),
+    ("textwidth", "textwidth"),
+    ("wrap", "wrap"),
+    ("list", "list"),
+    ("autopairs", "autopairs"),
+    ("incsearch", "incsearch"),
+    ("ignorecase", "ignorecase"),
+    ("smartcase", "smartcase"),
+    ("equalprg", "equalprg"),
+    ("trim_on_write", "trimonwrite"),
+    ("debug_log", "debuglog"),
+    ("color_mode", "colormode"),
+];
+
+// settings read from `~/.vimrsrc` at startup, one `key=value` per line
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    // arguments of `:set`, as `tabstop=2` or `noexpandtab`
+    pub options: Vec<String>,
+    pub theme: Option<String>,
+    // lines that were ignored, and why
+    pub warnings: Vec<String>,
+}
+
+impl Config {
+    // a missing file leaves every setting at its default
+    pub fn load() -> Self {
+        let Some(path) = env::var_os("HOME").map(|home| PathBuf::from(home).join(".vimrsrc"))
+        else {
+            return Config::default();
+        };
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents),
+            Err(_) => Config::default(),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        let mut config = Config::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                config
+                    .warnings
+                    .push(format!("line {}: expected key=value: {line}", i + 1));
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if key == "theme" {
+                config.theme = Some(value.to_string());
+                continue;
+            }
+            let Some((_, option)) = OPTIONS.iter().find(|(name, _)| *name == key) else {
+                config
+                    .warnings
+                    .push(format!("line {}: unknown key: {key}", i + 1));
+                continue;
+            };
+            config.options.push(match value {
+                "true" => option.to_string(),
+                "false" => format!("no{option}"),
+                _ => format!("{option}={value}"),
+            });
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            "# editor settings\n\
+             tab_width=2\n\
+             number = true\n\
+             theme=base16-ocean.dark\n\
+             \n\
+             scrolloff=5\n\
+             expandtab=false\n\
+             colour=red\n\
+             wrap\n",
+        );
+        assert_eq!(
+            config,
+            Config {
+                options: vec![
+                    "tabstop=2".to_string(),
+                    "number".to_string(),
+                    "scrolloff=5".to_string(),
+                    "noexpandtab".to_string(),
+                ],
+                theme: Some("base16-ocean.dark".to_string()),
+                warnings: vec![
+                    "line 8: unknown key: colour".to_string(),
+                    "line 9: expected key=value: wrap".to_string(),
+                ],
+            }
+        );
+        assert_eq!(Config::parse(""), Config::default());
+    }
+}
@@ -0,0 +1,33 @@
+use std::{fmt, io};
+
+// failures of the editor's own I/O. They are shown in the status bar, but
+// for those of the terminal which end the editor
+#[derive(Debug)]
+pub enum EditorError {
+    Read(String, io::Error),
+    Write(String, io::Error),
+    Terminal(io::Error),
+    // already worded as a message, like the errors of the undo file
+    Message(String),
+}
+
+impl fmt::Display for EditorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditorError::Read(file_name, err) => {
+                write!(f, "E484: Can't open file {file_name}: {err}")
+            }
+            EditorError::Write(file_name, err) => {
+                write!(f, "E212: Can't open file for writing: {file_name}: {err}")
+            }
+            EditorError::Terminal(err) => write!(f, "terminal error: {err}"),
+            EditorError::Message(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl From<String> for EditorError {
+    fn from(message: String) -> Self {
+        EditorError::Message(message)
+    }
+}
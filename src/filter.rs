@@ -0,0 +1,90 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    thread,
+};
+
+// pipe `input` through the shell command `prg` (`equalprg` or `:!`) and return
+// what it prints; the buffer is left alone when the command fails
+pub fn pipe(prg: &str, input: &str) -> Result<String, String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(prg)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("E282: Cannot execute {prg}: {err}"))?;
+    // written from another thread so that a command printing a lot before
+    // reading all of its input can't block on a full pipe
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .map_err(|err| format!("E282: Cannot execute {prg}: {err}"))?;
+    let _ = writer.join();
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.lines().next().unwrap_or_default();
+        return Err(format!("{prg}: {} {reason}", output.status));
+    }
+    String::from_utf8(output.stdout).map_err(|_| format!("{prg}: output is not valid UTF-8"))
+}
+
+// the built-in `=`: indent every line by `unit` once per bracket left open
+// above it, `above` being the lines before the first one
+pub fn indent_lines(above: &[String], lines: &[String], unit: &str) -> Vec<String> {
+    let mut depth = above.iter().fold(0, |depth, line| balance(depth, line));
+    lines
+        .iter()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            let closing = trimmed.starts_with([')', ']', '}']);
+            let indent = if trimmed.is_empty() {
+                0
+            } else if closing {
+                depth.saturating_sub(1)
+            } else {
+                depth
+            };
+            depth = balance(depth, line);
+            unit.repeat(indent) + trimmed
+        })
+        .collect()
+}
+
+fn balance(depth: usize, line: &str) -> usize {
+    line.chars().fold(depth, |depth, c| match c {
+        '(' | '[' | '{' => depth + 1,
+        ')' | ']' | '}' => depth.saturating_sub(1),
+        _ => depth,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipe_through_command() {
+        assert_eq!(pipe("tr a-z A-Z", "fn main\n").unwrap(), "FN MAIN\n");
+        assert!(pipe("exit 3", "fn main\n").is_err());
+    }
+
+    #[test]
+    fn indent_by_brackets() {
+        let lines: Vec<String> = ["fn main() {", "let a = [", "1,", "];", "", "}"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            indent_lines(&[], &lines, "  "),
+            vec!["fn main() {", "  let a = [", "    1,", "  ];", "", "}"]
+        );
+        assert_eq!(
+            indent_lines(&lines[..2], &lines[2..4], "\t"),
+            vec!["\t\t1,", "\t];"]
+        );
+    }
+}
@@ -0,0 +1,85 @@
+// width of the whitespace `line` starts with, a tab going to the next stop
+fn indent_width(line: &str, tab_width: usize) -> usize {
+    let mut width = 0;
+    for c in line.chars() {
+        match c {
+            ' ' => width += 1,
+            '\t' => width += tab_width - width % tab_width,
+            _ => break,
+        }
+    }
+    width
+}
+
+// `foldmethod=indent`: a fold for every run of lines indented at least as
+// much as the first of them, nested runs getting folds of their own. Blank
+// lines belong to the run around them but do not end it, and runs of a single
+// line are not folded. The folds are sorted by their first line
+pub fn indent_folds(lines: &[String], tab_width: usize) -> Vec<(usize, usize)> {
+    let mut folds = vec![];
+    // first line and indent of the runs still open, the deepest last
+    let mut open: Vec<(usize, usize)> = vec![];
+    let mut last_text = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let width = indent_width(line, tab_width);
+        let mut start = i;
+        while let Some(&(first, indent)) = open.last() {
+            if indent <= width {
+                break;
+            }
+            open.pop();
+            folds.push((first, last_text));
+            // a run less deep started with the one that was just closed
+            start = first;
+        }
+        if width > 0 && open.last().is_none_or(|&(_, indent)| indent < width) {
+            open.push((start, width));
+        }
+        last_text = i;
+    }
+    while let Some((first, _)) = open.pop() {
+        folds.push((first, last_text));
+    }
+    folds.retain(|(first, last)| first < last);
+    folds.sort();
+    folds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indent_fold_ranges() {
+        let lines: Vec<String> = [
+            "fn main() {",
+            "    let x = 1;",
+            "    if x > 0 {",
+            "        one();",
+            "",
+            "        two();",
+            "    }",
+            "}",
+            "",
+            "fn f() {",
+            "\tg();",
+            "\th();",
+            "}",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(indent_folds(&lines, 4), vec![(1, 6), (3, 5), (10, 11)]);
+
+        // a deeper first line still starts the fold of the lines after it
+        let lines: Vec<String> = ["a", "        b", "    c", "d"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(indent_folds(&lines, 4), vec![(1, 2)]);
+        assert_eq!(indent_folds(&[], 4), vec![]);
+    }
+}
@@ -0,0 +1,81 @@
+// the whitespace every non-blank line of `lines` starts with
+pub fn common_indent(lines: &[String]) -> String {
+    let mut lines = lines.iter().filter(|line| !line.trim().is_empty());
+    let Some(first) = lines.next() else {
+        return String::new();
+    };
+    let mut indent: String = first
+        .chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .collect();
+    for line in lines {
+        let len = indent
+            .chars()
+            .zip(line.chars())
+            .take_while(|(a, b)| a == b)
+            .count();
+        indent = indent.chars().take(len).collect();
+    }
+    indent
+}
+
+// `gq`: join the words of each paragraph of `lines` and split them again
+// into lines of at most `width` chars, each starting with `indent`. Blank
+// lines separate paragraphs and are kept, a word longer than `width` gets a
+// line of its own
+pub fn reflow(lines: &[String], width: usize, indent: &str) -> Vec<String> {
+    let mut reflowed = vec![];
+    let mut current = String::new();
+    for line in lines {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                reflowed.push(std::mem::take(&mut current));
+            }
+            reflowed.push(String::new());
+            continue;
+        }
+        for word in line.split_whitespace() {
+            if current.is_empty() {
+                current = format!("{indent}{word}");
+            } else if current.chars().count() + 1 + word.chars().count() <= width {
+                current.push(' ');
+                current.push_str(word);
+            } else {
+                reflowed.push(std::mem::replace(&mut current, format!("{indent}{word}")));
+            }
+        }
+    }
+    if !current.is_empty() {
+        reflowed.push(current);
+    }
+    reflowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &[&str]) -> Vec<String> {
+        text.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn reflow_paragraphs() {
+        let long = lines(&["the quick brown fox jumps over the lazy dog"]);
+        let reflowed = reflow(&long, 16, "");
+        assert_eq!(
+            reflowed,
+            vec!["the quick brown", "fox jumps over", "the lazy dog"]
+        );
+        assert!(reflowed.iter().all(|line| line.chars().count() <= 16));
+
+        let text = lines(&["  one two", "    three", "", "  four fivesixseven"]);
+        let indent = common_indent(&text);
+        assert_eq!(indent, "  ");
+        assert_eq!(
+            reflow(&text, 10, &indent),
+            vec!["  one two", "  three", "", "  four", "  fivesixseven"]
+        );
+        assert_eq!(common_indent(&lines(&["", "\tx", "  y"])), "");
+    }
+}
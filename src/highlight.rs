@@ -1,32 +1,475 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    env,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
 use syntect::{
-    easy::HighlightLines, highlighting::ThemeSet, parsing::SyntaxSet,
+    highlighting::{Color, HighlightIterator, HighlightState, Highlighter, Style, ThemeSet},
+    parsing::{ParseState, ScopeStack, SyntaxReference, SyntaxSet},
     util::as_24_bit_terminal_escaped,
 };
 
 pub struct HighLighter {
     ps: SyntaxSet,
     ts: ThemeSet,
-    extension: String,
+    // None for plain text, which is shown as it is
+    syntax: Option<SyntaxReference>,
+    theme: String,
+    color_mode: ColorMode,
+    cache: Vec<CachedLine>,
+}
+
+// colors the terminal can show, the RGB ones of the themes are turned into
+// the nearest of the 256 or 16 colors when it can't show them all
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMode {
+    Truecolor,
+    Ansi256,
+    Ansi16,
+}
+
+impl ColorMode {
+    pub fn detect() -> Self {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            ColorMode::Truecolor
+        } else if term.contains("256color") {
+            ColorMode::Ansi256
+        } else {
+            ColorMode::Ansi16
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "truecolor" => Some(ColorMode::Truecolor),
+            "256" => Some(ColorMode::Ansi256),
+            "16" => Some(ColorMode::Ansi16),
+            _ => None,
+        }
+    }
+
+    fn escape(self, color: Color) -> String {
+        let Color { r, g, b, .. } = color;
+        match self {
+            ColorMode::Truecolor => format!("\x1b[38;2;{r};{g};{b}m"),
+            ColorMode::Ansi256 => format!("\x1b[38;5;{}m", ansi256(r, g, b)),
+            ColorMode::Ansi16 => format!("\x1b[{}m", ansi16(r, g, b)),
+        }
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+// index of the nearest color of the 6x6x6 cube or of the 24 grays of the
+// 256-color palette
+pub fn ansi256(r: u8, g: u8, b: u8) -> u8 {
+    const STEPS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let level = |v: u8| match v {
+        0..=47 => 0,
+        48..=114 => 1,
+        _ => (v - 35) / 40,
+    };
+    let (lr, lg, lb) = (level(r), level(g), level(b));
+    let cube = (STEPS[lr as usize], STEPS[lg as usize], STEPS[lb as usize]);
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_index = (average.saturating_sub(3) / 10).min(23) as u8;
+    let gray = 8 + 10 * gray_index;
+    if distance((r, g, b), (gray, gray, gray)) < distance((r, g, b), cube) {
+        232 + gray_index
+    } else {
+        16 + 36 * lr + 6 * lg + lb
+    }
+}
+
+// SGR code of the nearest of the 16 colors, as xterm shows them
+pub fn ansi16(r: u8, g: u8, b: u8) -> u8 {
+    const PALETTE: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (205, 0, 0),
+        (0, 205, 0),
+        (205, 205, 0),
+        (0, 0, 238),
+        (205, 0, 205),
+        (0, 205, 205),
+        (229, 229, 229),
+        (127, 127, 127),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (92, 92, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+    let nearest = (0..16)
+        .min_by_key(|&i| distance((r, g, b), PALETTE[i]))
+        .unwrap() as u8;
+    if nearest < 8 {
+        30 + nearest
+    } else {
+        90 + nearest - 8
+    }
+}
+
+// highlight result of a single line, together with the parser state
+// right after that line, which is the start state of the next line
+struct CachedLine {
+    hash: u64,
+    output: String,
+    parse_state: ParseState,
+    highlight_state: HighlightState,
 }
 
 impl HighLighter {
     pub fn new(name: &str) -> Self {
-        let ps = SyntaxSet::load_defaults_newlines();
-        let ts = ThemeSet::load_defaults();
-        let split: Vec<&str> = name.split(".").collect();
-        assert!(split.len() > 1);
+        let dir = env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/vimrs"));
+        Self::with_dir(name, dir.as_deref())
+    }
+
+    // the syntaxes and themes of syntect, and those of the `syntaxes` and
+    // `themes` folders of `dir`
+    fn with_dir(name: &str, dir: Option<&Path>) -> Self {
+        let (ps, ts) = Self::load_sets(dir);
+        let path = Path::new(name);
+        // files like `Makefile` are listed by name in the syntax definitions
+        let syntax = path
+            .extension()
+            .or(path.file_name())
+            .and_then(|ext| ps.find_syntax_by_extension(&ext.to_string_lossy()))
+            .filter(|syntax| syntax.name != ps.find_syntax_plain_text().name)
+            .cloned();
         Self {
             ps,
-            extension: split.last().unwrap().to_string(),
+            syntax,
             ts,
+            theme: "base16-ocean.dark".to_string(),
+            color_mode: ColorMode::detect(),
+            cache: vec![],
+        }
+    }
+
+    // for files the name tells nothing about, pick the syntax from their
+    // first line, as `#!/bin/sh`
+    pub fn detect_from_first_line(&mut self, line: &str) {
+        if self.syntax.is_some() {
+            return;
+        }
+        self.syntax = self
+            .ps
+            .find_syntax_by_first_line(line)
+            .filter(|syntax| syntax.name != self.ps.find_syntax_plain_text().name)
+            .cloned();
+        self.cache.clear();
+    }
+
+    // missing folders, and files that can't be loaded, are left out
+    fn load_sets(dir: Option<&Path>) -> (SyntaxSet, ThemeSet) {
+        let mut ps = SyntaxSet::load_defaults_newlines();
+        let mut ts = ThemeSet::load_defaults();
+        let Some(dir) = dir else {
+            return (ps, ts);
+        };
+        let _ = ts.add_from_folder(dir.join("themes"));
+        let syntaxes = dir.join("syntaxes");
+        if syntaxes.is_dir() {
+            let mut builder = ps.clone().into_builder();
+            if builder.add_from_folder(&syntaxes, true).is_ok() {
+                ps = builder.build();
+            }
+        }
+        (ps, ts)
+    }
+
+    // the name of the detected syntax in lower case, as `rust`
+    pub fn file_type(&self) -> Option<String> {
+        self.syntax
+            .as_ref()
+            .map(|syntax| syntax.name.to_lowercase())
+    }
+
+    pub fn themes(&self) -> Vec<String> {
+        self.ts.themes.keys().cloned().collect()
+    }
+
+    pub fn set_theme(&mut self, name: &str) -> Result<(), String> {
+        if !self.ts.themes.contains_key(name) {
+            return Err(format!("E185: Cannot find color scheme '{name}'"));
+        }
+        self.theme = name.to_string();
+        self.cache.clear();
+        Ok(())
+    }
+
+    pub fn set_color_mode(&mut self, mode: ColorMode) {
+        self.color_mode = mode;
+        self.cache.clear();
+    }
+
+    // the theme and color mode of `other`, for the buffers opened later
+    pub fn copy_settings(&mut self, other: &HighLighter) {
+        self.theme = other.theme.clone();
+        self.set_color_mode(other.color_mode);
+    }
+
+    // the escapes of the foreground colors of `ranges`, the same as
+    // `as_24_bit_terminal_escaped` gives for truecolor
+    fn escaped(&self, ranges: &[(Style, &str)]) -> String {
+        if self.color_mode == ColorMode::Truecolor {
+            return as_24_bit_terminal_escaped(ranges, false);
         }
+        ranges
+            .iter()
+            .map(|(style, text)| self.color_mode.escape(style.foreground) + text)
+            .collect()
+    }
+
+    // lines are expected to be highlighted in order, starting from line 0,
+    // since the parse state of a line depends on all the lines before it
+    pub fn highlight_line(&mut self, index: usize, line: &str) -> String {
+        let hash = Self::hash_of(line);
+        if let Some(cached) = self.cache.get(index) {
+            if cached.hash == hash {
+                return cached.output.clone();
+            }
+        }
+
+        let Some(syntax) = &self.syntax else {
+            return line.to_string();
+        };
+        let theme = &self.ts.themes[&self.theme];
+        let highlighter = Highlighter::new(theme);
+        let (mut parse_state, mut highlight_state) = match index.checked_sub(1) {
+            Some(prev) if prev < self.cache.len() => (
+                self.cache[prev].parse_state.clone(),
+                self.cache[prev].highlight_state.clone(),
+            ),
+            _ => (
+                ParseState::new(syntax),
+                HighlightState::new(&highlighter, ScopeStack::new()),
+            ),
+        };
+
+        // syntaxes are loaded with newlines, so feed the line with its '\n'
+        let line = format!("{line}\n");
+        let ops = parse_state.parse_line(&line, &self.ps).unwrap();
+        let ranges: Vec<_> =
+            HighlightIterator::new(&mut highlight_state, &ops, &line, &highlighter).collect();
+        let mut output = self.escaped(&ranges);
+        if output.ends_with('\n') {
+            output.pop();
+        }
+
+        let cached = CachedLine {
+            hash,
+            output: output.clone(),
+            parse_state,
+            highlight_state,
+        };
+        if index < self.cache.len() {
+            // a multi-line scope (string, comment) opened or closed on this
+            // line, so every line below it has to be highlighted again
+            if self.cache[index].parse_state != cached.parse_state
+                || self.cache[index].highlight_state != cached.highlight_state
+            {
+                self.cache.truncate(index + 1);
+            }
+            self.cache[index] = cached;
+        } else {
+            self.cache.truncate(index);
+            self.cache.push(cached);
+        }
+        output
+    }
+
+    fn hash_of(line: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        line.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn highlight_all(highlighter: &mut HighLighter, lines: &[&str]) -> Vec<String> {
+        lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| highlighter.highlight_line(i, line))
+            .collect()
+    }
+
+    #[test]
+    fn fallback_to_plain_text() {
+        let mut highlighter = HighLighter::new("Makefile");
+        highlighter.highlight_line(0, "all: main");
+
+        let mut highlighter = HighLighter::new("LICENSE");
+        assert!(highlighter.syntax.is_none());
+        assert_eq!(highlighter.file_type(), None);
+        assert_eq!(highlighter.highlight_line(0, "MIT License"), "MIT License");
+
+        let mut highlighter = HighLighter::new("notes.unknownext");
+        assert!(highlighter.syntax.is_none());
+        assert_eq!(
+            highlighter.highlight_line(0, "fn main() {}"),
+            "fn main() {}"
+        );
+    }
+
+    #[test]
+    fn detect_from_first_line() {
+        let mut highlighter = HighLighter::new("script");
+        highlighter.detect_from_first_line("#!/usr/bin/env python3");
+        assert_eq!(highlighter.file_type().as_deref(), Some("python"));
+
+        let mut highlighter = HighLighter::new("run");
+        highlighter.detect_from_first_line("#!/bin/bash");
+        assert_eq!(
+            highlighter.file_type().as_deref(),
+            Some("bourne again shell (bash)")
+        );
+
+        // the name wins over the first line
+        let mut highlighter = HighLighter::new("test.rs");
+        highlighter.detect_from_first_line("#!/bin/bash");
+        assert_eq!(highlighter.file_type().as_deref(), Some("rust"));
+
+        let mut highlighter = HighLighter::new("notes");
+        highlighter.detect_from_first_line("hello");
+        assert_eq!(highlighter.file_type(), None);
     }
 
-    pub fn highlight_line(&self, line: &str) -> String {
-        let syntax = self.ps.find_syntax_by_extension(&self.extension).unwrap();
-        let theme = self.ts.themes["base16-ocean.dark"].clone();
-        let mut h = HighlightLines::new(syntax, &theme);
-        let ranges = h.highlight_line(line, &self.ps).unwrap();
-        as_24_bit_terminal_escaped(&ranges[..], false)
+    #[test]
+    fn set_theme() {
+        let mut highlighter = HighLighter::new("test.rs");
+        assert_eq!(highlighter.file_type().as_deref(), Some("rust"));
+        let before = highlighter.highlight_line(0, "fn main() {}");
+        assert!(highlighter.set_theme("InspiredGitHub").is_ok());
+        assert_ne!(highlighter.highlight_line(0, "fn main() {}"), before);
+        assert!(highlighter.set_theme("no-such-theme").is_err());
+        assert_eq!(highlighter.theme, "InspiredGitHub");
+    }
+
+    #[test]
+    fn load_from_dir() {
+        let dir = std::env::temp_dir().join("vim_rs_highlight_test");
+        std::fs::create_dir_all(dir.join("themes")).unwrap();
+        std::fs::create_dir_all(dir.join("syntaxes")).unwrap();
+        std::fs::write(
+            dir.join("themes/red.tmTheme"),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>name</key>
+    <string>Red</string>
+    <key>settings</key>
+    <array>
+        <dict>
+            <key>settings</key>
+            <dict>
+                <key>foreground</key>
+                <string>#FF0000</string>
+            </dict>
+        </dict>
+    </array>
+</dict>
+</plist>
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("syntaxes/notes.sublime-syntax"),
+            "%YAML 1.2\n---\nname: Notes\nfile_extensions: [notes]\nscope: text.notes\n\
+             contexts:\n  main:\n    - match: 'TODO'\n      scope: keyword.notes\n",
+        )
+        .unwrap();
+
+        let mut highlighter = HighLighter::with_dir("todo.notes", Some(&dir));
+        highlighter.set_color_mode(ColorMode::Truecolor);
+        assert_eq!(highlighter.file_type().as_deref(), Some("notes"));
+        assert!(highlighter.set_theme("red").is_ok());
+        assert!(highlighter.set_theme("InspiredGitHub").is_ok());
+        assert!(highlighter.set_theme("red").is_ok());
+        let line = highlighter.highlight_line(0, "TODO: tests");
+        assert!(line.contains("\x1b[38;2;255;0;0m"), "{line:?}");
+
+        // the defaults are kept, and a missing folder is ignored
+        let highlighter = HighLighter::with_dir("test.rs", Some(&dir.join("missing")));
+        assert_eq!(highlighter.file_type().as_deref(), Some("rust"));
+        assert!(!highlighter.themes().contains(&"red".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn downsample_colors() {
+        assert_eq!(ansi256(255, 0, 0), 196);
+        assert_eq!(ansi256(0, 0, 0), 16);
+        assert_eq!(ansi256(128, 128, 128), 244);
+        assert_eq!(ansi256(95, 135, 175), 67);
+        assert_eq!(ansi16(255, 0, 0), 91);
+        assert_eq!(ansi16(200, 10, 10), 31);
+        assert_eq!(ansi16(250, 250, 250), 97);
+
+        let mut highlighter = HighLighter::new("test.rs");
+        highlighter.set_color_mode(ColorMode::Ansi256);
+        let line = highlighter.highlight_line(0, "fn main() {}");
+        assert!(line.contains("\x1b[38;5;") && !line.contains("\x1b[38;2;"));
+        highlighter.set_color_mode(ColorMode::Ansi16);
+        let line = highlighter.highlight_line(0, "fn main() {}");
+        assert!(!line.contains("\x1b[38;"), "{line:?}");
+        assert_eq!(ColorMode::parse("256"), Some(ColorMode::Ansi256));
+        assert_eq!(ColorMode::parse("88"), None);
+
+        let mut other = HighLighter::new("other.rs");
+        other.copy_settings(&highlighter);
+        assert_eq!(other.color_mode, ColorMode::Ansi16);
+        assert_eq!(other.highlight_line(0, "fn main() {}"), line);
+    }
+
+    #[test]
+    fn reuse_cache_for_unchanged_lines() {
+        let mut highlighter = HighLighter::new("test.rs");
+        let lines = vec!["fn main() {", "    let a = 1;", "}"];
+        let first = highlight_all(&mut highlighter, &lines);
+        assert_eq!(highlighter.cache.len(), 3);
+
+        // editing line 1 doesn't change the parse state after it,
+        // so the cached result of line 2 is kept
+        highlighter.highlight_line(0, "fn main() {");
+        highlighter.highlight_line(1, "    let b = 2;");
+        assert_eq!(highlighter.cache.len(), 3);
+        assert_eq!(
+            highlighter.cache[1].hash,
+            HighLighter::hash_of("    let b = 2;")
+        );
+        assert_eq!(highlighter.highlight_line(2, "}"), first[2]);
+    }
+
+    #[test]
+    fn invalidate_lines_below_multi_line_scope() {
+        let mut highlighter = HighLighter::new("test.rs");
+        let lines = vec!["let a = 1;", "let b = 2;", "let c = 3;"];
+        let first = highlight_all(&mut highlighter, &lines);
+
+        // opening a block comment changes how the lines below are highlighted
+        highlighter.highlight_line(0, "/* let a = 1;");
+        assert_eq!(highlighter.cache.len(), 1);
+        let lines = vec!["/* let a = 1;", "let b = 2;", "let c = 3;"];
+        let second = highlight_all(&mut highlighter, &lines);
+        assert_ne!(first[1], second[1]);
+        assert_ne!(first[2], second[2]);
+
+        let lines = vec!["let a = 1;", "let b = 2;", "let c = 3;"];
+        let third = highlight_all(&mut highlighter, &lines);
+        assert_eq!(first, third);
     }
 }
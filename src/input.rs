@@ -0,0 +1,31 @@
+use std::io::Read;
+
+use termion::{event::Key, input::Keys};
+
+// where the editor reads its keys from, the terminal by default, but a
+// script in tests or a socket for remote control work just as well
+pub trait KeySource {
+    // None means there is no more input
+    fn next_key(&mut self) -> Option<Key>;
+
+    // (columns, rows) of the terminal the keys come from, if there is one
+    fn terminal_size(&self) -> Option<(u16, u16)> {
+        None
+    }
+}
+
+impl<R: Read> KeySource for Keys<R> {
+    fn next_key(&mut self) -> Option<Key> {
+        self.next().and_then(|key| key.ok())
+    }
+
+    fn terminal_size(&self) -> Option<(u16, u16)> {
+        termion::terminal_size().ok()
+    }
+}
+
+impl KeySource for std::vec::IntoIter<Key> {
+    fn next_key(&mut self) -> Option<Key> {
+        self.next()
+    }
+}
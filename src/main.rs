@@ -1,17 +1,38 @@
 mod command;
+mod comments;
+mod config;
+mod error;
+mod filter;
+mod fold;
+mod format;
 mod highlight;
+mod input;
 mod mode;
+mod positions;
+mod recent;
+mod register;
+mod session;
+mod spell;
 mod task;
 mod text;
 
 use crate::mode::Mode;
-use command::{Action, ActionStack, CmdAction};
-use highlight::HighLighter;
+use command::{parse_address, Action, ActionStack, CmdAction, LineRange, Substitute, UndoStep};
+use config::Config;
+use error::EditorError;
+use highlight::{ColorMode, HighLighter};
+use input::KeySource;
+use register::{Register, Registers};
+use serde::{Deserialize, Serialize};
+use session::{Session, SessionBuffer};
+use spell::Spell;
 use std::{
     env::args,
-    fmt::write,
-    fs,
-    io::{stderr, stdin, stdout, BufWriter, Write},
+    fmt,
+    fs::{self, OpenOptions},
+    io::{stdin, stdout, BufWriter, ErrorKind, Write},
+    ops::Range,
+    path::{Path, PathBuf},
 };
 use task::Task;
 use termion::{
@@ -22,21 +43,31 @@ use termion::{
     screen::AlternateScreen,
     style,
 };
-use text::Text;
+use text::{LineEnding, Text};
+use unicode_width::UnicodeWidthChar;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Coordinates {
     pub x: usize,
     pub y: usize,
 }
 
-#[allow(dead_code)]
+#[derive(Debug, PartialEq, Eq)]
 enum CursorStyle {
     Bar,
     Block,
     Underline,
 }
 
+// the cursor shown in each mode, a bar where text is typed
+fn cursor_style_for(mode: Mode) -> CursorStyle {
+    match mode {
+        Mode::Insert | Mode::Command | Mode::Search => CursorStyle::Bar,
+        Mode::Replace => CursorStyle::Underline,
+        Mode::Normal | Mode::Visual | Mode::Exit => CursorStyle::Block,
+    }
+}
+
 #[derive(Clone, Copy)]
 struct Size(u16, u16);
 
@@ -59,6 +90,189 @@ struct TextEditor {
     repeating_action: bool,
     highlighter: HighLighter,
     dialogs: Vec<Dialog>,
+    modifiable: bool,
+    // the file can't be written, or was opened with `-R`: nothing may
+    // change it but a forced `:w!`
+    readonly: bool,
+    last_search: Option<String>,
+    // false after `#`, for which `n` searches backward
+    search_forward: bool,
+    // cursor (cur_line, cur_pos) when `/` was pressed
+    search_origin: Option<(usize, Coordinates)>,
+    incsearch: bool,
+    // searches ignore case, unless with `smartcase` the pattern has an
+    // upper case letter
+    ignorecase: bool,
+    smartcase: bool,
+    autopairs: bool,
+    wrap: bool,
+    // show tabs, trailing blanks and line ends
+    list: bool,
+    // show blanks at the end of lines on a red background
+    highlight_trailing_ws: bool,
+    // show the words `spell` doesn't know on a light red background
+    spell_check: bool,
+    // remove the blanks at the end of lines before writing the file
+    trim_on_write: bool,
+    tab_width: usize,
+    expandtab: bool,
+    // allow block selections to extend past the end of short lines
+    virtualedit_block: bool,
+    // show the keys of a command being typed in the status bar
+    showcmd: bool,
+    // show line numbers, or their distance from the cursor line, left of
+    // the text
+    show_line_numbers: bool,
+    relative_numbers: bool,
+    // lines kept visible above and below the cursor
+    scrolloff: usize,
+    // display column (from 1) marked on every line as a right margin, 0 for
+    // none
+    colorcolumn: usize,
+    // width `gq` formats lines to, 0 for 79
+    textwidth: usize,
+    // secondary cursors of multi-cursor editing (x is the line, y is the
+    // column), they repeat the edits made at the main cursor
+    cursors: Vec<Coordinates>,
+    spell: Spell,
+    // command the `=` operator filters lines through, the built-in
+    // indenting is used when it is empty
+    equalprg: String,
+    // file the debugging output is appended to, set with `:set debuglog=`.
+    // There is none by default, stderr being the terminal drawn on
+    debug_log: Option<String>,
+    registers: Registers,
+    // the selection visual mode was last left with, for `gv`
+    last_visual: Option<SelectView>,
+    // set by every command that may change the text, until it is written
+    modified: bool,
+    // line ending of the file, and whether its last line has one
+    line_ending: LineEnding,
+    eol: bool,
+    // all the opened buffers, the entry of the active one is None as its
+    // state is in use by the editor
+    buffers: Vec<Option<Buffer>>,
+    buffer: usize,
+    // entered `:` commands, the last one at the end
+    history: Vec<String>,
+    // entry shown while browsing the history with Up and Down, together
+    // with what was typed before, which the entries have to start with
+    history_pos: Option<(usize, String)>,
+    // candidates found by Tab on the command line, the text before them
+    // and the one shown, so that pressing Tab again shows the next one
+    completion: Option<(String, Vec<String>, usize)>,
+    // the same for `Ctrl-n` and `Ctrl-p` in insert mode: the typed start
+    // of the word, the words of the text starting with it and the one
+    // inserted
+    keyword_completion: Option<(String, Vec<String>, usize)>,
+    // after `Ctrl-v` in insert mode, the code of the char to insert typed so
+    // far: decimal digits, or `u` and hexadecimal ones
+    literal: Option<String>,
+    // the line as it was when `R` was pressed, and the chars typed over
+    // since, None for the ones appended past the end of the line
+    replacing: Option<(String, Vec<Option<char>>)>,
+    // a `:s///c` asking about each match, which takes the keys until done
+    substituting: Option<Substitution>,
+    // a block `I` or `A` whose text is repeated on the other lines of the
+    // block when leaving insert mode
+    block_insert: Option<BlockInsert>,
+    // what `.` repeats, when it is more than the last undoable action
+    last_change: Option<LastChange>,
+    // folds made with `zf`: first and last line (0-based), and whether they
+    // are closed, which shows them as a single row
+    folds: Vec<(usize, usize, bool)>,
+    // `foldmethod=indent`, the folds follow the indent of the lines
+    fold_indent: bool,
+    // (line, column) of the cursor before the jumps made by `G`, `gg` and
+    // searches, and the entry `Ctrl-o` and `Ctrl-i` are at, which is the
+    // end of the list unless going through it
+    jumps: Vec<(usize, usize)>,
+    jump_index: usize,
+    // register and keys of the macro being recorded with `q`
+    recording: Option<(char, Vec<Key>)>,
+    // register replayed last, for `@@`
+    last_macro: Option<char>,
+    // index of the cursor positions files were left at, None to neither
+    // restore nor remember them
+    positions: Option<PathBuf>,
+    // list of the files last opened or written, None to keep none
+    recent: Option<PathBuf>,
+}
+
+// state of a `:s///c` between its prompts
+struct Substitution {
+    sub: Substitute,
+    // first line of the range (0-based) and its lines before any replacement
+    first: usize,
+    old: Vec<String>,
+    // the match asked about, as a line (0-based) and a char column
+    line: usize,
+    col: usize,
+    // where the last replacement was made, for the cursor when done
+    replaced: Option<(usize, usize)>,
+}
+
+// a change `.` makes again at the cursor as the command it was, rather than
+// as the text it changed. Other changes are repeated from the undo history
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum LastChange {
+    // `dw` and its count
+    DeleteWords(usize),
+    // `diw`
+    DeleteInnerWord,
+    // `ciw` and the keys typed until `Esc`
+    ChangeInnerWord(Vec<Key>),
+    // `cgn` or `cgN` and the keys typed until `Esc`, for the next match
+    ChangeMatch(bool, Vec<Key>),
+    // `>>` and `<<`: the lines shifted, and whether to the right
+    Shift(usize, bool),
+}
+
+// state of a block `I` or `A` until insert mode is left
+struct BlockInsert {
+    // first line of the block (0-based) and its lines before the insert
+    first: usize,
+    old: Vec<String>,
+    // column the text is inserted at, and whether it is after the block
+    col: usize,
+    append: bool,
+    // line count of the text, a typed line break ends the block insert
+    len: usize,
+}
+
+// a file opened with `:e`. The state of the active buffer is kept in the
+// fields of `TextEditor`, the other ones wait here until switched to
+struct Buffer {
+    text: Text,
+    file_name: String,
+    cur_pos: Coordinates,
+    cur_line: usize,
+    view: TextView,
+    modified: bool,
+    readonly: bool,
+    line_ending: LineEnding,
+    eol: bool,
+    highlighter: HighLighter,
+    action_stack: ActionStack,
+}
+
+// an edit made at the main cursor, to be repeated at the secondary ones
+#[derive(Clone, Copy)]
+pub enum CursorEdit {
+    Insert(char),
+    Backspace,
+    Delete,
+}
+
+// counts of the text shown by `:stats`, `byte` is the offset of the cursor
+// (from 1) in the text as it is written to the file
+#[derive(Debug, PartialEq, Eq)]
+struct Stats {
+    lines: usize,
+    words: usize,
+    chars: usize,
+    bytes: usize,
+    byte: usize,
 }
 
 #[derive(Clone)]
@@ -72,16 +286,25 @@ struct Dialog {
 enum SelectView {
     CharacterView(CharacterView),
     LineView(LineView),
-    #[allow(dead_code)]
     BlockView(CharacterView),
     None,
 }
 
+// both ends are selected, as `x` the column and `y` the line from 0, the
+// way the screen is drawn. `Text` takes them the other way round
 #[derive(Debug, PartialEq, Eq)]
 struct CharacterView {
     start: Coordinates,
     end: Coordinates,
 }
+
+impl CharacterView {
+    // the ends as `Text` coordinates, line in `x` and column in `y`
+    fn text_range(&self) -> (Coordinates, Coordinates) {
+        let swap = |c: Coordinates| Coordinates { x: c.y, y: c.x };
+        (swap(self.start), swap(self.end))
+    }
+}
 #[derive(Debug, PartialEq, Eq)]
 struct LineView {
     start: usize,
@@ -92,6 +315,8 @@ struct LineView {
 struct TextView {
     lower_line: usize,
     upper_line: usize,
+    // first column shown on screen, for lines wider than the terminal
+    left_col: usize,
 }
 
 impl TextView {
@@ -127,28 +352,158 @@ impl TextView {
     }
 }
 
+// split a line into screen rows of `width` chars, for soft wrapping
+fn wrap_line(text: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.is_empty() {
+        return vec!["".to_string()];
+    }
+    chars
+        .chunks(width.max(1))
+        .map(|row| row.iter().collect())
+        .collect()
+}
+
 impl TextEditor {
-    pub fn new(file_name: &str) -> Self {
+    pub fn new(file_name: &str) -> Result<Self, EditorError> {
+        let size = termion::terminal_size().map_err(EditorError::Terminal)?;
+        let mut out = MouseTerminal::from(AlternateScreen::from(BufWriter::with_capacity(
+            1 << 14,
+            stdout(),
+        )))
+        .into_raw_mode()
+        .map_err(EditorError::Terminal)?;
+        write!(out, "{}", termion::cursor::Show).map_err(EditorError::Terminal)?;
+        let mut editor = Self::with_file(file_name, Box::new(out), Size(size.0, size.1));
+        editor.positions = positions::index_path();
+        editor.restore_position();
+        editor.recent = recent::list_path();
+        editor.add_recent();
+        Ok(editor)
+    }
+
+    // put the active file at the top of the recent ones. The list is only
+    // a convenience, a failure to keep it isn't worth a message
+    fn add_recent(&self) {
+        if let Some(list) = &self.recent {
+            let _ = recent::add(list, &self.file_name);
+        }
+    }
+
+    // `:recent`, list the recent files, or open the one numbered `arg`
+    fn open_recent(&mut self, arg: &str) {
+        let files = self.recent.as_deref().map(recent::load).unwrap_or_default();
+        if arg.is_empty() {
+            if files.is_empty() {
+                self.show_message("No recent files");
+                return;
+            }
+            let list = files
+                .iter()
+                .enumerate()
+                .map(|(i, file)| format!("{:3} {file}", i + 1))
+                .collect();
+            self.show_list(list);
+            return;
+        }
+        match arg.parse::<usize>() {
+            Ok(n) if (1..=files.len()).contains(&n) => self.edit_file(&files[n - 1]),
+            _ => self.show_message(&format!("E475: Invalid argument: {arg}")),
+        }
+    }
+
+    // go back to where the cursor was when the file was last left, unless
+    // the file got shorter than that since
+    fn restore_position(&mut self) {
+        let Some(index) = &self.positions else {
+            return;
+        };
+        match positions::load(index, &self.file_name) {
+            Some((line, col)) if line <= self.text_length() => self.jump_to(line, col),
+            _ => {}
+        }
+    }
+
+    // remember where the cursor is in every open file. This is done on the
+    // way out, with nowhere left to show a failure
+    fn save_positions(&self) {
+        let Some(index) = &self.positions else {
+            return;
+        };
+        let active = (&self.file_name, self.cur_line, self.cur_pos.x);
+        let others = self
+            .buffers
+            .iter()
+            .flatten()
+            .map(|buffer| (&buffer.file_name, buffer.cur_line, buffer.cur_pos.x));
+        for (file_name, line, col) in std::iter::once(active).chain(others) {
+            let _ = positions::save(index, file_name, line, col);
+        }
+    }
+
+    fn with_file(file_name: &str, out: Box<dyn Write>, size: Size) -> Self {
+        let (loaded, error) = match Self::load_text(file_name) {
+            Ok(loaded) => (loaded, None),
+            // the buffer starts empty, with the reason shown
+            Err(err) => (Self::parse_text(""), Some(err)),
+        };
+        let (text, line_ending, eol) = loaded;
+        let mut highlighter = HighLighter::new(file_name);
+        highlighter.detect_from_first_line(&text.line_at(0));
+        let mut editor = Self::with_text(text, file_name, highlighter, out, size);
+        editor.line_ending = line_ending;
+        editor.eol = eol;
+        editor.action_stack = ActionStack::from_undo_file(file_name);
+        editor.readonly = Self::is_readonly_file(file_name);
+        if let Some(err) = error {
+            // the empty buffer isn't what the file holds, only `:w!` may
+            // write it over the file
+            editor.readonly = true;
+            editor.report(err);
+        }
+        editor
+    }
+
+    // a missing file can be created, so it isn't read-only
+    fn is_readonly_file(file_name: &str) -> bool {
+        fs::metadata(file_name).is_ok_and(|metadata| metadata.permissions().readonly())
+    }
+
+    // the lines of the file, how they are ended and whether the last one is
+    fn load_text(file_name: &str) -> Result<(Text, LineEnding, bool), EditorError> {
+        // a missing file is created by the first `:w`
+        let file_handle = match fs::read_to_string(file_name) {
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            res => res.map_err(|err| EditorError::Read(file_name.to_string(), err))?,
+        };
+        Ok(Self::parse_text(&file_handle))
+    }
+
+    fn parse_text(file_handle: &str) -> (Text, LineEnding, bool) {
         let mut text = Text::new();
-        let file_handle = fs::read_to_string(file_name).unwrap();
-        let highlighter = HighLighter::new(file_name);
         for line in file_handle.lines() {
             text.push_line(line.to_string());
         }
-        let text_length = file_handle.lines().count();
-        let size = termion::terminal_size().unwrap();
+        if text.len() == 0 {
+            text.push_line("".to_string());
+        }
+        // only an existing last line can lack its line ending
+        let eol = file_handle.is_empty() || file_handle.ends_with('\n');
+        (text, LineEnding::detect(file_handle), eol)
+    }
+
+    fn with_text(
+        text: Text,
+        file_name: &str,
+        highlighter: HighLighter,
+        out: Box<dyn Write>,
+        size: Size,
+    ) -> Self {
         let view = TextView {
             lower_line: 0,
-            upper_line: text_length.min(size.1 as usize - 1),
+            upper_line: text.len().min(size.1 as usize - 1),
+            left_col: 0,
         };
-        let mut out = MouseTerminal::from(AlternateScreen::from(BufWriter::with_capacity(
-            1 << 14,
-            stdout(),
-        )))
-        .into_raw_mode()
-        .unwrap();
-        write!(out, "{}", termion::cursor::Show).unwrap();
-        let out = Box::new(out);
         TextEditor {
             text,
             bar_text: Text::new(),
@@ -157,7 +512,7 @@ impl TextEditor {
             cur_line: 1,
             view,
             select_view: SelectView::None,
-            terminal_size: Size(size.0, size.1),
+            terminal_size: size,
             file_name: file_name.into(),
             out,
             mode: Mode::Normal,
@@ -168,9 +523,67 @@ impl TextEditor {
             repeating_action: false,
             highlighter,
             dialogs: vec![],
+            modifiable: true,
+            readonly: false,
+            last_search: None,
+            search_forward: true,
+            search_origin: None,
+            incsearch: false,
+            ignorecase: false,
+            smartcase: false,
+            autopairs: true,
+            wrap: false,
+            list: false,
+            spell_check: false,
+            highlight_trailing_ws: false,
+            trim_on_write: false,
+            tab_width: 4,
+            expandtab: true,
+            virtualedit_block: false,
+            showcmd: false,
+            show_line_numbers: false,
+            relative_numbers: false,
+            scrolloff: 0,
+            colorcolumn: 0,
+            textwidth: 0,
+            cursors: vec![],
+            spell: Spell::new(),
+            equalprg: String::new(),
+            debug_log: None,
+            registers: Registers::default(),
+            last_visual: None,
+            modified: false,
+            line_ending: LineEnding::Lf,
+            eol: true,
+            buffers: vec![None],
+            buffer: 0,
+            history: vec![],
+            history_pos: None,
+            completion: None,
+            keyword_completion: None,
+            literal: None,
+            replacing: None,
+            substituting: None,
+            block_insert: None,
+            last_change: None,
+            folds: vec![],
+            fold_indent: false,
+            jumps: vec![],
+            jump_index: 0,
+            recording: None,
+            last_macro: None,
+            positions: None,
+            recent: None,
         }
     }
 
+    #[cfg(test)]
+    fn test_output() -> Box<dyn Write> {
+        let mut out = BufWriter::with_capacity(1 << 14, vec![]);
+        write!(out, "{}", termion::cursor::Show).unwrap();
+        Box::new(out)
+    }
+
     #[cfg(test)]
     pub fn new_from_vec(lines: &Vec<String>) -> Self {
         let highlighter = HighLighter::new("test.rs");
@@ -178,52 +591,37 @@ impl TextEditor {
         for line in lines {
             text.push_line(line.clone());
         }
-        let text_length = lines.len();
-        let size = termion::terminal_size().unwrap();
-        let view = TextView {
-            lower_line: 0,
-            upper_line: text_length.min(size.1 as usize - 1),
-        };
-        let mut out = BufWriter::with_capacity(1 << 14, vec![]);
-        write!(out, "{}", termion::cursor::Show).unwrap();
-        let out = Box::new(out);
-        TextEditor {
+        Self::with_text(
             text,
-            bar_text: Text::new(),
-            cur_pos: Coordinates { x: 1, y: 1 },
-            saved_pos: None,
-            cur_line: 1,
-            view,
-            select_view: SelectView::None,
-            terminal_size: Size(size.0, size.1),
-            file_name: "test_file".into(),
-            out,
-            mode: Mode::Normal,
-            task: Task::default(),
-            action_stack: ActionStack::default(),
-            processing_action: false,
-            processing_task: false,
-            repeating_action: false,
+            "test_file",
             highlighter,
-            dialogs: vec![],
-        }
+            Self::test_output(),
+            Size(80, 24),
+        )
+    }
+
+    #[cfg(test)]
+    pub fn new_from_file(file_name: &str) -> Self {
+        Self::with_file(file_name, Self::test_output(), Size(80, 24))
     }
 
     fn flush(&mut self) {
-        let mut old_pos = Coordinates { x: 1, y: 1 };
         if self.mode == Mode::Command || self.mode == Mode::Search {
-            if self.saved_pos == None {
+            if self.saved_pos.is_none() {
                 self.saved_pos = Some(self.cur_pos);
             }
         } else {
-            if let Some(pos) = self.saved_pos {
-                old_pos = pos;
-                self.saved_pos = None;
+            if let Some(pos) = self.saved_pos.take() {
+                self.cur_pos = pos;
+            }
+            self.scroll_to_col(self.cur_pos.x.min(self.max_x()));
+            if self.wrap {
+                self.scroll_to_wrapped_cursor();
             } else {
-                old_pos = self.cur_pos;
+                self.keep_scrolloff();
             }
         }
-        write!(stderr(), "saved_pos {:?}\n", self.saved_pos).unwrap();
+        self.debug(format_args!("saved_pos {:?}", self.saved_pos));
         self.print_text();
         for i in 0..self.dialogs.len() {
             self.print_dialog(self.dialogs[i].clone());
@@ -234,8 +632,8 @@ impl TextEditor {
         // FIXME: when '$' status is on, we should also move to the end of the line
         //          no matter what old_x is.
         if self.mode != Mode::Command && self.mode != Mode::Search {
-            old_pos.x = old_pos.x.min(self.len_of_cur_line());
-            self.set_pos(old_pos.x, old_pos.y);
+            self.cur_pos.x = self.cur_pos.x.min(self.max_x());
+            self.update_pos();
         } else {
             let x = self.mode.to_string().len() + 2 + self.bar_text.line_at(0).len();
             self.set_pos(x, self.terminal_size.1 as usize);
@@ -251,80 +649,508 @@ impl TextEditor {
             termion::cursor::Goto(1, 1)
         )
         .unwrap();
-        for line in self.view.lower_line()..self.view.upper_line() {
-            let text = self.text.line_at(line as usize);
-            let line_text = self.highlighter.highlight_line(&text);
-            let highlight_text = line_text.as_bytes();
-            let mut h_ind = 0;
-            for (col, c) in text.chars().enumerate() {
+        self.refresh_folds();
+        // lines above the view only feed the parse state, their output is
+        // cached so this is cheap once they have been highlighted
+        for line in 0..self.view.lower_line() {
+            self.highlighter
+                .highlight_line(line, &self.text.line_at(line));
+        }
+        let width = self.text_width();
+        let left_col = self.view.left_col;
+        let mut rows_left = self.max_y() as usize;
+        let gutter = self.number_width();
+        // wrapped lines go without the marker
+        let color_column = self.color_column_x().filter(|_| !self.wrap);
+        // past the upper line of the view when folds hide some lines
+        let mut line = self.view.lower_line();
+        while line < self.text_length() {
+            if rows_left == 0 {
+                break;
+            }
+            let row = self.max_y() as usize - rows_left + 1;
+            if gutter > 0 {
+                write!(
+                    self.out,
+                    "{}{}{}",
+                    color::Fg(color::Yellow),
+                    self.line_number(line),
+                    color::Fg(color::Reset)
+                )
+                .unwrap();
+            }
+            if let Some((_, end)) = self.closed_fold_at(line) {
+                let summary = self.fold_summary(line, end);
+                write!(self.out, "{}{summary}{}", style::Bold, style::Reset).unwrap();
+                writeln!(self.out, "\r").unwrap();
+                rows_left = rows_left.saturating_sub(1);
+                // the parse state still goes through the hidden lines
+                for hidden in line..=end {
+                    self.highlighter
+                        .highlight_line(hidden, &self.text.line_at(hidden));
+                }
+                line = end + 1;
+                continue;
+            }
+            let text = self.text.line_at(line);
+            let glyphs = self.glyphs_of(&text);
+            let trailing = Self::trailing_whitespace(&text);
+            let misspelled = if self.spell_check {
+                self.spell.misspelled_words(&text)
+            } else {
+                vec![]
+            };
+            let line_text = self.highlighter.highlight_line(line, &text);
+            let mut chars = line_text.chars();
+            let mut col = 0;
+            let mut screen_col = 0;
+            'chars: while let Some(c) = chars.next() {
+                if c == '\x1b' {
+                    // color escapes are always written so that the
+                    // visible part of the line keeps the right colors
+                    write!(self.out, "{c}").unwrap();
+                    for c in chars.by_ref() {
+                        write!(self.out, "{c}").unwrap();
+                        if c == 'm' {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                let cells = self.cells_of(c, screen_col);
+                let mut glyph = glyphs.get(col).copied().unwrap_or(c);
+                // a wide char is drawn once over its cells, or as blanks
+                // when the edge of the view cuts it
+                let wide = c != '\t' && cells > 1;
+                let cut = if self.wrap {
+                    screen_col % width + cells > width
+                } else {
+                    screen_col < left_col || screen_col + cells > left_col + width
+                };
+                if wide && cut {
+                    glyph = ' ';
+                }
+                let drawn_once = wide && !cut;
+                if self.highlight_trailing_ws && col == trailing.start {
+                    write!(self.out, "{}", color::Bg(color::Red)).unwrap();
+                }
+                if misspelled.iter().any(|word| word.start == col) {
+                    write!(self.out, "{}", color::Bg(color::LightRed)).unwrap();
+                }
                 if self.is_select_start(col, line) {
                     write!(self.out, "{}", termion::style::Invert).unwrap();
                 }
-                while highlight_text[h_ind] != c as u8 {
-                    write!(self.out, "{}", highlight_text[h_ind] as char).unwrap();
-                    h_ind += 1;
+                let at_cursor = self.cursors.contains(&Coordinates { x: line, y: col });
+                if at_cursor {
+                    write!(self.out, "{}", termion::style::Underline).unwrap();
+                }
+                for cell in 0..cells {
+                    if self.wrap && screen_col > 0 && screen_col % width == 0 {
+                        rows_left -= 1;
+                        if rows_left == 0 {
+                            break 'chars;
+                        }
+                        writeln!(self.out, "\r").unwrap();
+                        write!(self.out, "{}", " ".repeat(gutter)).unwrap();
+                    }
+                    let visible =
+                        self.wrap || screen_col >= left_col && screen_col < left_col + width;
+                    if visible && !(drawn_once && cell > 0) {
+                        write!(self.out, "{glyph}").unwrap();
+                    }
+                    // the rest of a tab is blank
+                    glyph = ' ';
+                    screen_col += 1;
+                }
+                if at_cursor {
+                    write!(self.out, "{}", termion::style::NoUnderline).unwrap();
                 }
-
-                // write!(self.out, "{}", c).unwrap();
                 if self.is_select_end(col, line) {
                     write!(self.out, "{}", termion::style::NoInvert).unwrap();
                 }
+                if misspelled.iter().any(|word| word.end == col + 1) {
+                    write!(self.out, "{}", color::Bg(color::Reset)).unwrap();
+                }
+                col += 1;
             }
-            while h_ind < highlight_text.len() {
-                write!(self.out, "{}", highlight_text[h_ind] as char).unwrap();
-                h_ind += 1;
+            if self.highlight_trailing_ws && !trailing.is_empty() {
+                write!(self.out, "{}", style::Reset).unwrap();
+            }
+            if self.list && (self.wrap || screen_col >= left_col && screen_col < left_col + width) {
+                write!(self.out, "$").unwrap();
+            }
+            if let Some(x) = color_column {
+                let glyph = self.glyph_at_display_col(&text, self.colorcolumn - 1);
+                write!(
+                    self.out,
+                    "{}{}{}{glyph}{}",
+                    termion::cursor::Goto(x as u16, row as u16),
+                    style::Reset,
+                    color::Bg(color::LightBlack),
+                    style::Reset
+                )
+                .unwrap();
             }
             writeln!(self.out, "\r").unwrap();
+            rows_left = rows_left.saturating_sub(1);
+            line += 1;
+        }
+    }
+
+    // with `foldmethod=indent`, fold the text as it is now. Folds keep being
+    // open or closed by their first line, new ones are open
+    fn refresh_folds(&mut self) {
+        if !self.fold_indent {
+            return;
+        }
+        let lines: Vec<String> = (0..self.text_length())
+            .map(|line| self.text.line_at(line))
+            .collect();
+        let old = std::mem::take(&mut self.folds);
+        self.folds = fold::indent_folds(&lines, self.tab_width)
+            .into_iter()
+            .map(|(start, end)| {
+                let closed = old
+                    .iter()
+                    .any(|&(first, _, closed)| first == start && closed);
+                (start, end, closed)
+            })
+            .collect();
+    }
+
+    // `zR` and `zM`
+    pub fn set_folds_closed(&mut self, closed: bool) {
+        for fold in &mut self.folds {
+            fold.2 = closed;
+        }
+        if let Some((start, _)) = self.closed_fold_at(self.cur_line - 1) {
+            self.jump_to(start + 1, self.cur_pos.x);
+        }
+    }
+
+    // the outermost closed fold `line` (0-based) is in
+    fn closed_fold_at(&self, line: usize) -> Option<(usize, usize)> {
+        self.folds
+            .iter()
+            .filter(|&&(start, end, closed)| closed && start <= line && line <= end)
+            .map(|&(start, end, _)| (start, end))
+            .min_by_key(|&(start, end)| (start, usize::MAX - end))
+    }
+
+    // keep the folds on their lines when `count` lines are inserted before
+    // line `at` (0-based), a fold growing when they are inserted into it
+    fn shift_folds(&mut self, at: usize, count: usize) {
+        for (start, end, _) in &mut self.folds {
+            if *start >= at {
+                *start += count;
+            }
+            if *end >= at {
+                *end += count;
+            }
+        }
+    }
+
+    // the same when `count` lines from line `at` are removed, the folds of
+    // only those lines going with them
+    fn drop_folded_lines(&mut self, at: usize, count: usize) {
+        let removed = at..at + count;
+        self.folds
+            .retain(|(start, end, _)| !(removed.contains(start) && removed.contains(end)));
+        for (start, end, _) in &mut self.folds {
+            if *start >= removed.end {
+                *start -= count;
+            } else if removed.contains(start) {
+                *start = at;
+            }
+            if *end >= removed.end {
+                *end -= count;
+            } else if removed.contains(end) {
+                *end = at - 1;
+            }
+        }
+    }
+
+    // lines (from `line`, 0-based) that `count` lines on screen stand for,
+    // a closed fold being one of them
+    fn folded_span(&self, line: usize, count: usize) -> usize {
+        let mut end = line;
+        for _ in 0..count {
+            if end >= self.text_length() {
+                break;
+            }
+            end = self.closed_fold_at(end).map_or(end, |(_, last)| last) + 1;
+        }
+        end - line
+    }
+
+    // what a closed fold shows, cut to the width of the text
+    fn fold_summary(&self, start: usize, end: usize) -> String {
+        let first = self.text.line_at(start);
+        let summary = format!("+--{:>3} lines: {}", end - start + 1, first.trim());
+        summary.chars().take(self.text_width()).collect()
+    }
+
+    // lines between the top of the view and the cursor line that closed
+    // folds keep off the screen
+    fn hidden_rows(&self) -> usize {
+        let mut hidden = 0;
+        let mut line = self.view.lower_line();
+        while line < self.cur_line - 1 {
+            match self.closed_fold_at(line) {
+                Some((_, end)) => {
+                    hidden += end.min(self.cur_line - 1) - line;
+                    line = end + 1;
+                }
+                None => line += 1,
+            }
+        }
+        hidden
+    }
+
+    // `zf`: fold lines `first..=last` (0-based), closed
+    pub fn create_fold(&mut self, first: usize, last: usize) {
+        self.folds.push((first, last, true));
+        self.jump_to(first + 1, self.cur_pos.x);
+    }
+
+    // `za`: open the closed fold under the cursor, or close the innermost
+    // open one
+    pub fn toggle_fold(&mut self) {
+        let line = self.cur_line - 1;
+        if let Some(fold) = self.closed_fold_at(line) {
+            for (start, end, closed) in &mut self.folds {
+                if (*start, *end) == fold {
+                    *closed = false;
+                }
+            }
+            return;
+        }
+        let innermost = self
+            .folds
+            .iter_mut()
+            .filter(|(start, end, _)| *start <= line && line <= *end)
+            .min_by_key(|(start, end, _)| end - start);
+        match innermost {
+            Some(fold) => {
+                fold.2 = true;
+                let start = fold.0;
+                self.jump_to(start + 1, self.cur_pos.x);
+            }
+            None => self.show_message("E490: No fold found"),
+        }
+    }
+
+    // `j`, a closed fold being stepped over as a single line
+    fn next_line(&mut self) {
+        if let Some((_, end)) = self.closed_fold_at(self.cur_line - 1) {
+            if end + 1 >= self.text_length() {
+                return;
+            }
+            while self.cur_line - 1 < end {
+                self.inc_y();
+            }
+        }
+        self.inc_y();
+    }
+
+    // `k`, landing on the first line of a closed fold
+    fn prev_line(&mut self) {
+        self.dec_y();
+        if let Some((start, _)) = self.closed_fold_at(self.cur_line - 1) {
+            while self.cur_line - 1 > start {
+                self.dec_y();
+            }
+        }
+    }
+
+    // the glyph each char of `text` is shown with, which is the first cell
+    // of a tab. With `list` tabs and trailing spaces are made visible
+    fn glyphs_of(&self, text: &str) -> Vec<char> {
+        let trailing = Self::trailing_whitespace(text).start;
+        text.chars()
+            .enumerate()
+            .map(|(col, c)| match c {
+                '\t' if self.list => '→',
+                '\t' => ' ',
+                // control chars inserted with `Ctrl-v` as their symbols
+                '\0'..='\x1f' => char::from_u32(0x2400 + c as u32).unwrap_or(c),
+                '\x7f' => '␡',
+                ' ' if self.list && col >= trailing => '·',
+                c => c,
+            })
+            .collect()
+    }
+
+    // screen column (from 1) the `colorcolumn` marker is drawn at, None
+    // when it is off or scrolled out of view
+    fn color_column_x(&self) -> Option<usize> {
+        let col = self.colorcolumn.checked_sub(1)?;
+        let left_col = self.view.left_col;
+        if col < left_col || col >= left_col + self.text_width() {
+            return None;
+        }
+        Some(self.number_width() + col - left_col + 1)
+    }
+
+    // what is shown at display column `col` of `text`, blank past its end
+    // and on the cells of a tab but the first one
+    fn glyph_at_display_col(&self, text: &str, col: usize) -> char {
+        let glyphs = self.glyphs_of(text);
+        let mut screen_col = 0;
+        for (i, c) in text.chars().enumerate() {
+            let cells = self.cells_of(c, screen_col);
+            if col < screen_col + cells {
+                return if col == screen_col { glyphs[i] } else { ' ' };
+            }
+            screen_col += cells;
+        }
+        ' '
+    }
+
+    // columns (from 0) of the blanks at the end of `text`
+    fn trailing_whitespace(text: &str) -> Range<usize> {
+        let start = text.trim_end_matches([' ', '\t']).chars().count();
+        start..text.chars().count()
+    }
+
+    // start and end (x is the line, y is the column) of a character- or
+    // line-wise selection
+    fn selected_range(&self) -> Option<(Coordinates, Coordinates)> {
+        match Self::sort_select_view(&self.select_view) {
+            SelectView::CharacterView(v) => Some(v.text_range()),
+            SelectView::LineView(v) => Some((
+                Coordinates { x: v.start, y: 0 },
+                Coordinates {
+                    x: v.end,
+                    y: self.len_of_line_at(v.end).saturating_sub(1),
+                },
+            )),
+            _ => None,
         }
     }
 
     fn delete_selected(&mut self) {
-        let contents = match Self::sort_select_view(&self.select_view) {
-            SelectView::CharacterView(v) => {
-                let start = Coordinates {
-                    x: v.start.y,
-                    y: v.start.x,
-                };
-                let end = Coordinates {
-                    x: v.end.y,
-                    y: v.end.x,
-                };
-                self.set_pos(v.start.x + 1, v.start.y + 1);
-                self.set_cur_line(v.start.y + 1);
-                self.text.delete_range(start, end)
+        match Self::sort_select_view(&self.select_view) {
+            SelectView::BlockView(v) => {
+                self.delete_block(v);
+                return;
             }
+            // as `dd` does, which leaves an empty line when all are gone
             SelectView::LineView(v) => {
-                let start = Coordinates { x: v.start, y: 0 };
-                let end = Coordinates {
-                    x: v.end,
-                    y: self.len_of_line_at(v.end) - 1,
-                };
-                self.set_pos(1, v.start + 1);
-                self.set_cur_line(v.start + 1);
-                self.text.delete_range(start, end)
+                self.jump_to(v.start + 1, 1);
+                self.delete_lines(v.end - v.start + 1);
+                return;
             }
-            SelectView::BlockView(_) => todo!(),
-            SelectView::None => "".to_string(),
+            _ => (),
+        }
+        let Some((start, end)) = self.selected_range() else {
+            return;
         };
+        self.set_pos(start.y + 1, start.x + 1);
+        self.set_cur_line(start.x + 1);
+        let contents = self.delete_text_range(start, end);
+        if !contents.is_empty() && !self.processing_action {
+            self.registers.push_delete(Register {
+                text: contents.clone(),
+                linewise: false,
+            });
+        }
         if !contents.is_empty() {
             self.action_stack
                 .add_action(Action::Delete, self.cur_line, self.cur_pos);
-            write!(
-                stderr(),
-                "add action delete at ({},{:?})\n",
-                self.cur_line,
-                self.cur_pos
-            )
-            .unwrap();
+            self.debug(format_args!(
+                "add action delete at ({},{:?})",
+                self.cur_line, self.cur_pos
+            ));
             self.action_stack.append_string_to_top(contents);
         }
     }
 
+    // visual `y`: copy the selection into the unnamed register and go back
+    // to the start of it
+    pub fn yank_selected(&mut self) {
+        let (register, start) = match Self::sort_select_view(&self.select_view) {
+            SelectView::LineView(v) => {
+                let lines: Vec<String> = (v.start..=v.end).map(|i| self.text.line_at(i)).collect();
+                let register = Register {
+                    text: lines.join("\n") + "\n",
+                    linewise: true,
+                };
+                (register, Coordinates { x: v.start, y: 0 })
+            }
+            SelectView::BlockView(v) => {
+                let lines: Vec<String> = (v.start.y..=v.end.y)
+                    .map(|i| {
+                        let end = Coordinates { x: i, y: v.end.x };
+                        self.text
+                            .text_in_range(Coordinates { x: i, y: v.start.x }, end)
+                    })
+                    .collect();
+                let register = Register {
+                    text: lines.join("\n"),
+                    linewise: false,
+                };
+                (register, v.text_range().0)
+            }
+            _ => {
+                let Some((start, end)) = self.selected_range() else {
+                    return;
+                };
+                let register = Register {
+                    text: self.text.text_in_range(start, end),
+                    linewise: false,
+                };
+                (register, start)
+            }
+        };
+        self.registers.set_unnamed(register);
+        self.jump_to(start.x + 1, start.y + 1);
+    }
+
+    // `p`, put register `name` after the cursor, or below the current line
+    // when it holds whole lines
+    pub fn put_after(&mut self, name: char) {
+        let Some(register) = self.registers.get(name).cloned() else {
+            self.show_message(&format!("E353: Nothing in register {name}"));
+            return;
+        };
+        if register.linewise {
+            let text = register.text.strip_suffix('\n').unwrap_or(&register.text);
+            self.insert_lines_below(
+                self.cur_line,
+                text.split('\n').map(str::to_string).collect(),
+            );
+            self.move_to_first_char_of_line();
+            return;
+        }
+        // an empty line has no char to put the text after
+        if self.text.len_of_line_at(self.cur_line - 1) > 0 {
+            self.cur_pos.x += 1;
+        }
+        self.action_stack
+            .add_action(Action::Insert, self.cur_line, self.cur_pos);
+        self.action_stack
+            .append_string_to_top(register.text.clone());
+        // inserted as in insert mode, past the last char of the line if needed
+        let mode = std::mem::replace(&mut self.mode, Mode::Insert);
+        self.processing_action = true;
+        register
+            .text
+            .chars()
+            .for_each(|c| self.append_char_at_cur(c));
+        self.processing_action = false;
+        self.mode = mode;
+        // on the last char put
+        self.dec_x();
+    }
+
     fn is_select_end(&mut self, col: usize, line: usize) -> bool {
         match Self::sort_select_view(&self.select_view) {
             SelectView::CharacterView(v) => line > v.end.y || col >= v.end.x && line == v.end.y,
             SelectView::LineView(v) => col >= v.end,
-            SelectView::BlockView(v) => col == v.end.x && line <= v.end.y,
+            // every char of the block is closed on its own, as lines may end
+            // before the right edge of the block
+            SelectView::BlockView(v) => Self::in_block(&v, col, line),
             SelectView::None => false,
         }
     }
@@ -335,7 +1161,7 @@ impl TextEditor {
                     && (line < v.end.y || line == v.end.y && col <= v.end.x)
             }
             SelectView::LineView(v) => line >= v.start && line <= v.end,
-            SelectView::BlockView(v) => col == v.start.x && line >= v.start.y,
+            SelectView::BlockView(v) => Self::in_block(&v, col, line),
             SelectView::None => false,
         }
     }
@@ -358,18 +1184,256 @@ impl TextEditor {
                 }
                 SelectView::LineView(LineView { start, end })
             }
-            SelectView::BlockView(_) => todo!(),
+            // the top left and bottom right corners of the rectangle
+            SelectView::BlockView(v) => SelectView::BlockView(CharacterView {
+                start: Coordinates {
+                    x: v.start.x.min(v.end.x),
+                    y: v.start.y.min(v.end.y),
+                },
+                end: Coordinates {
+                    x: v.start.x.max(v.end.x),
+                    y: v.start.y.max(v.end.y),
+                },
+            }),
             SelectView::None => SelectView::None,
         }
     }
 
-    pub fn set_visual_mode(&mut self, mode: SelectView) {
-        self.select_view = mode;
+    fn in_block(v: &CharacterView, col: usize, line: usize) -> bool {
+        line >= v.start.y && line <= v.end.y && col >= v.start.x && col <= v.end.x
     }
 
-    pub fn update_visual_pos(&mut self) {
-        if self.mode != Mode::Visual {
-            return;
+    // visual `u`, `U`, `~` and `r`: pass every selected char through `f`, as
+    // one undoable action, leaving the cursor at the start of the selection
+    pub fn map_range(&mut self, f: impl Fn(char) -> char) {
+        let view = Self::sort_select_view(&self.select_view);
+        let (first, last) = match &view {
+            SelectView::BlockView(v) => (v.start.y, v.end.y),
+            _ => match self.selected_range() {
+                Some((start, end)) => (start.x, end.x),
+                None => return,
+            },
+        };
+        let selected = |line: usize, col: usize| match &view {
+            SelectView::CharacterView(v) => {
+                let (start, end) = v.text_range();
+                (line, col) >= (start.x, start.y) && (line, col) <= (end.x, end.y)
+            }
+            SelectView::BlockView(v) => Self::in_block(v, col, line),
+            _ => true,
+        };
+        let old: Vec<String> = (first..=last).map(|i| self.text.line_at(i)).collect();
+        let new: Vec<String> = old
+            .iter()
+            .zip(first..)
+            .map(|(text, line)| {
+                text.chars()
+                    .enumerate()
+                    .map(|(col, c)| if selected(line, col) { f(c) } else { c })
+                    .collect()
+            })
+            .collect();
+        self.change_lines(first, old, new);
+        let col = match view {
+            SelectView::CharacterView(v) | SelectView::BlockView(v) => v.start.x,
+            _ => 0,
+        };
+        self.jump_to(first + 1, col + 1);
+    }
+
+    // the other case of `c`, for `~`
+    fn toggle_case(c: char) -> char {
+        if c.is_lowercase() {
+            c.to_uppercase().next().unwrap_or(c)
+        } else {
+            c.to_lowercase().next().unwrap_or(c)
+        }
+    }
+
+    // delete the columns of a (sorted) block from every line it spans, as
+    // one undoable action; lines ending before the block are left alone
+    fn delete_block(&mut self, v: CharacterView) {
+        let old: Vec<String> = (v.start.y..=v.end.y)
+            .map(|i| self.text.line_at(i))
+            .collect();
+        let new: Vec<String> = old
+            .iter()
+            .map(|line| {
+                line.chars()
+                    .enumerate()
+                    .filter(|(col, _)| *col < v.start.x || *col > v.end.x)
+                    .map(|(_, c)| c)
+                    .collect()
+            })
+            .collect();
+        self.jump_to(v.start.y + 1, 1);
+        if new != old {
+            self.action_stack
+                .add_action(Action::Replace(old.clone()), self.cur_line, self.cur_pos);
+            self.action_stack.append_string_to_top(new.join("\n"));
+            self.replace_lines(v.start.y, old.len(), new);
+        }
+        self.jump_to(v.start.y + 1, v.start.x + 1);
+    }
+
+    // block `I` and `A`: insert at the left column of the block, or after its
+    // right one, on its first line; the text is repeated on the other lines
+    // when leaving insert mode. `I` leaves lines shorter than the column
+    // alone, `A` pads them with spaces
+    pub fn start_block_insert(&mut self, append: bool) {
+        let SelectView::BlockView(v) = Self::sort_select_view(&self.select_view) else {
+            return;
+        };
+        let col = if append { v.end.x + 1 } else { v.start.x };
+        let old: Vec<String> = (v.start.y..=v.end.y)
+            .map(|i| self.text.line_at(i))
+            .collect();
+        // the first line is padded right away for the cursor to get there,
+        // it is recorded along with the rest when done
+        self.text.insert_str_padded(v.start.y, col, "");
+        self.block_insert = Some(BlockInsert {
+            first: v.start.y,
+            old,
+            col,
+            append,
+            len: self.text_length(),
+        });
+        self.change_mode_immediately(Mode::Insert);
+        self.jump_to(v.start.y + 1, col + 1);
+        self.action_stack
+            .add_action(Action::Insert, self.cur_line, self.cur_pos);
+    }
+
+    // leaving insert mode after block `I` or `A`, repeat the text inserted in
+    // the first line on the others as one undoable action. Nothing more is
+    // done when the insert wasn't a plain run of text at the column
+    pub fn finish_block_insert(&mut self) {
+        let Some(BlockInsert {
+            first,
+            old,
+            col,
+            append,
+            len,
+        }) = self.block_insert.take()
+        else {
+            return;
+        };
+        if self.text_length() != len {
+            return;
+        }
+        let split = |line: &str| -> (String, String) {
+            let line = format!("{line:<col$}");
+            (
+                line.chars().take(col).collect(),
+                line.chars().skip(col).collect(),
+            )
+        };
+        let (head, tail) = split(&old[0]);
+        let line = self.text.line_at(first);
+        let Some(inserted) = line
+            .strip_prefix(head.as_str())
+            .and_then(|rest| rest.strip_suffix(tail.as_str()))
+            .filter(|inserted| !inserted.is_empty())
+        else {
+            return;
+        };
+        let new: Vec<String> = old
+            .iter()
+            .map(|line| {
+                if !append && line.chars().count() < col {
+                    return line.clone();
+                }
+                let (head, tail) = split(line);
+                format!("{head}{inserted}{tail}")
+            })
+            .collect();
+        self.action_stack.discard_top();
+        self.replace_lines(first, old.len(), old.clone());
+        self.change_lines(first, old, new);
+        self.jump_to(first + 1, col + 1);
+    }
+
+    // with `virtualedit=block` the cursor may go past the end of the line
+    // while selecting a block
+    fn is_virtual_block(&self) -> bool {
+        self.virtualedit_block
+            && self.mode == Mode::Visual
+            && matches!(self.select_view, SelectView::BlockView(_))
+    }
+
+    // the rightmost column the cursor can be on
+    fn max_x(&self) -> usize {
+        if self.is_virtual_block() {
+            usize::MAX
+        } else {
+            self.len_of_cur_line()
+        }
+    }
+
+    // visual `o`: move the cursor to the other end of the selection, the end
+    // it leaves becomes the anchored one
+    pub fn swap_selection_ends(&mut self) {
+        let cursor = Coordinates {
+            x: self.cur_pos.x - 1,
+            y: self.cur_line - 1,
+        };
+        let (line, col) = match &mut self.select_view {
+            SelectView::CharacterView(v) | SelectView::BlockView(v) => {
+                let anchor = std::mem::replace(&mut v.start, cursor);
+                (anchor.y, anchor.x)
+            }
+            SelectView::LineView(v) => (std::mem::replace(&mut v.start, cursor.y), cursor.x),
+            SelectView::None => return,
+        };
+        self.jump_to(line + 1, col + 1);
+    }
+
+    pub fn set_visual_mode(&mut self, mode: SelectView) {
+        let old = std::mem::replace(&mut self.select_view, mode);
+        if self.select_view == SelectView::None && old != SelectView::None {
+            self.last_visual = Some(old);
+        }
+    }
+
+    // `gv`: select the last selection again, clamped to the text as it is
+    // now, with the cursor on its end; false when there was none
+    pub fn reselect_last_visual(&mut self) -> bool {
+        let Some(view) = self.last_visual.take() else {
+            return false;
+        };
+        let last_line = self.text_length() - 1;
+        let clamp = |pos: Coordinates| {
+            let y = pos.y.min(last_line);
+            let len = self.text.line_at(y).chars().count();
+            Coordinates {
+                x: pos.x.min(len.saturating_sub(1)),
+                y,
+            }
+        };
+        let (view, end) = match view {
+            SelectView::CharacterView(v) => {
+                let (start, end) = (clamp(v.start), clamp(v.end));
+                (SelectView::CharacterView(CharacterView { start, end }), end)
+            }
+            SelectView::BlockView(v) => {
+                let (start, end) = (clamp(v.start), clamp(v.end));
+                (SelectView::BlockView(CharacterView { start, end }), end)
+            }
+            SelectView::LineView(v) => {
+                let (start, end) = (v.start.min(last_line), v.end.min(last_line));
+                let view = SelectView::LineView(LineView { start, end });
+                (view, Coordinates { x: 0, y: end })
+            }
+            SelectView::None => return false,
+        };
+        self.select_view = view;
+        self.jump_to(end.y + 1, end.x + 1);
+        true
+    }
+
+    pub fn update_visual_pos(&mut self) {
+        if self.mode != Mode::Visual {
+            return;
         }
         match &self.select_view {
             SelectView::CharacterView(v) => {
@@ -389,8 +1453,15 @@ impl TextEditor {
                     end: self.cur_line - 1,
                 });
             }
-            SelectView::BlockView(_) => todo!(),
-            SelectView::None => return,
+            SelectView::BlockView(v) => {
+                let start = v.start;
+                let end = Coordinates {
+                    x: self.cur_pos.x - 1,
+                    y: self.cur_line - 1,
+                };
+                self.select_view = SelectView::BlockView(CharacterView { start, end });
+            }
+            SelectView::None => (),
         }
     }
 
@@ -405,126 +1476,2315 @@ impl TextEditor {
             dialog.size.0,
             dialog.size.1,
         );
-        write!(self.out, "{}", termion::cursor::Goto(x, y as u16)).unwrap();
+        write!(self.out, "{}", termion::cursor::Goto(x, y)).unwrap();
         write!(self.out, "{}", color::Bg(color::LightWhite)).unwrap();
         for i in 0..height {
-            write!(self.out, "{}", termion::cursor::Goto(x, y + i as u16)).unwrap();
+            write!(self.out, "{}", termion::cursor::Goto(x, y + i)).unwrap();
             for _ in 0..width {
                 write!(self.out, " ").unwrap();
             }
         }
-        for (i, line) in dialog.contents.iter().enumerate() {
-            write!(self.out, "{}", termion::cursor::Goto(x, y + i as u16)).unwrap();
-            write!(self.out, "{line}").unwrap();
+        for (i, line) in dialog.contents.iter().enumerate() {
+            write!(self.out, "{}", termion::cursor::Goto(x, y + i as u16)).unwrap();
+            write!(self.out, "{line}").unwrap();
+        }
+        write!(self.out, "{}", style::Reset).unwrap();
+    }
+    fn show_bar(&mut self) {
+        write!(
+            self.out,
+            "{}",
+            termion::cursor::Goto(1, self.terminal_size.1)
+        )
+        .unwrap();
+        match self.mode {
+            Mode::Command | Mode::Search => {
+                write!(
+                    self.out,
+                    "{}{}{}:{}",
+                    color::Fg(color::Yellow),
+                    style::Bold,
+                    self.mode,
+                    self.bar_text.line_at(0)
+                )
+                .unwrap();
+            }
+            _ if !self.bar_text.line_at(0).is_empty() => {
+                write!(
+                    self.out,
+                    "{}{}{}{}",
+                    color::Fg(color::Red),
+                    style::Bold,
+                    self.bar_text.line_at(0),
+                    style::Reset
+                )
+                .unwrap();
+            }
+            _ => {
+                write!(
+                    self.out,
+                    "{}{}{}{}{}",
+                    color::Bg(color::Green),
+                    color::Fg(color::Blue),
+                    style::Bold,
+                    self.status_line(),
+                    style::Reset
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    // the mode as shown in the status line, which tells the kinds of
+    // visual mode apart
+    fn mode_name(&self) -> String {
+        match (self.mode, &self.select_view) {
+            (Mode::Visual, SelectView::LineView(_)) => "VISUAL LINE".to_string(),
+            (Mode::Visual, SelectView::BlockView(_)) => "VISUAL BLOCK".to_string(),
+            (mode, _) => mode.to_string(),
+        }
+    }
+
+    // the file, `[+]` when it has unsaved changes and its type on the left,
+    // the position of the cursor as `line:col` and how far it is through
+    // the file on the right
+    fn status_line(&self) -> String {
+        let mut left = String::new();
+        if self.mode != Mode::Normal {
+            left.push_str(&format!("-- {} -- ", self.mode_name()));
+        }
+        left.push_str(if self.file_name.is_empty() {
+            "[No Name]"
+        } else {
+            &self.file_name
+        });
+        if self.modified {
+            left.push_str(" [+]");
+        }
+        if self.readonly {
+            left.push_str(" [RO]");
+        }
+        if let Some(file_type) = self.highlighter.file_type() {
+            left.push_str(&format!(" [{file_type}]"));
+        }
+        let mut right = String::new();
+        if self.showcmd {
+            right.push_str(&format!("{}   ", self.task));
+        }
+        right.push_str(&format!(
+            "{}:{}  {:>3}%",
+            self.cur_line,
+            self.cur_pos.x,
+            self.cur_line * 100 / self.text_length()
+        ));
+        let width = self.terminal_size.0 as usize;
+        let gap = width.saturating_sub(left.chars().count() + right.chars().count());
+        format!("{left}{}{right}", " ".repeat(gap.max(1)))
+    }
+
+    fn set_pos(&mut self, x: usize, y: usize) {
+        self.cur_pos.x = x;
+        self.cur_pos.y = y;
+        self.update_pos();
+    }
+    fn set_cur_line(&mut self, line: usize) {
+        self.cur_line = line;
+    }
+
+    fn set_cursor_style(&mut self, style: CursorStyle) {
+        match style {
+            CursorStyle::Bar => write!(self.out, "{}", termion::cursor::BlinkingBar),
+            CursorStyle::Block => write!(self.out, "{}", termion::cursor::BlinkingBlock),
+            CursorStyle::Underline => write!(self.out, "{}", termion::cursor::BlinkingUnderline),
+        }
+        .unwrap();
+    }
+
+    fn update_pos(&mut self) {
+        // the command line isn't scrolled with the text
+        let (x, y) = if self.mode == Mode::Command || self.mode == Mode::Search {
+            (self.cur_pos.x, self.cur_pos.y)
+        } else if self.wrap {
+            let (x, y) = self.wrapped_cursor_pos();
+            (x + self.number_width(), y)
+        } else {
+            let col = self.display_width(self.cur_line - 1, self.cur_pos.x - 1);
+            let x = col + 1 - self.view.left_col.min(col);
+            (x + self.number_width(), self.cur_pos.y - self.hidden_rows())
+        };
+        write!(self.out, "{}", termion::cursor::Goto(x as u16, y as u16)).unwrap();
+    }
+
+    // columns taken by line numbers, with the blank after them
+    fn number_width(&self) -> usize {
+        if !self.show_line_numbers && !self.relative_numbers {
+            return 0;
+        }
+        self.text_length().to_string().len().max(3) + 1
+    }
+
+    // columns left for the text
+    fn text_width(&self) -> usize {
+        (self.terminal_size.0 as usize)
+            .saturating_sub(self.number_width())
+            .max(1)
+    }
+
+    // what is shown left of `line` (from 0): its number or, with
+    // `relativenumber`, its distance from the cursor line, which itself
+    // shows its own number when `number` is also set
+    fn line_number(&self, line: usize) -> String {
+        let distance = line.abs_diff(self.cur_line - 1);
+        let number = if self.relative_numbers && (distance > 0 || !self.show_line_numbers) {
+            distance
+        } else {
+            line + 1
+        };
+        format!("{number:>width$} ", width = self.number_width() - 1)
+    }
+
+    // scroll so that `scrolloff` lines stay visible around the cursor,
+    // unless the view is at the start or the end of the file
+    fn keep_scrolloff(&mut self) {
+        let height = self.max_y() as usize;
+        let scrolloff = self.scrolloff.min(height.saturating_sub(1) / 2);
+        let line = self.cur_line - 1;
+        let top = self
+            .view
+            .lower_line()
+            .min(line.saturating_sub(scrolloff))
+            .max((line + scrolloff + 1).saturating_sub(height));
+        if top != self.view.lower_line() {
+            self.set_view_top(top);
+        }
+    }
+
+    // cells `c` takes when drawn at screen column `screen_col` (from 0): up
+    // to the next tab stop for a tab, two for wide chars such as CJK
+    fn cells_of(&self, c: char, screen_col: usize) -> usize {
+        match c {
+            '\t' => self.tab_width - screen_col % self.tab_width,
+            c => c.width().unwrap_or(1).max(1),
+        }
+    }
+
+    // screen width of the first `col` chars of `line`
+    fn display_width(&self, line: usize, col: usize) -> usize {
+        let text = self.text.line_at(line);
+        let mut width = 0;
+        for c in text.chars().take(col) {
+            width += self.cells_of(c, width);
+        }
+        // columns past the end of the line (virtual editing) take one cell
+        width + col.saturating_sub(text.chars().count())
+    }
+
+    fn expand_tabs(&self, text: &str) -> String {
+        let mut expanded = String::new();
+        for c in text.chars() {
+            if c == '\t' {
+                let n = self.tab_width - expanded.chars().count() % self.tab_width;
+                expanded.push_str(&" ".repeat(n));
+            } else {
+                expanded.push(c);
+            }
+        }
+        expanded
+    }
+
+    fn rows_of_line(&self, line: usize) -> usize {
+        let text = self.expand_tabs(&self.text.line_at(line));
+        wrap_line(&text, self.text_width()).len()
+    }
+
+    // screen position of the cursor when long lines are wrapped
+    fn wrapped_cursor_pos(&self) -> (usize, usize) {
+        let width = self.text_width();
+        let rows: usize = (self.view.lower_line()..self.cur_line - 1)
+            .map(|line| self.rows_of_line(line))
+            .sum();
+        let col = self.display_width(self.cur_line - 1, self.cur_pos.x - 1);
+        (col % width + 1, rows + col / width + 1)
+    }
+
+    // scroll down until every row of the cursor line fits on screen
+    fn scroll_to_wrapped_cursor(&mut self) {
+        while self.view.lower_line() + 1 < self.cur_line {
+            let rows: usize = (self.view.lower_line()..self.cur_line)
+                .map(|line| self.rows_of_line(line))
+                .sum();
+            if rows <= self.max_y() as usize {
+                break;
+            }
+            self.view.lower_line += 1;
+            self.view.upper_line = (self.view.upper_line + 1).min(self.text_length());
+            self.cur_pos.y -= 1;
+        }
+    }
+
+    // fit the view to a terminal of the new size, keeping the top line
+    // unless the cursor line would be below the view
+    fn resize(&mut self, width: u16, height: u16) {
+        self.terminal_size = Size(width, height);
+        let rows = self.max_y() as usize;
+        self.set_view_top(
+            self.view
+                .lower_line()
+                .max(self.cur_line.saturating_sub(rows)),
+        );
+    }
+
+    // `Ctrl-f`, scroll down a page, keeping two lines of the old page on
+    // screen, and move the cursor to the top line if it went off screen
+    fn page_down(&mut self) {
+        let page = self.max_y() as usize - 1;
+        let n = page.min(self.text_length() - self.view.upper_line());
+        if n == 0 {
+            self.jump_to(self.text_length(), self.cur_pos.x);
+            return;
+        }
+        self.view.move_down(n);
+        self.cur_line = self.cur_line.max(self.view.lower_line() + 1);
+        self.cur_pos.y = self.cur_line - self.view.lower_line();
+        self.cur_pos.x = self.cur_pos.x.min(self.len_of_cur_line()).max(1);
+    }
+
+    // `Ctrl-b`, the same as `page_down` in the other direction
+    fn page_up(&mut self) {
+        let page = self.max_y() as usize - 1;
+        let n = page.min(self.view.lower_line());
+        if n == 0 {
+            self.jump_to(1, self.cur_pos.x);
+            return;
+        }
+        self.view.move_up(n);
+        self.cur_line = self.cur_line.min(self.view.upper_line());
+        self.cur_pos.y = self.cur_line - self.view.lower_line();
+        self.cur_pos.x = self.cur_pos.x.min(self.len_of_cur_line()).max(1);
+    }
+
+    // `Ctrl-e` and `Ctrl-y`, scroll the view down or up by `n` lines. The
+    // cursor stays on its line unless that goes off screen, or into the
+    // `scrolloff` lines that would scroll the view back
+    fn scroll_lines(&mut self, n: usize, down: bool) {
+        let height = self.max_y() as usize;
+        let scrolloff = self.scrolloff.min(height.saturating_sub(1) / 2);
+        let last_top = self.text_length().saturating_sub(height);
+        let top = self.view.lower_line();
+        let top = if down {
+            (top + n).min(last_top)
+        } else {
+            top.saturating_sub(n)
+        };
+        let first = if top == 0 { 0 } else { top + scrolloff };
+        let last = if top == last_top {
+            self.text_length() - 1
+        } else {
+            top + height - 1 - scrolloff
+        };
+        self.cur_line = (self.cur_line - 1).clamp(first, last) + 1;
+        self.view.lower_line = top;
+        self.view.upper_line = (top + height).min(self.text_length());
+        self.cur_pos.y = self.cur_line - top;
+        self.cur_pos.x = self.cur_pos.x.min(self.len_of_cur_line()).max(1);
+    }
+
+    // scroll so that the view starts at line `top` (from 0), keeping the
+    // cursor line on screen and the screen filled at the end of the file
+    fn set_view_top(&mut self, top: usize) {
+        let height = self.max_y() as usize;
+        let top = top
+            .min(self.cur_line - 1)
+            .min(self.text_length().saturating_sub(height));
+        self.view.lower_line = top;
+        self.view.upper_line = (top + height).min(self.text_length());
+        self.cur_pos.y = self.cur_line - top;
+    }
+
+    // `gj`, move down by one screen row instead of one line
+    fn inc_display_row(&mut self) {
+        let width = self.text_width();
+        let col = self.cur_pos.x - 1;
+        if !self.wrap {
+            self.inc_y();
+        } else if col + width < self.text.len_of_line_at(self.cur_line - 1) {
+            self.cur_pos.x += width;
+        } else if self.cur_line < self.text_length() {
+            self.inc_y();
+            self.cur_pos.x = col % width + 1;
+        }
+    }
+
+    // `gk`, move up by one screen row instead of one line
+    fn dec_display_row(&mut self) {
+        let width = self.text_width();
+        let col = self.cur_pos.x - 1;
+        if !self.wrap {
+            self.dec_y();
+        } else if col >= width {
+            self.cur_pos.x -= width;
+        } else if self.cur_line > 1 {
+            self.dec_y();
+            let len = self.text.len_of_line_at(self.cur_line - 1);
+            self.cur_pos.x = len.saturating_sub(1) / width * width + col % width + 1;
+        }
+    }
+
+    // scroll the view horizontally so that column `x` (starts from 1) is visible
+    fn scroll_to_col(&mut self, x: usize) {
+        if self.wrap {
+            self.view.left_col = 0;
+            return;
+        }
+        let width = self.text_width();
+        let col = self.display_width(self.cur_line - 1, x.saturating_sub(1));
+        if col < self.view.left_col {
+            self.view.left_col = col;
+        } else if col >= self.view.left_col + width {
+            self.view.left_col = col + 1 - width;
+        }
+    }
+
+    fn flush_to_disk(&mut self) -> Result<(), EditorError> {
+        // trimmed in the buffer as well, so that it and its undo history
+        // match the written file
+        if self.trim_on_write && self.modifiable && !self.readonly {
+            self.trim_lines(LineRange {
+                first: 1,
+                last: self.text_length(),
+            });
+        }
+        let contents = self.text.to_file_contents(self.line_ending, self.eol);
+        fs::write(&self.file_name, &contents)
+            .map_err(|err| EditorError::Write(self.file_name.clone(), err))?;
+        // changes made before the write, as the trimming, are in the file
+        self.action_stack.take_changed();
+        self.modified = false;
+        self.add_recent();
+        self.action_stack
+            .write_undo_file(&self.file_name, &contents)?;
+        Ok(())
+    }
+
+    // `:w path`, write the text to another file. The buffer keeps its own
+    // file and stays modified, an existing file is only written over with `!`
+    fn write_copy(&mut self, path: &str, force: bool) {
+        if !force && Path::new(path).exists() {
+            self.show_message("E13: File exists (add ! to override)");
+            return;
+        }
+        let contents = self.text.to_file_contents(self.line_ending, self.eol);
+        if let Err(err) = fs::write(path, contents) {
+            self.report(EditorError::Write(path.to_string(), err));
+        }
+    }
+
+    // `:e path`, switch to the buffer of the file, opening it if needed
+    fn edit_file(&mut self, file_name: &str) {
+        if file_name.is_empty() {
+            self.show_message("E32: No file name");
+            return;
+        }
+        let opened = (0..self.buffers.len()).find(|&i| self.buffer_name(i) == file_name);
+        if let Some(index) = opened {
+            self.switch_buffer(index);
+            return;
+        }
+        let (text, line_ending, eol) = match Self::load_text(file_name) {
+            Ok(loaded) => loaded,
+            Err(err) => return self.report(err),
+        };
+        let view = TextView {
+            lower_line: 0,
+            upper_line: text.len().min(self.max_y() as usize),
+            left_col: 0,
+        };
+        let mut highlighter = HighLighter::new(file_name);
+        highlighter.detect_from_first_line(&text.line_at(0));
+        highlighter.copy_settings(&self.highlighter);
+        self.buffers.push(Some(Buffer {
+            text,
+            file_name: file_name.to_string(),
+            cur_pos: Coordinates { x: 1, y: 1 },
+            cur_line: 1,
+            view,
+            modified: false,
+            readonly: Self::is_readonly_file(file_name),
+            line_ending,
+            eol,
+            highlighter,
+            action_stack: ActionStack::from_undo_file(file_name),
+        }));
+        self.switch_buffer(self.buffers.len() - 1);
+        self.add_recent();
+    }
+
+    fn buffer_name(&self, index: usize) -> &str {
+        match &self.buffers[index] {
+            Some(buffer) => &buffer.file_name,
+            None => &self.file_name,
+        }
+    }
+
+    // `:mksession`, the open buffers with their cursor and view
+    fn session(&self) -> Session {
+        let buffers = self
+            .buffers
+            .iter()
+            .map(|buffer| match buffer {
+                Some(buffer) => SessionBuffer {
+                    file_name: buffer.file_name.clone(),
+                    line: buffer.cur_line,
+                    col: buffer.cur_pos.x,
+                    top: buffer.view.lower_line,
+                    left_col: buffer.view.left_col,
+                },
+                None => SessionBuffer {
+                    file_name: self.file_name.clone(),
+                    line: self.cur_line,
+                    col: self.cur_pos.x,
+                    top: self.view.lower_line,
+                    left_col: self.view.left_col,
+                },
+            })
+            .collect();
+        Session {
+            buffers,
+            active: self.buffer,
+        }
+    }
+
+    // `:source` and `-S`, open the files of `session` where they were left.
+    // Missing ones are opened empty, with a warning
+    fn restore_session(&mut self, session: Session) {
+        let mut missing = vec![];
+        for buffer in &session.buffers {
+            if !Path::new(&buffer.file_name).exists() {
+                missing.push(buffer.file_name.as_str());
+            }
+            self.edit_file(&buffer.file_name);
+            if self.file_name != buffer.file_name {
+                // it couldn't be read
+                continue;
+            }
+            self.jump_to(buffer.line, buffer.col);
+            let height = self.max_y() as usize;
+            self.set_view_top(buffer.top.max(self.cur_line.saturating_sub(height)));
+            self.view.left_col = buffer.left_col;
+        }
+        // the buffers may have been opened already, in another order
+        let active = &session.buffers[session.active].file_name;
+        if let Some(index) = (0..self.buffers.len()).find(|&i| self.buffer_name(i) == active) {
+            self.switch_buffer(index);
+        }
+        if !missing.is_empty() {
+            self.show_message(&format!(
+                "session: no such file, opened empty: {}",
+                missing.join(", ")
+            ));
+        }
+    }
+
+    // put the state of the active buffer away and take the one of `index`
+    fn switch_buffer(&mut self, index: usize) {
+        if index == self.buffer {
+            return;
+        }
+        if let Some(pos) = self.saved_pos.take() {
+            self.cur_pos = pos;
+        }
+        self.set_visual_mode(SelectView::None);
+        self.last_visual = None;
+        self.cursors.clear();
+        let buffer = self.buffers[index].take().unwrap();
+        let old = Buffer {
+            text: std::mem::replace(&mut self.text, buffer.text),
+            file_name: std::mem::replace(&mut self.file_name, buffer.file_name),
+            cur_pos: std::mem::replace(&mut self.cur_pos, buffer.cur_pos),
+            cur_line: std::mem::replace(&mut self.cur_line, buffer.cur_line),
+            view: std::mem::replace(&mut self.view, buffer.view),
+            modified: std::mem::replace(&mut self.modified, buffer.modified),
+            readonly: std::mem::replace(&mut self.readonly, buffer.readonly),
+            line_ending: std::mem::replace(&mut self.line_ending, buffer.line_ending),
+            eol: std::mem::replace(&mut self.eol, buffer.eol),
+            highlighter: std::mem::replace(&mut self.highlighter, buffer.highlighter),
+            action_stack: std::mem::replace(&mut self.action_stack, buffer.action_stack),
+        };
+        self.buffers[self.buffer] = Some(old);
+        self.buffer = index;
+    }
+
+    // `:ls`
+    fn show_buffers(&mut self) {
+        let contents: Vec<String> = (0..self.buffers.len())
+            .map(|i| {
+                let (active, modified) = match &self.buffers[i] {
+                    Some(buffer) => ("  ", buffer.modified),
+                    None => ("%a", self.modified),
+                };
+                let modified = if modified { "+" } else { " " };
+                format!(
+                    "{:3} {active} {modified} \"{}\"",
+                    i + 1,
+                    self.buffer_name(i)
+                )
+            })
+            .collect();
+        self.show_list(contents);
+    }
+
+    pub fn try_perform_command(&mut self) -> Option<Mode> {
+        assert!(self.mode == Mode::Command || self.mode == Mode::Search);
+        if self.mode == Mode::Command {
+            // commands may move the cursor, which is parked on the command
+            // line while typing
+            if let Some(pos) = self.saved_pos.take() {
+                self.cur_pos = pos;
+            }
+            let command = self.bar_text.line_at(0);
+            self.clear_message();
+            self.add_history(&command);
+            let (range, command) = LineRange::parse(&command, self.cur_line, self.text_length());
+            let name_len = command
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(command.len());
+            let (name, arg) = (&command[..name_len], command[name_len..].trim());
+            let lines = range.unwrap_or(LineRange {
+                first: self.cur_line,
+                last: self.cur_line,
+            });
+            match name {
+                "" if command.starts_with('!') => {
+                    let prg = &command[1..];
+                    match range {
+                        Some(lines) => self.filter_through(lines.first - 1, lines.last - 1, prg),
+                        // without a range the output is only shown
+                        None => match filter::pipe(prg, "") {
+                            Ok(output) => {
+                                self.show_list(output.lines().map(str::to_string).collect())
+                            }
+                            Err(err) => self.show_message(&err),
+                        },
+                    }
+                    Some(Mode::Normal)
+                }
+                // `:3` goes to line 3
+                "" => {
+                    self.push_jump();
+                    self.jump_to(lines.last, 1);
+                    self.move_to_first_char_of_line();
+                    Some(Mode::Normal)
+                }
+                "d" | "delete" => {
+                    if self.can_modify() {
+                        self.jump_to(lines.first, 1);
+                        self.delete_lines(lines.last - lines.first + 1);
+                    }
+                    Some(Mode::Normal)
+                }
+                "m" | "move" | "t" | "co" | "copy" => {
+                    match parse_address(arg, self.cur_line, self.text_length()) {
+                        Some((dest, "")) => self.transfer_lines(lines, dest, name.starts_with('m')),
+                        _ => self.show_message(&format!("E14: Invalid address: {arg}")),
+                    }
+                    Some(Mode::Normal)
+                }
+                "norm" | "normal" => {
+                    // blanks after the first one are keys as well
+                    let keys = &command[name_len..];
+                    let keys: Vec<Key> = keys
+                        .strip_prefix(' ')
+                        .unwrap_or(keys)
+                        .chars()
+                        .map(Key::Char)
+                        .collect();
+                    for line in lines.first..=lines.last.min(self.text_length()) {
+                        self.jump_to(line, 1);
+                        let mode = self.feed_keys(Mode::Normal, &keys);
+                        if mode == Mode::Exit {
+                            return Some(Mode::Exit);
+                        }
+                        // an unfinished command is ended as with Esc
+                        self.feed_keys(mode, &[Key::Esc]);
+                        self.task.clear();
+                    }
+                    Some(Mode::Normal)
+                }
+                "r" | "read" => {
+                    if !self.can_modify() {
+                        return Some(Mode::Normal);
+                    }
+                    let output = match arg.strip_prefix('!') {
+                        Some(prg) => filter::pipe(prg, ""),
+                        None if arg.is_empty() => Err("E32: No file name".to_string()),
+                        None => fs::read_to_string(arg)
+                            .map_err(|_| format!("E484: Can't open file {arg}")),
+                    };
+                    match output {
+                        Ok(output) => {
+                            let text = output.lines().map(str::to_string).collect();
+                            self.insert_lines_below(lines.last, text);
+                        }
+                        Err(err) => self.show_message(&err),
+                    }
+                    Some(Mode::Normal)
+                }
+                "s" | "substitute" => {
+                    match Substitute::parse(&command[name_len..]) {
+                        Ok(sub) => self.substitute(lines, sub),
+                        Err(err) => self.show_message(&err),
+                    }
+                    Some(Mode::Normal)
+                }
+                "sort" => {
+                    let lines = range.unwrap_or(LineRange {
+                        first: 1,
+                        last: self.text_length(),
+                    });
+                    let reverse = arg.starts_with('!');
+                    self.sort_lines(lines, reverse, arg.contains('n'));
+                    Some(Mode::Normal)
+                }
+                "trim" => {
+                    let lines = range.unwrap_or(LineRange {
+                        first: 1,
+                        last: self.text_length(),
+                    });
+                    self.trim_lines(lines);
+                    Some(Mode::Normal)
+                }
+                "stats" => {
+                    let stats = self.stats();
+                    self.show_message(&format!(
+                        "Col {} of {}; Line {} of {}; Words {}; Chars {}; Byte {} of {}",
+                        self.cur_pos.x,
+                        self.text.line_at(self.cur_line - 1).chars().count(),
+                        self.cur_line,
+                        stats.lines,
+                        stats.words,
+                        stats.chars,
+                        stats.byte,
+                        stats.bytes
+                    ));
+                    Some(Mode::Normal)
+                }
+                "q" => Some(Mode::Exit),
+                "w" => {
+                    let (force, path) = match arg.strip_prefix('!') {
+                        Some(path) => (true, path.trim()),
+                        None => (false, arg),
+                    };
+                    if !path.is_empty() && path != self.file_name {
+                        self.write_copy(path, force);
+                    } else if self.readonly && !force {
+                        self.show_message("E45: 'readonly' option is set (add ! to override)");
+                    } else if let Err(err) = self.flush_to_disk() {
+                        self.report(err);
+                    }
+                    Some(Mode::Normal)
+                }
+                "e" | "edit" => {
+                    self.edit_file(arg);
+                    Some(Mode::Normal)
+                }
+                "recent" => {
+                    self.open_recent(arg);
+                    Some(Mode::Normal)
+                }
+                "mks" | "mksession" => {
+                    let written = match arg {
+                        "" => Err("E471: Argument required".to_string()),
+                        path => self.session().write(path),
+                    };
+                    if let Err(err) = written {
+                        self.show_message(&err);
+                    }
+                    Some(Mode::Normal)
+                }
+                "so" | "source" => {
+                    match Session::read(arg) {
+                        Ok(session) => self.restore_session(session),
+                        Err(err) => self.show_message(&err),
+                    }
+                    Some(Mode::Normal)
+                }
+                "bn" | "bnext" => {
+                    self.switch_buffer((self.buffer + 1) % self.buffers.len());
+                    Some(Mode::Normal)
+                }
+                "bp" | "bprevious" => {
+                    let len = self.buffers.len();
+                    self.switch_buffer((self.buffer + len - 1) % len);
+                    Some(Mode::Normal)
+                }
+                "ls" => {
+                    self.show_buffers();
+                    Some(Mode::Normal)
+                }
+                "set" => {
+                    if let Err(err) = self.set_option(arg) {
+                        self.show_message(&err);
+                    }
+                    Some(Mode::Normal)
+                }
+                "earlier" | "later" => {
+                    match UndoStep::parse(arg) {
+                        Some(step) if name == "earlier" => self.undo_by(step),
+                        Some(step) => self.redo_by(step),
+                        None => self.show_message(&format!("E475: Invalid argument: {arg}")),
+                    }
+                    Some(Mode::Normal)
+                }
+                "colorscheme" => {
+                    if arg.is_empty() {
+                        self.show_themes();
+                    } else if let Err(err) = self.highlighter.set_theme(arg) {
+                        self.show_message(&err);
+                    }
+                    Some(Mode::Normal)
+                }
+                _ => {
+                    self.show_message(&format!("E492: Not an editor command: {command}"));
+                    Some(Mode::Normal)
+                }
+            }
+        } else {
+            let pattern = self.bar_text.line_at(0);
+            self.clear_message();
+            if let Some(pos) = self.saved_pos.take() {
+                self.cur_pos = pos;
+            }
+            self.restore_search_origin();
+            if !pattern.is_empty() {
+                self.last_search = Some(pattern);
+            }
+            self.search_forward = true;
+            self.search_next(true);
+            Some(Mode::Normal)
+        }
+    }
+
+    // names completed by Tab at the start of the command line
+    const COMMANDS: [&'static str; 26] = [
+        "bn",
+        "bnext",
+        "bp",
+        "bprevious",
+        "colorscheme",
+        "copy",
+        "delete",
+        "e",
+        "earlier",
+        "edit",
+        "later",
+        "ls",
+        "mksession",
+        "move",
+        "normal",
+        "q",
+        "read",
+        "recent",
+        "s",
+        "set",
+        "sort",
+        "source",
+        "stats",
+        "substitute",
+        "trim",
+        "w",
+    ];
+
+    // Tab on the command line, complete the last word as a command name
+    // or, after `:e` and `:w`, as a path
+    pub fn complete_command(&mut self) {
+        if let Some((head, candidates, i)) = self.completion.take() {
+            let i = (i + 1) % candidates.len();
+            self.bar_text = Text::new();
+            self.bar_text.push_line(format!("{head}{}", candidates[i]));
+            self.completion = Some((head, candidates, i));
+            return;
+        }
+        let line = self.bar_text.line_at(0);
+        let candidates = match line.split_once(' ') {
+            None => Self::COMMANDS
+                .iter()
+                .filter(|c| c.starts_with(&line))
+                .map(|c| c.to_string())
+                .collect(),
+            Some(("e" | "edit" | "w" | "mksession" | "source", _)) => {
+                let word = line.rsplit(' ').next().unwrap();
+                Self::complete_path(word)
+            }
+            Some(_) => vec![],
+        };
+        if candidates.is_empty() {
+            return;
+        }
+        let head = line[..line.len() - line.rsplit(' ').next().unwrap().len()].to_string();
+        self.bar_text = Text::new();
+        self.bar_text.push_line(format!("{head}{}", candidates[0]));
+        if candidates.len() > 1 {
+            self.show_list(candidates.clone());
+            self.completion = Some((head, candidates, 0));
+        }
+    }
+
+    // the entries of the directory in `word` whose names start with the
+    // rest of it, directories ending with '/'
+    fn complete_path(word: &str) -> Vec<String> {
+        let (dir, name) = match word.rfind('/') {
+            Some(i) => word.split_at(i + 1),
+            None => ("", word),
+        };
+        let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+            return vec![];
+        };
+        let mut candidates: Vec<String> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let file_name = entry.file_name().into_string().ok()?;
+                if !file_name.starts_with(name) || file_name.starts_with('.') && name.is_empty() {
+                    return None;
+                }
+                let slash = if entry.path().is_dir() { "/" } else { "" };
+                Some(format!("{dir}{file_name}{slash}"))
+            })
+            .collect();
+        candidates.sort();
+        candidates
+    }
+
+    fn add_history(&mut self, command: &str) {
+        self.history_pos = None;
+        if command.is_empty() {
+            return;
+        }
+        self.history.retain(|c| c != command);
+        self.history.push(command.to_string());
+    }
+
+    // Up and Down on the command line, show the previous or the next entry
+    // starting with the typed text, and the typed text itself after the
+    // newest one
+    pub fn browse_history(&mut self, older: bool) {
+        let (pos, typed) = match self.history_pos.take() {
+            Some((pos, typed)) => (pos, typed),
+            None => (self.history.len(), self.bar_text.line_at(0)),
+        };
+        let matches = |i: &usize| self.history[*i].starts_with(&typed);
+        let found = if older {
+            (0..pos).rev().find(matches)
+        } else {
+            (pos + 1..self.history.len()).find(matches)
+        };
+        let line = match found {
+            Some(i) => {
+                self.history_pos = Some((i, typed));
+                self.history[i].clone()
+            }
+            None if older && pos < self.history.len() => {
+                self.history_pos = Some((pos, typed));
+                return;
+            }
+            None => typed,
+        };
+        self.bar_text = Text::new();
+        self.bar_text.push_line(line);
+    }
+
+    fn last_search_pattern(&mut self) -> Option<String> {
+        if self.last_search.is_none() {
+            self.show_message("E35: No previous regular expression");
+        }
+        self.last_search.clone()
+    }
+
+    // the text a search for `pattern` looks for, and whether it has to be a
+    // whole word, as with the `\<word\>` of `*` and `#`
+    fn split_pattern(pattern: &str) -> (&str, bool) {
+        match pattern
+            .strip_prefix("\\<")
+            .and_then(|word| word.strip_suffix("\\>"))
+        {
+            Some(word) => (word, true),
+            None => (pattern, false),
+        }
+    }
+
+    // whether the `len` chars found at `at` are a match, see `split_pattern`
+    fn is_match_at(&self, at: Coordinates, len: usize, whole_word: bool) -> bool {
+        !whole_word || self.is_whole_word(at, len)
+    }
+
+    // the match nearest to the cursor, skipping the one under it
+    fn match_after_cursor(&self, pattern: &str, forward: bool) -> Option<Coordinates> {
+        let (pattern, whole_word) = Self::split_pattern(pattern);
+        let ignore_case = self.ignores_case(pattern);
+        let len = pattern.chars().count();
+        let mut from = Coordinates {
+            x: self.cur_line - 1,
+            y: self.cur_pos.x - 1,
+        };
+        let mut first = None;
+        loop {
+            from = self.next_search_start(from, forward);
+            let found = if forward {
+                self.text.find(from, pattern, ignore_case, true)
+            } else {
+                self.text.rfind(from, pattern, ignore_case, true)
+            }?;
+            if self.is_match_at(found, len, whole_word) {
+                return Some(found);
+            }
+            // went through every match without a whole word
+            if first == Some(found) {
+                return None;
+            }
+            first = first.or(Some(found));
+            from = found;
+        }
+    }
+
+    // where to look for the match next to the one at `from` (x is the
+    // line, y is the column)
+    fn next_search_start(&self, from: Coordinates, forward: bool) -> Coordinates {
+        if forward {
+            Coordinates {
+                x: from.x,
+                y: from.y + 1,
+            }
+        } else if from.y > 0 {
+            Coordinates {
+                x: from.x,
+                y: from.y - 1,
+            }
+        } else {
+            Coordinates {
+                x: (from.x + self.text_length() - 1) % self.text_length(),
+                y: usize::MAX,
+            }
+        }
+    }
+
+    fn ignores_case(&self, pattern: &str) -> bool {
+        self.ignorecase && !(self.smartcase && pattern.chars().any(char::is_uppercase))
+    }
+
+    // `*` and `#`, search for the word under the cursor as a whole word
+    pub fn search_word_under_cursor(&mut self, forward: bool) {
+        let Some((start, end)) = self.word_at_cursor() else {
+            self.show_message("E348: No string under cursor");
+            return;
+        };
+        let word: String = self
+            .text
+            .line_at(self.cur_line - 1)
+            .chars()
+            .skip(start)
+            .take(end - start)
+            .collect();
+        // so that `#` skips the word the cursor is in
+        self.cur_pos.x = start + 1;
+        self.last_search = Some(format!("\\<{word}\\>"));
+        self.search_forward = forward;
+        self.search_next(forward);
+    }
+
+    pub fn search_next(&mut self, forward: bool) {
+        let Some(pattern) = self.last_search_pattern() else {
+            return;
+        };
+        match self.match_after_cursor(&pattern, forward) {
+            Some(pos) => {
+                self.push_jump();
+                self.jump_to(pos.x + 1, pos.y + 1);
+            }
+            None => self.show_message(&format!("E486: Pattern not found: {pattern}")),
+        }
+    }
+
+    // with `incsearch`, move to the first match of the pattern typed so far.
+    // While typing, `cur_pos` may be parked on the command line with the
+    // real cursor kept in `saved_pos`, so the jump is done on the latter
+    pub fn preview_search(&mut self) {
+        let Some((line, pos)) = self.search_origin else {
+            return;
+        };
+        if !self.incsearch {
+            return;
+        }
+        let parked = self
+            .saved_pos
+            .take()
+            .map(|saved| std::mem::replace(&mut self.cur_pos, saved));
+        self.jump_to(line, pos.x);
+        let pattern = self.bar_text.line_at(0);
+        if let Some(pos) = self.match_after_cursor(&pattern, true) {
+            self.jump_to(pos.x + 1, pos.y + 1);
+        }
+        if let Some(bar) = parked {
+            self.saved_pos = Some(std::mem::replace(&mut self.cur_pos, bar));
+        }
+    }
+
+    // go back to where the search started, when it is canceled or before
+    // searching for the final pattern
+    pub fn restore_search_origin(&mut self) {
+        let Some((line, pos)) = self.search_origin.take() else {
+            return;
+        };
+        if let Some(saved) = self.saved_pos.take() {
+            self.cur_pos = saved;
+        }
+        self.jump_to(line, pos.x);
+    }
+
+    // select the match under the cursor or the next one, like `gn`/`gN`
+    pub fn select_match(&mut self, forward: bool) -> bool {
+        let Some(pattern) = self.last_search_pattern() else {
+            return false;
+        };
+        let (word, whole_word) = Self::split_pattern(&pattern);
+        let len = word.chars().count();
+        let cur = Coordinates {
+            x: self.cur_line - 1,
+            y: self.cur_pos.x - 1,
+        };
+        let ignore_case = self.ignores_case(word);
+        let start = match self.text.rfind(cur, word, ignore_case, true) {
+            Some(pos)
+                if pos.x == cur.x
+                    && pos.y + len > cur.y
+                    && self.is_match_at(pos, len, whole_word) =>
+            {
+                Some(pos)
+            }
+            _ => self.match_after_cursor(&pattern, forward),
+        };
+        let Some(start) = start else {
+            self.show_message(&format!("E486: Pattern not found: {pattern}"));
+            return false;
+        };
+        self.jump_to(start.x + 1, start.y + len);
+        self.set_visual_mode(SelectView::CharacterView(CharacterView {
+            start: Coordinates {
+                x: start.y,
+                y: start.x,
+            },
+            end: Coordinates {
+                x: start.y + len - 1,
+                y: start.x,
+            },
+        }));
+        true
+    }
+
+    // move the cursor to `line` and `col` (both start from 1),
+    // scrolling the view when needed
+    fn jump_to(&mut self, line: usize, col: usize) {
+        while self.cur_line < line && self.cur_line < self.text_length() {
+            self.inc_y();
+        }
+        while self.cur_line > line && self.cur_line > 1 {
+            self.dec_y();
+        }
+        self.cur_pos.x = col.min(self.len_of_cur_line()).max(1);
+    }
+
+    const MAX_JUMPS: usize = 100;
+
+    fn stats(&self) -> Stats {
+        let lines = self.text_length();
+        let mut stats = Stats {
+            lines,
+            words: 0,
+            // the line breaks between lines
+            chars: lines - 1,
+            bytes: lines - 1,
+            byte: self.cur_line,
+        };
+        for i in 0..lines {
+            let line = self.text.line_at(i);
+            stats.words += line.split(Self::is_blank).filter(|w| !w.is_empty()).count();
+            stats.chars += line.chars().count();
+            stats.bytes += line.len();
+            if i + 1 < self.cur_line {
+                stats.byte += line.len();
+            } else if i + 1 == self.cur_line {
+                let col = self.cur_pos.x - 1;
+                stats.byte += line.char_indices().nth(col).map_or(line.len(), |(i, _)| i);
+            }
+        }
+        stats
+    }
+
+    // remember the cursor position before a jump, a line is in the jump
+    // list only once
+    fn push_jump(&mut self) {
+        self.jumps.truncate(self.jump_index);
+        let line = self.cur_line;
+        self.jumps.retain(|&(l, _)| l != line);
+        self.jumps.push((line, self.cur_pos.x));
+        if self.jumps.len() > Self::MAX_JUMPS {
+            self.jumps.remove(0);
+        }
+        self.jump_index = self.jumps.len();
+    }
+
+    // `Ctrl-o`, go back to where the last jump was made from
+    pub fn jump_back(&mut self) {
+        if self.jump_index == 0 {
+            return;
+        }
+        if self.jump_index == self.jumps.len() {
+            // so that `Ctrl-i` can come back here
+            self.push_jump();
+            self.jump_index -= 1;
+        }
+        if self.jump_index == 0 {
+            return;
+        }
+        self.jump_index -= 1;
+        let (line, col) = self.jumps[self.jump_index];
+        self.jump_to(line, col);
+    }
+
+    // `Ctrl-i`, undo a `Ctrl-o`
+    pub fn jump_forward(&mut self) {
+        if self.jump_index + 1 >= self.jumps.len() {
+            return;
+        }
+        self.jump_index += 1;
+        let (line, col) = self.jumps[self.jump_index];
+        self.jump_to(line, col);
+    }
+
+    // `:set option`, `:set nooption` or `:set option=value`
+    fn set_option(&mut self, option: &str) -> Result<(), String> {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        match (name, value) {
+            ("modifiable" | "ma", None) => self.modifiable = true,
+            ("nomodifiable" | "noma", None) => self.modifiable = false,
+            ("fileformat" | "ff", Some("unix")) => self.line_ending = LineEnding::Lf,
+            ("fileformat" | "ff", Some("dos")) => self.line_ending = LineEnding::Crlf,
+            ("endofline" | "eol", None) => self.eol = true,
+            ("noendofline" | "noeol", None) => self.eol = false,
+            ("readonly" | "ro", None) => self.readonly = true,
+            ("noreadonly" | "noro", None) => self.readonly = false,
+            ("incsearch" | "is", None) => self.incsearch = true,
+            ("noincsearch" | "nois", None) => self.incsearch = false,
+            ("ignorecase" | "ic", None) => self.ignorecase = true,
+            ("noignorecase" | "noic", None) => self.ignorecase = false,
+            ("smartcase" | "scs", None) => self.smartcase = true,
+            ("nosmartcase" | "noscs", None) => self.smartcase = false,
+            ("autopairs", None) => self.autopairs = true,
+            ("noautopairs", None) => self.autopairs = false,
+            ("spelllang" | "spl", Some(value)) => self.spell.set_lang(value)?,
+            ("spellfile" | "spf", Some(value)) => self.spell.set_spellfile(value)?,
+            ("equalprg" | "ep", Some(value)) => self.equalprg = value.to_string(),
+            ("hlws", None) => self.highlight_trailing_ws = true,
+            ("nohlws", None) => self.highlight_trailing_ws = false,
+            ("spell", None) => self.spell_check = true,
+            ("nospell", None) => self.spell_check = false,
+            ("list", None) => self.list = true,
+            ("nolist", None) => self.list = false,
+            ("wrap", None) => self.wrap = true,
+            ("nowrap", None) => self.wrap = false,
+            ("expandtab" | "et", None) => self.expandtab = true,
+            ("noexpandtab" | "noet", None) => self.expandtab = false,
+            ("number" | "nu", None) => self.show_line_numbers = true,
+            ("nonumber" | "nonu", None) => self.show_line_numbers = false,
+            ("relativenumber" | "rnu", None) => self.relative_numbers = true,
+            ("norelativenumber" | "nornu", None) => self.relative_numbers = false,
+            ("scrolloff" | "so", Some(value)) => match value.parse() {
+                Ok(n) => self.scrolloff = n,
+                _ => return Err(format!("E521: Number required after =: {option}")),
+            },
+            ("colorcolumn" | "cc", Some(value)) => match value.parse() {
+                Ok(n) => self.colorcolumn = n,
+                _ => return Err(format!("E521: Number required after =: {option}")),
+            },
+            ("textwidth" | "tw", Some(value)) => match value.parse() {
+                Ok(n) => self.textwidth = n,
+                _ => return Err(format!("E521: Number required after =: {option}")),
+            },
+            ("trimonwrite", None) => self.trim_on_write = true,
+            ("notrimonwrite", None) => self.trim_on_write = false,
+            ("colormode", Some(value)) => match ColorMode::parse(value) {
+                Some(mode) => self.highlighter.set_color_mode(mode),
+                None => return Err(format!("E474: Invalid argument: {option}")),
+            },
+            ("debuglog", Some("")) | ("nodebuglog", None) => self.debug_log = None,
+            ("debuglog", Some(path)) => self.debug_log = Some(path.to_string()),
+            ("showcmd" | "sc", None) => self.showcmd = true,
+            ("noshowcmd" | "nosc", None) => self.showcmd = false,
+            ("virtualedit" | "ve", Some("block")) => self.virtualedit_block = true,
+            ("virtualedit" | "ve", Some("")) => self.virtualedit_block = false,
+            ("foldmethod" | "fdm", Some("manual")) => self.fold_indent = false,
+            ("foldmethod" | "fdm", Some("indent")) => {
+                self.fold_indent = true;
+                self.refresh_folds();
+                // every fold starts closed, as with `foldlevel=0`
+                self.set_folds_closed(true);
+            }
+            ("tabstop" | "ts", Some(value)) => match value.parse() {
+                Ok(n) if n > 0 => self.tab_width = n,
+                _ => return Err(format!("E487: Argument must be positive: {option}")),
+            },
+            _ => return Err(format!("E518: Unknown option: {option}")),
+        }
+        Ok(())
+    }
+
+    // settings of the config file, the problems found are shown as messages
+    fn apply_config(&mut self, config: &Config) {
+        for warning in &config.warnings {
+            self.show_message(&format!("~/.vimrsrc: {warning}"));
+        }
+        for option in &config.options {
+            if let Err(err) = self.set_option(option) {
+                self.show_message(&format!("~/.vimrsrc: {err}"));
+            }
+        }
+        if let Some(theme) = &config.theme {
+            if let Err(err) = self.highlighter.set_theme(theme) {
+                self.show_message(&format!("~/.vimrsrc: {err}"));
+            }
+        }
+    }
+
+    fn show_themes(&mut self) {
+        let mut themes = self.highlighter.themes();
+        themes.sort();
+        self.show_list(themes);
+    }
+
+    // a dialog of one item per line, right above the status bar
+    fn show_list(&mut self, contents: Vec<String>) {
+        let width = contents
+            .iter()
+            .map(|c| c.chars().count())
+            .max()
+            .unwrap_or(0) as u16;
+        let height = contents.len() as u16;
+        self.dialogs.push(Dialog {
+            pos: Coordinates {
+                x: 1,
+                y: self.max_y().saturating_sub(height) as usize + 1,
+            },
+            size: Size(width, height),
+            contents,
+        });
+    }
+
+    pub const NOT_MODIFIABLE: &'static str = "E21: Cannot make changes, 'modifiable' is off";
+    pub const READONLY: &'static str = "W10: Warning: Changing a readonly file";
+
+    // every handler that changes the buffer has to ask here first
+    pub fn can_modify(&mut self) -> bool {
+        if !self.modifiable {
+            self.show_message(Self::NOT_MODIFIABLE);
+        } else if self.readonly {
+            self.show_message(Self::READONLY);
+        }
+        self.modifiable && !self.readonly
+    }
+
+    pub fn show_message(&mut self, message: &str) {
+        self.bar_text = Text::new();
+        self.bar_text.push_line(message.to_string());
+    }
+
+    fn debug(&self, message: fmt::Arguments) {
+        let Some(path) = &self.debug_log else {
+            return;
+        };
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+            let _ = writeln!(file, "{message}");
+        }
+    }
+
+    pub fn report(&mut self, err: EditorError) {
+        self.show_message(&err.to_string());
+    }
+
+    pub fn clear_message(&mut self) {
+        self.bar_text = Text::new();
+    }
+
+    pub fn try_perform_task(&mut self) -> Mode {
+        let mut mode = Mode::Normal;
+        self.processing_task = true;
+        // before the registers, as the key after `gq` is no register name
+        if self.task.ends_with("gqq") {
+            let count = self.task.num().unwrap_or(1);
+            self.task.clear();
+            let first = self.cur_line - 1;
+            if self.can_modify() {
+                self.format_lines(first, (first + count).min(self.text_length()) - 1);
+            }
+        } else if self.task.ends_with("gqap") || self.task.ends_with("gqip") {
+            self.task.clear();
+            let (first, last) = self.paragraph();
+            if self.can_modify() {
+                self.format_lines(first, last);
+            }
+        } else if self.task.ends_with("gq")
+            || self.task.ends_with("gqa")
+            || self.task.ends_with("gqi")
+        {
+            // the rest of the motion is still to come
+        } else if self.task.to_string().contains("gq") {
+            let count = self.task.num().unwrap_or(1);
+            let lines = self.line_motion_range(count);
+            self.task.clear();
+            match lines {
+                Some((first, last)) => {
+                    if self.can_modify() {
+                        self.format_lines(first, last);
+                    }
+                }
+                None => {
+                    self.show_message("gq: only q, ap, ip, j, k and G are supported as motions")
+                }
+            }
+        } else if self.task.ends_with("dw") {
+            let count = self.task.num().unwrap_or(1);
+            self.task.clear();
+            self.repeat_change(LastChange::DeleteWords(count));
+        } else if self.task.ends_with("diw") {
+            self.task.clear();
+            self.repeat_change(LastChange::DeleteInnerWord);
+        } else if self.task.ends_with("ciw") {
+            self.task.clear();
+            mode = self.repeat_change(LastChange::ChangeInnerWord(vec![]));
+        } else if self.task.ends_with(">>") || self.task.ends_with("<<") {
+            let count = self.task.num().unwrap_or(1);
+            let right = self.task.ends_with(">>");
+            self.task.clear();
+            self.repeat_change(LastChange::Shift(count, right));
+        } else if let Some(name) = self.task.register_after('q') {
+            self.task.clear();
+            if name.is_ascii_lowercase() {
+                self.recording = Some((name, vec![]));
+            }
+        } else if let Some(name) = self.task.register_after('@') {
+            let count = self.task.num().unwrap_or(1);
+            self.task.clear();
+            mode = self.replay_macro(name, count);
+        } else if self.task.ends_with("gj") {
+            self.inc_display_row();
+            self.task.clear();
+        } else if self.task.ends_with("gk") {
+            self.dec_display_row();
+            self.task.clear();
+        } else if self.task.ends_with("ge") || self.task.ends_with("gE") {
+            let class = if self.task.ends_with("ge") {
+                Self::word_class
+            } else {
+                Self::big_word_class
+            };
+            let count = self.task.num().unwrap_or(1);
+            self.task.clear();
+            for _ in 0..count {
+                self.backward_to_end_of_prev_word(class);
+            }
+        } else if self.task.ends_with("d)") || self.task.ends_with("d(") {
+            let count = self.task.num().unwrap_or(1);
+            let forward = self.task.ends_with("d)");
+            self.task.clear();
+            self.delete_sentences(count, forward);
+        } else if self.task.ends_with("g_") {
+            let count = self.task.num().unwrap_or(1);
+            self.task.clear();
+            self.jump_to(self.cur_line + count - 1, 1);
+            self.move_to_last_char_of_line();
+        } else if self.task.ends_with("gJ") {
+            let count = self.task.num().unwrap_or(2);
+            self.task.clear();
+            if self.can_modify() {
+                self.join_lines(count, false);
+            }
+        } else if self.task.ends_with("za") {
+            self.task.clear();
+            self.toggle_fold();
+        } else if self.task.ends_with("zR") || self.task.ends_with("zM") {
+            let closed = self.task.ends_with("zM");
+            self.task.clear();
+            self.set_folds_closed(closed);
+        } else if self.task.to_string().contains("zf") {
+            let count = self.task.num().unwrap_or(1);
+            // none while the motion is still to come
+            if let Some((first, last)) = self.line_motion_range(count) {
+                self.task.clear();
+                self.create_fold(first, last);
+            }
+        } else if self.task.ends_with("zz") {
+            self.task.clear();
+            let half = self.max_y() as usize / 2;
+            self.set_view_top((self.cur_line - 1).saturating_sub(half));
+        } else if self.task.ends_with("zt") {
+            self.task.clear();
+            self.set_view_top(self.cur_line - 1);
+        } else if self.task.ends_with("zb") {
+            self.task.clear();
+            self.set_view_top(self.cur_line.saturating_sub(self.max_y() as usize));
+        } else if self.task.is_movement() {
+            // it is guaranteed that current tasks have num
+            assert!(self.task.has_num());
+            let n = self.task.num().unwrap();
+            let key = *self.task.last_task().unwrap();
+            for _ in 0..n {
+                Mode::handle_normal(self, key);
+            }
+            self.task.clear();
+        } else if self.task.last_two_task() == Some("dd".to_string()) {
+            let count = self.task.num().unwrap_or(1);
+            self.task.clear();
+            // a closed fold is deleted as the single line it shows as
+            if let Some((start, _)) = self.closed_fold_at(self.cur_line - 1) {
+                self.jump_to(start + 1, 1);
+            }
+            let count = self.folded_span(self.cur_line - 1, count);
+            self.delete_lines(count);
+        } else if self.task.ends_with("gv") {
+            self.task.clear();
+            if self.reselect_last_visual() {
+                mode = Mode::Visual;
+            }
+        } else if self.task.ends_with("gg") {
+            self.task.clear();
+            self.push_jump();
+            self.jump_to(1, 1);
+            self.move_to_first_char_of_line();
+        } else if self.task.last_two_task() == Some("==".to_string()) {
+            let count = self.task.num().unwrap_or(1);
+            self.task.clear();
+            let first = self.cur_line - 1;
+            self.filter_lines(first, (first + count).min(self.text_length()) - 1);
+        } else if self.task.ends_with("=G") {
+            self.task.clear();
+            self.filter_lines(self.cur_line - 1, self.text_length() - 1);
+        } else if self.task.ends_with("zg") {
+            self.task.clear();
+            self.add_word_to_dictionary();
+        } else if self.task.ends_with("gcc") {
+            self.task.clear();
+            self.toggle_comment(self.cur_line - 1, self.cur_line - 1);
+        } else if self.task.last_two_task() == Some("cc".to_string()) {
+            self.task.clear();
+            mode = Mode::handle_normal(self, Key::Char('S'));
+        } else if self.task.ends_with("gn") || self.task.ends_with("gN") {
+            let forward = self.task.ends_with("gn");
+            let operator = self.task.to_string().chars().rev().nth(2);
+            self.task.clear();
+            if self.select_match(forward) {
+                mode = match operator {
+                    Some('d') => {
+                        self.delete_selected();
+                        self.set_visual_mode(SelectView::None);
+                        Mode::Normal
+                    }
+                    Some('c') => self.repeat_change(LastChange::ChangeMatch(forward, vec![])),
+                    _ => Mode::Visual,
+                };
+            }
+        }
+        self.processing_task = false;
+        mode
+    }
+
+    // first and last line (0-based) from the cursor to where the `j`, `k`
+    // or `G` ending the task moves it, `count` times. None for other keys
+    fn line_motion_range(&self, count: usize) -> Option<(usize, usize)> {
+        let line = self.cur_line - 1;
+        match self.task.last_task() {
+            Some(Key::Char('j')) => Some((line, (line + count).min(self.text_length() - 1))),
+            Some(Key::Char('k')) => Some((line.saturating_sub(count), line)),
+            Some(Key::Char('G')) => Some((line, self.text_length() - 1)),
+            _ => None,
+        }
+    }
+
+    // `Esc` after `3ifoo`, the text typed since `3i` is inserted twice more,
+    // each time on a new line for `o` and `O`
+    pub fn repeat_insert(&mut self) {
+        let count = self.task.num().unwrap_or(1);
+        let entry = self.task.last_task().copied();
+        self.task.clear();
+        let Some(Key::Char(c @ ('i' | 'a' | 'I' | 'A' | 'o' | 'O'))) = entry else {
+            return;
+        };
+        let Some(action) = self.action_stack.current() else {
+            return;
+        };
+        let mut keys = action.contents;
+        if c == 'o' || c == 'O' {
+            keys.insert(0, Key::Char('\n'));
+        }
+        // replayed as the recorded keys are, which already contain the
+        // indent and the closing chars of pairs
+        self.processing_action = true;
+        for _ in 1..count {
+            for &key in &keys {
+                Mode::handle_insert(self, key);
+            }
+        }
+        self.processing_action = false;
+        for _ in 1..count {
+            for &key in &keys {
+                self.action_stack.append_key_to_top(key);
+            }
+        }
+    }
+
+    // `q` while recording, keep the keys typed since `qa` in register a
+    pub fn stop_recording(&mut self) {
+        if let Some((name, mut keys)) = self.recording.take() {
+            // the `q` that stopped the recording
+            keys.pop();
+            self.registers.set_recorded(name, keys);
+        }
+    }
+
+    // `@a`, feed the keys recorded in register a to the editor `count`
+    // times, `@@` replays the register replayed last
+    fn replay_macro(&mut self, name: char, count: usize) -> Mode {
+        let name = match (name, self.last_macro) {
+            ('@', Some(last)) => last,
+            ('@', None) => {
+                self.show_message("E748: No previously used register");
+                return Mode::Normal;
+            }
+            _ => name,
+        };
+        let Some(keys) = self.registers.recorded(name).cloned() else {
+            return Mode::Normal;
+        };
+        self.last_macro = Some(name);
+        // they may start tasks of their own
+        self.processing_task = false;
+        let mut mode = Mode::Normal;
+        for _ in 0..count {
+            mode = self.feed_keys(mode, &keys);
+            if mode == Mode::Exit {
+                break;
+            }
+        }
+        mode
+    }
+
+    // handle `keys` as if they were typed in `mode`, returning the mode
+    // they leave the editor in
+    fn feed_keys(&mut self, mut mode: Mode, keys: &[Key]) -> Mode {
+        // the keys are part of the command being recorded already
+        let recording = self.recording.take();
+        for &key in keys {
+            self.mode = mode;
+            mode = mode.handle(self, key);
+            if mode == Mode::Exit {
+                break;
+            }
+        }
+        self.recording = recording;
+        mode
+    }
+
+    // comment out lines `first..=last` (0-based), or uncomment them when
+    // all of them are commented already, as one undoable action. Blank
+    // lines are left alone
+    pub fn toggle_comment(&mut self, first: usize, last: usize) {
+        let Some(leader) = comments::comment_leader(&self.file_name) else {
+            self.show_message("No comment leader for this file type");
+            return;
+        };
+        let (line, col) = (self.cur_line, self.cur_pos.x);
+        let old: Vec<String> = (first..=last).map(|i| self.text.line_at(i)).collect();
+        let is_blank = |line: &String| line.trim().is_empty();
+        let commented = old
+            .iter()
+            .filter(|line| !is_blank(line))
+            .all(|line| line.trim_start().starts_with(leader));
+        let new = old
+            .iter()
+            .map(|line| {
+                if is_blank(line) {
+                    return line.clone();
+                }
+                let indent = Self::leading_whitespace(line);
+                let rest = &line[indent.len()..];
+                if commented {
+                    let rest = &rest[leader.len()..];
+                    format!("{indent}{}", rest.strip_prefix(' ').unwrap_or(rest))
+                } else {
+                    format!("{indent}{leader} {rest}")
+                }
+            })
+            .collect();
+        self.change_lines(first, old, new);
+        self.jump_to(first + 1, if first + 1 == line { col } else { 1 });
+    }
+
+    // first and last line (0-based) of the selection
+    pub fn selected_lines(&self) -> Option<(usize, usize)> {
+        match Self::sort_select_view(&self.select_view) {
+            SelectView::CharacterView(v) | SelectView::BlockView(v) => Some((v.start.y, v.end.y)),
+            SelectView::LineView(v) => Some((v.start, v.end)),
+            SelectView::None => None,
+        }
+    }
+
+    // char columns [start, end) of the word under the cursor
+    fn word_at_cursor(&self) -> Option<(usize, usize)> {
+        let is_word = |c: &char| Self::is_alphabet(*c) || *c == '_';
+        let chars: Vec<char> = self.text.line_at(self.cur_line - 1).chars().collect();
+        let col = self.cur_pos.x - 1;
+        if !chars.get(col).is_some_and(is_word) {
+            return None;
+        }
+        let start = chars[..col]
+            .iter()
+            .rposition(|c| !is_word(c))
+            .map_or(0, |i| i + 1);
+        let end = chars[col..]
+            .iter()
+            .position(|c| !is_word(c))
+            .map_or(chars.len(), |i| col + i);
+        Some((start, end))
+    }
+
+    fn is_whole_word(&self, at: Coordinates, len: usize) -> bool {
+        let is_word = |c: &char| Self::is_alphabet(*c) || *c == '_';
+        let chars: Vec<char> = self.text.line_at(at.x).chars().collect();
+        (at.y == 0 || !is_word(&chars[at.y - 1])) && !chars.get(at.y + len).is_some_and(is_word)
+    }
+
+    // `Ctrl-d`: add a cursor at the next occurrence of the word under the
+    // main cursor, after the last cursor added
+    pub fn add_cursor_at_next_match(&mut self) {
+        let Some((start, end)) = self.word_at_cursor() else {
+            return;
+        };
+        let line = self.cur_line - 1;
+        let word: String = self
+            .text
+            .line_at(line)
+            .chars()
+            .skip(start)
+            .take(end - start)
+            .collect();
+        let offset = self.cur_pos.x - 1 - start;
+        let main = Coordinates { x: line, y: start };
+        let mut from = self.cursors.last().map_or(main, |cursor| Coordinates {
+            x: cursor.x,
+            y: cursor.y - offset,
+        });
+        loop {
+            from.y += 1;
+            let Some(found) = self.text.find(from, &word, false, true) else {
+                return;
+            };
+            // wrapped around to the word under the main cursor
+            if found == main {
+                self.show_message(&format!("No more matches of {word}"));
+                return;
+            }
+            let cursor = Coordinates {
+                x: found.x,
+                y: found.y + offset,
+            };
+            if self.is_whole_word(found, end - start) && !self.cursors.contains(&cursor) {
+                self.cursors.push(cursor);
+                return;
+            }
+            from = found;
+        }
+    }
+
+    // repeat at every secondary cursor the edit just made at the main
+    // cursor, which was at column `col` of `line` before the edit
+    pub fn edit_at_cursors(&mut self, edit: CursorEdit, line: usize, col: usize) {
+        if self.cursors.is_empty() || self.processing_action {
+            return;
+        }
+        let (delta, from) = match edit {
+            CursorEdit::Insert(_) => (1, col),
+            CursorEdit::Backspace => (-1, col),
+            CursorEdit::Delete => (-1, col + 1),
+        };
+        self.shift_cursors(line, from, delta);
+        for i in 0..self.cursors.len() {
+            let at = self.cursors[i];
+            let from = match edit {
+                CursorEdit::Insert(c) => {
+                    self.text.insert_str_padded(at.x, at.y, &c.to_string());
+                    at.y
+                }
+                CursorEdit::Backspace if at.y > 0 => {
+                    self.text.remove_str_at(at.x, at.y - 1, 1);
+                    at.y
+                }
+                CursorEdit::Delete if at.y < self.text.line_at(at.x).chars().count() => {
+                    self.text.remove_str_at(at.x, at.y, 1);
+                    at.y + 1
+                }
+                _ => continue,
+            };
+            self.shift_cursors(at.x, from, delta);
+            if self.cur_line - 1 == at.x && self.cur_pos.x > from {
+                self.cur_pos.x = self.cur_pos.x.saturating_add_signed(delta);
+            }
+        }
+    }
+
+    // move the secondary cursors at or after column `from` of `line`
+    fn shift_cursors(&mut self, line: usize, from: usize, delta: isize) {
+        for cursor in self.cursors.iter_mut() {
+            if cursor.x == line && cursor.y >= from {
+                cursor.y = cursor.y.saturating_add_signed(delta);
+            }
+        }
+    }
+
+    // `zg`: the word under the cursor is no longer flagged as misspelled
+    fn add_word_to_dictionary(&mut self) {
+        let Some((start, end)) = self.word_at_cursor() else {
+            return;
+        };
+        let word: String = self
+            .text
+            .line_at(self.cur_line - 1)
+            .chars()
+            .skip(start)
+            .take(end - start)
+            .collect();
+        if let Err(err) = self.spell.add_word(&word) {
+            self.show_message(&err);
+        }
+    }
+
+    // the `=` operator: run lines `first..=last` (0-based) through
+    // `equalprg`, or indent them, as one undoable action
+    pub fn filter_lines(&mut self, first: usize, last: usize) {
+        if !self.equalprg.is_empty() {
+            let prg = self.equalprg.clone();
+            self.filter_through(first, last, &prg);
+            return;
+        }
+        let old: Vec<String> = (first..=last).map(|i| self.text.line_at(i)).collect();
+        let above: Vec<String> = (0..first).map(|i| self.text.line_at(i)).collect();
+        let unit = if self.expandtab {
+            " ".repeat(self.tab_width)
+        } else {
+            "\t".to_string()
+        };
+        let new = filter::indent_lines(&above, &old, &unit);
+        self.change_lines(first, old, new);
+    }
+
+    // `gq`, reflow lines `first..=last` (0-based) to `textwidth` keeping the
+    // indent they share, as one undoable action. The cursor ends on the last
+    // line formatted
+    pub fn format_lines(&mut self, first: usize, last: usize) {
+        let old: Vec<String> = (first..=last).map(|i| self.text.line_at(i)).collect();
+        let indent = format::common_indent(&old);
+        let width = if self.textwidth == 0 {
+            79
+        } else {
+            self.textwidth
+        };
+        let new = format::reflow(&old, width, &indent);
+        let len = new.len().max(1);
+        self.change_lines(first, old, new);
+        self.jump_to(first + len, 1);
+        self.move_to_first_char_of_line();
+    }
+
+    // with `textwidth` set, a char typed past it breaks the line at the
+    // blanks before the word being typed, which goes on to a new line with
+    // the same indent. The break is an undoable change of its own
+    pub fn wrap_typed_word(&mut self) {
+        let line = self.cur_line - 1;
+        let col = self.cur_pos.x - 1;
+        if self.textwidth == 0 || self.display_width(line, col) <= self.textwidth {
+            return;
+        }
+        let text = self.text.line_at(line);
+        let chars: Vec<char> = text.chars().collect();
+        let indent = Self::leading_whitespace(&text);
+        let indent_len = indent.chars().count();
+        let mut start = col;
+        while start > indent_len && !Self::is_blank(chars[start - 1]) {
+            start -= 1;
+        }
+        let mut end = start;
+        while end > indent_len && Self::is_blank(chars[end - 1]) {
+            end -= 1;
+        }
+        // a single word too long for the width is left alone
+        if end == indent_len {
+            return;
+        }
+        let head: String = chars[..end].iter().collect();
+        let tail: String = chars[start..].iter().collect();
+        self.change_lines(line, vec![text], vec![head, indent.clone() + &tail]);
+        self.jump_to(line + 2, indent_len + col - start + 1);
+        self.action_stack
+            .add_action(Action::Insert, self.cur_line, self.cur_pos);
+    }
+
+    // first and last line (0-based) of the paragraph under the cursor with
+    // the blank lines after it, or of the blank lines under the cursor with
+    // the paragraph after them
+    fn paragraph(&self) -> (usize, usize) {
+        let is_blank = |i: usize| self.text.line_at(i).trim().is_empty();
+        let len = self.text_length();
+        let mut first = self.cur_line - 1;
+        let mut last = first;
+        let blank = is_blank(first);
+        while first > 0 && is_blank(first - 1) == blank {
+            first -= 1;
+        }
+        while last + 1 < len && is_blank(last + 1) == blank {
+            last += 1;
+        }
+        // the blank lines after a paragraph, or the paragraph after them
+        while last + 1 < len && is_blank(last + 1) != blank {
+            last += 1;
+        }
+        (first, last)
+    }
+
+    // `>` and `<`, shift lines `first..=last` (0-based) `count` times by
+    // `tab_width` columns, as one undoable action. Empty lines are kept empty
+    pub fn shift_lines(&mut self, first: usize, last: usize, count: usize, right: bool) {
+        let old: Vec<String> = (first..=last).map(|i| self.text.line_at(i)).collect();
+        let new = old
+            .iter()
+            .map(|line| {
+                if line.is_empty() {
+                    return String::new();
+                }
+                let indent = Self::leading_whitespace(line);
+                let width = indent.chars().fold(0, |width, c| match c {
+                    '\t' => (width / self.tab_width + 1) * self.tab_width,
+                    _ => width + 1,
+                });
+                let shift = count * self.tab_width;
+                let width = if right {
+                    width + shift
+                } else {
+                    width.saturating_sub(shift)
+                };
+                let indent = if self.expandtab {
+                    " ".repeat(width)
+                } else {
+                    "\t".repeat(width / self.tab_width) + &" ".repeat(width % self.tab_width)
+                };
+                indent + &line[Self::leading_whitespace(line).len()..]
+            })
+            .collect();
+        self.change_lines(first, old, new);
+    }
+
+    // `:{range}!cmd`, replace lines `first..=last` (0-based) with what the
+    // shell command `prg` prints for them
+    pub fn filter_through(&mut self, first: usize, last: usize, prg: &str) {
+        if !self.can_modify() {
+            return;
+        }
+        let old: Vec<String> = (first..=last).map(|i| self.text.line_at(i)).collect();
+        let new = match filter::pipe(prg, &(old.join("\n") + "\n")) {
+            Ok(output) if output.is_empty() => vec![String::new()],
+            Ok(output) => output.lines().map(str::to_string).collect(),
+            Err(err) => {
+                self.show_message(&err);
+                return;
+            }
+        };
+        self.change_lines(first, old, new);
+    }
+
+    // put `new` in place of the `old` lines starting at `first` (0-based)
+    // as one undoable action
+    fn change_lines(&mut self, first: usize, old: Vec<String>, new: Vec<String>) {
+        self.jump_to(first + 1, 1);
+        if new != old {
+            self.action_stack
+                .add_action(Action::Replace(old.clone()), self.cur_line, self.cur_pos);
+            self.action_stack.append_string_to_top(new.join("\n"));
+            self.replace_lines(first, old.len(), new);
+        }
+        self.move_to_first_char_of_line();
+    }
+
+    // `J` and `gJ`, join `count` lines from the cursor down, at least two.
+    // With `spaces` the blanks around each line break become a single space,
+    // none when either side is empty
+    pub fn join_lines(&mut self, count: usize, spaces: bool) {
+        let first = self.cur_line - 1;
+        let last = (first + count.max(2) - 1).min(self.text_length() - 1);
+        if first == last {
+            return;
+        }
+        let old: Vec<String> = (first..=last).map(|i| self.text.line_at(i)).collect();
+        let mut joined = old[0].clone();
+        let mut col = 0;
+        for line in &old[1..] {
+            let mut line = line.as_str();
+            if spaces {
+                joined.truncate(joined.trim_end_matches([' ', '\t']).len());
+                line = line.trim_start_matches([' ', '\t']);
+            }
+            col = joined.chars().count();
+            if spaces && !joined.is_empty() && !line.is_empty() {
+                joined.push(' ');
+            }
+            joined.push_str(line);
+        }
+        self.change_lines(first, old, vec![joined]);
+        // on the last join
+        self.cur_pos.x = (col + 1).min(self.len_of_cur_line());
+    }
+
+    // `Ctrl-a` and `Ctrl-x`, add `delta` to the number under or after the
+    // cursor, leaving the cursor on its last digit
+    pub fn add_to_number(&mut self, delta: i64) {
+        let line: Vec<char> = self.text.line_at(self.cur_line - 1).chars().collect();
+        let Some(mut start) = (self.cur_pos.x - 1..line.len()).find(|&i| line[i].is_ascii_digit())
+        else {
+            return;
+        };
+        while start > 0 && line[start - 1].is_ascii_digit() {
+            start -= 1;
+        }
+        let mut end = start;
+        while end < line.len() && line[end].is_ascii_digit() {
+            end += 1;
+        }
+        if start > 0 && line[start - 1] == '-' {
+            start -= 1;
+        }
+        let number: String = line[start..end].iter().collect();
+        let Ok(number) = number.parse::<i64>() else {
+            return;
+        };
+        let number = number.saturating_add(delta).to_string();
+        let old = self.text.line_at(self.cur_line - 1);
+        let new = format!(
+            "{}{number}{}",
+            line[..start].iter().collect::<String>(),
+            line[end..].iter().collect::<String>()
+        );
+        self.action_stack
+            .add_action(Action::Replace(vec![old]), self.cur_line, self.cur_pos);
+        self.action_stack.append_string_to_top(new.clone());
+        self.replace_lines(self.cur_line - 1, 1, vec![new]);
+        self.cur_pos.x = start + number.len();
+    }
+
+    // `:a,bm d` and `:a,bt d`, move or copy lines `a..=b` to after line d,
+    // 0 being above the first line
+    fn transfer_lines(&mut self, lines: LineRange, dest: usize, move_lines: bool) {
+        if !self.can_modify() {
+            return;
+        }
+        let (first, last) = (lines.first - 1, lines.last);
+        if move_lines && dest > first && dest < last {
+            self.show_message("E134: Cannot move a range of lines into itself");
+            return;
+        }
+        let old: Vec<String> = (0..self.text_length())
+            .map(|i| self.text.line_at(i))
+            .collect();
+        let mut new = old.clone();
+        let mut dest = dest;
+        if move_lines {
+            new.drain(first..last);
+            if dest >= last {
+                dest -= last - first;
+            }
+        }
+        new.splice(dest..dest, old[first..last].iter().cloned());
+        self.replace_changed_lines(&old, new);
+        self.jump_to(dest + last - first, 1);
+        self.move_to_first_char_of_line();
+    }
+
+    // `:r`, put `lines` below line `after` (from 1) as one undoable action,
+    // with the cursor on the first of them
+    fn insert_lines_below(&mut self, after: usize, lines: Vec<String>) {
+        if lines.is_empty() {
+            return;
+        }
+        let old: Vec<String> = (0..self.text_length())
+            .map(|i| self.text.line_at(i))
+            .collect();
+        let mut new = old.clone();
+        new.splice(after..after, lines);
+        self.replace_changed_lines(&old, new);
+        self.jump_to(after + 1, 1);
+    }
+
+    // `:sort`, sort lines by their text or, with `numeric`, by the integer
+    // they start with, 0 when there is none
+    fn sort_lines(&mut self, lines: LineRange, reverse: bool, numeric: bool) {
+        if !self.can_modify() {
+            return;
+        }
+        let old: Vec<String> = (0..self.text_length())
+            .map(|i| self.text.line_at(i))
+            .collect();
+        let mut new = old.clone();
+        let sorted = &mut new[lines.first - 1..lines.last];
+        if numeric {
+            sorted.sort_by_key(|line| Self::leading_integer(line));
+        } else {
+            sorted.sort();
+        }
+        if reverse {
+            sorted.reverse();
+        }
+        self.replace_changed_lines(&old, new);
+        self.jump_to(lines.first, 1);
+    }
+
+    // `:s`, replace the first or every match of each line. With the confirm
+    // flag each match is asked about, one key at a time, and the range is
+    // changed as one undoable action once done
+    fn substitute(&mut self, lines: LineRange, sub: Substitute) {
+        if !self.can_modify() {
+            return;
+        }
+        let first = lines.first - 1;
+        let old: Vec<String> = (first..lines.last.min(self.text_length()))
+            .map(|i| self.text.line_at(i))
+            .collect();
+        let Some(last_match) = old.iter().rposition(|line| sub.find(line, 0).is_some()) else {
+            self.show_message(&format!("E486: Pattern not found: {}", sub.pattern));
+            return;
+        };
+        if sub.confirm {
+            self.substituting = Some(Substitution {
+                sub,
+                first,
+                old,
+                line: first,
+                col: 0,
+                replaced: None,
+            });
+            self.next_substitution();
+            return;
+        }
+        let new = old.iter().map(|line| sub.apply(line)).collect();
+        self.change_lines(first, old, new);
+        self.jump_to(first + last_match + 1, 1);
+        self.move_to_first_char_of_line();
+    }
+
+    // move on to the match at or after the one of `s`, false when there is
+    // none left in the range
+    fn find_substitution(&self, s: &mut Substitution) -> bool {
+        while s.line < s.first + s.old.len() {
+            if let Some(col) = s.sub.find(&self.text.line_at(s.line), s.col) {
+                s.col = col;
+                return true;
+            }
+            s.line += 1;
+            s.col = 0;
+        }
+        false
+    }
+
+    // replace the match of `s` and move past it, or to the next line
+    // without the `g` flag
+    fn replace_substitution(&mut self, s: &mut Substitution) {
+        let line = s.sub.replace_at(&self.text.line_at(s.line), s.col);
+        self.replace_lines(s.line, 1, vec![line]);
+        s.replaced = Some((s.line, s.col));
+        if s.sub.global {
+            s.col += s.sub.replacement.chars().count();
+        } else {
+            s.line += 1;
+            s.col = 0;
         }
-        write!(self.out, "{}", style::Reset).unwrap();
     }
-    fn show_bar(&mut self) {
-        write!(
-            self.out,
-            "{}",
-            termion::cursor::Goto(1, (self.terminal_size.1) as u16)
-        )
-        .unwrap();
-        match self.mode {
-            Mode::Command | Mode::Search => {
-                write!(
-                    self.out,
-                    "{}{}{}:{}",
-                    color::Fg(color::Yellow),
-                    style::Bold,
-                    self.mode,
-                    self.bar_text.line_at(0)
-                )
-                .unwrap();
+
+    // select the next match of a `:s///c` and ask about it, or finish
+    fn next_substitution(&mut self) {
+        let Some(mut s) = self.substituting.take() else {
+            return;
+        };
+        if !self.find_substitution(&mut s) {
+            return self.finish_substitution(s);
+        }
+        let len = s.sub.pattern.chars().count();
+        self.jump_to(s.line + 1, s.col + 1);
+        self.select_view = SelectView::CharacterView(CharacterView {
+            start: Coordinates {
+                x: s.col,
+                y: s.line,
+            },
+            end: Coordinates {
+                x: s.col + len - 1,
+                y: s.line,
+            },
+        });
+        self.show_message(&format!("replace with {} (y/n/a/q/l)?", s.sub.replacement));
+        self.substituting = Some(s);
+    }
+
+    // answer the prompt of a `:s///c`: `y` replaces the match, `n` skips
+    // it, `a` replaces it and all the following ones, `l` replaces it and
+    // stops, `q` and Esc stop. Other keys ask again
+    pub fn substitute_interactive(&mut self, key: Key) {
+        let Some(mut s) = self.substituting.take() else {
+            return;
+        };
+        match key {
+            Key::Char('y') => self.replace_substitution(&mut s),
+            Key::Char('l') => {
+                self.replace_substitution(&mut s);
+                return self.finish_substitution(s);
             }
-            _ => {
-                write!(self.out, "{}{}{} line-count={} filename: {}, size: ({}, {}) line[{}-{}] pos[{}:{}] mode:{} task:{} {}",
-                    color::Bg(color::Green),
-                    color::Fg(color::Blue),
-                    style::Bold,
-                    self.text_length(),
-                    self.file_name,
-                    self.terminal_size.0,
-                    self.terminal_size.1,
-                    self.view.lower_line(),
-                    self.view.upper_line(),
-                    self.cur_pos.x,
-                    self.cur_pos.y,
-                    self.mode,
-                    self.task,
-                    style::Reset
-                ).unwrap();
+            Key::Char('n') if s.sub.global => s.col += 1,
+            Key::Char('n') => {
+                s.line += 1;
+                s.col = 0;
+            }
+            Key::Char('a') => {
+                while self.find_substitution(&mut s) {
+                    self.replace_substitution(&mut s);
+                }
             }
+            Key::Char('q') | Key::Esc => return self.finish_substitution(s),
+            _ => {}
         }
+        self.substituting = Some(s);
+        self.next_substitution();
     }
 
-    fn set_pos(&mut self, x: usize, y: usize) {
-        self.cur_pos.x = x;
-        self.cur_pos.y = y;
-        self.update_pos();
-    }
-    fn set_cur_line(&mut self, line: usize) {
-        self.cur_line = line;
+    fn finish_substitution(&mut self, s: Substitution) {
+        self.select_view = SelectView::None;
+        self.clear_message();
+        let Some((line, col)) = s.replaced else {
+            return;
+        };
+        let new: Vec<String> = (s.first..s.first + s.old.len())
+            .map(|i| self.text.line_at(i))
+            .collect();
+        // the replacements were made one by one, put the old lines back so
+        // that they are recorded as a single change
+        self.replace_lines(s.first, s.old.len(), s.old.clone());
+        self.change_lines(s.first, s.old, new);
+        self.jump_to(line + 1, col + 1);
     }
 
-    fn set_cursor_style(&mut self, style: CursorStyle) {
-        match style {
-            CursorStyle::Bar => write!(self.out, "{}", termion::cursor::BlinkingBar),
-            CursorStyle::Block => write!(self.out, "{}", termion::cursor::BlinkingBlock),
-            CursorStyle::Underline => write!(self.out, "{}", termion::cursor::BlinkingUnderline),
+    // `:trim`, remove the blanks at the end of lines as one undoable action,
+    // the cursor stays where it was as far as the line allows
+    fn trim_lines(&mut self, lines: LineRange) {
+        if !self.can_modify() {
+            return;
         }
-        .unwrap();
+        let (line, col) = (self.cur_line, self.cur_pos.x);
+        let old: Vec<String> = (0..self.text_length())
+            .map(|i| self.text.line_at(i))
+            .collect();
+        let mut new = old.clone();
+        for line in &mut new[lines.first - 1..lines.last.min(old.len())] {
+            line.truncate(line.trim_end_matches([' ', '\t']).len());
+        }
+        self.replace_changed_lines(&old, new);
+        self.jump_to(line, col);
     }
 
-    fn update_pos(&mut self) {
-        write!(
-            self.out,
-            "{}",
-            termion::cursor::Goto(self.cur_pos.x as u16, self.cur_pos.y as u16)
-        )
-        .unwrap();
+    fn leading_integer(line: &str) -> i64 {
+        let line = line.trim_start();
+        let digits = line
+            .char_indices()
+            .find(|&(i, c)| !(c.is_ascii_digit() || i == 0 && c == '-'))
+            .map_or(line.len(), |(i, _)| i);
+        line[..digits].parse().unwrap_or(0)
     }
 
-    fn flush_to_disk(&self) {
-        fs::write(&self.file_name, self.text.to_string()).unwrap();
+    // replace the text made of `old` lines with `new` ones as one undoable
+    // action, leaving the lines they both start and end with untouched
+    fn replace_changed_lines(&mut self, old: &[String], new: Vec<String>) {
+        let start = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+        let max_end = old.len().min(new.len()) - start;
+        let end = old
+            .iter()
+            .rev()
+            .zip(new.iter().rev())
+            .take(max_end)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if start == old.len() && start == new.len() {
+            return;
+        }
+        let removed = old[start..old.len() - end].to_vec();
+        let added = new[start..new.len() - end].to_vec();
+        let contents = added.join("\n");
+        self.replace_lines(start, removed.len(), added);
+        // only now is there a line to go to when lines were added past the
+        // last one, the action is kept at `start` in any case
+        self.jump_to(start + 1, 1);
+        self.action_stack
+            .add_action(Action::Replace(removed), start + 1, self.cur_pos);
+        self.action_stack.append_string_to_top(contents);
     }
 
-    pub fn try_perform_command(&mut self) -> Option<Mode> {
-        assert!(self.mode == Mode::Command || self.mode == Mode::Search);
-        if self.mode == Mode::Command {
-            match self.bar_text.line_at(0).as_str() {
-                "q" => Some(Mode::Exit),
-                "w" => {
-                    self.flush_to_disk();
-                    Some(Mode::Normal)
-                }
-                _ => {
-                    unimplemented!()
-                }
+    fn replace_lines(&mut self, first: usize, count: usize, lines: Vec<String>) {
+        self.modified = true;
+        // the folds only follow the lines added or removed at the end
+        let folds = std::mem::take(&mut self.folds);
+        let added = lines.len();
+        for _ in 0..count {
+            self.delete_line_at(first);
+        }
+        for (i, line) in lines.into_iter().enumerate() {
+            self.text.add_line_before(first + i, line);
+            if self.text_length() < self.terminal_size.1 as usize - 1 {
+                self.view.expand_upper();
             }
+        }
+        self.folds = folds;
+        if added > count {
+            self.shift_folds(first + count, added - count);
         } else {
-            // TODO: handle search
-            None
+            self.drop_folded_lines(first + added, count - added);
         }
     }
-    pub fn try_perform_task(&mut self) {
-        self.processing_task = true;
-        if self.task.is_movement() {
-            // it is guaranteed that current tasks have num
-            assert!(self.task.has_num());
-            let n = self.task.num().unwrap();
-            let key = *self.task.last_task().unwrap();
-            for _ in 0..n {
-                Mode::handle_normal(self, key);
-            }
-            self.task.clear();
-        } else if self.task.last_two_task() == Some("dd".to_string()) {
-            // FIXME: considering `2dd`
-            self.delete_cur_line();
-            self.task.clear();
+
+    // `Text::delete_range`, the lines it joins taking their folds along
+    fn delete_text_range(&mut self, start: Coordinates, end: Coordinates) -> String {
+        let len = self.text_length();
+        let contents = self.text.delete_range(start, end);
+        self.drop_folded_lines(start.x + 1, len - self.text_length());
+        contents
+    }
+
+    pub fn undo_by(&mut self, step: UndoStep) {
+        let actions = match step {
+            UndoStep::Count(n) => (0..n).map_while(|_| self.action_stack.backward()).collect(),
+            UndoStep::Time(duration) => self.action_stack.backward_by(duration),
+        };
+        for action in actions {
+            self.revoke_action(Some(action));
+        }
+    }
+
+    pub fn redo_by(&mut self, step: UndoStep) {
+        let actions = match step {
+            UndoStep::Count(n) => (0..n).map_while(|_| self.action_stack.forward()).collect(),
+            UndoStep::Time(duration) => self.action_stack.forward_by(duration),
+        };
+        for action in actions {
+            self.restore_action(Some(action));
         }
-        self.processing_task = false;
     }
 
     pub fn revoke_action(&mut self, action: Option<CmdAction>) {
@@ -536,19 +3796,38 @@ impl TextEditor {
             self.set_pos(pos.x, pos.y);
             self.cur_line = cur_line;
             match action.action {
-                Action::Delete => action.contents.iter().for_each(|&a| match a {
-                    Key::Char(c) => self.append_char_at_cur(c),
-                    _ => unreachable!(),
-                }),
-                Action::Insert => action.contents.iter().for_each(|&a| {
-                    if a == Key::Char('\t') {
-                        for _ in 0..4 {
-                            self.delete_cur_char();
-                        }
-                    } else {
-                        self.delete_cur_char();
+                Action::Delete => {
+                    // put the text back as in insert mode, so that it can be
+                    // appended past the last char of the line
+                    let mode = std::mem::replace(&mut self.mode, Mode::Insert);
+                    action.contents.iter().for_each(|&a| match a {
+                        Key::Char(c) => self.append_char_at_cur(c),
+                        _ => unreachable!(),
+                    });
+                    self.mode = mode;
+                    // back to where the text was deleted
+                    self.jump_to(cur_line, pos.x);
+                }
+                Action::Replace(old) => {
+                    let new = 1 + action
+                        .contents
+                        .iter()
+                        .filter(|&&key| key == Key::Char('\n'))
+                        .count();
+                    self.replace_lines(cur_line - 1, new, old);
+                    // the lines added past the last one are gone again
+                    if cur_line > self.text_length() {
+                        self.jump_to(self.text_length(), pos.x);
                     }
-                }),
+                }
+                // cursor keys recorded along with the text don't insert anything
+                Action::Insert => action
+                    .contents
+                    .iter()
+                    .filter(|key| matches!(key, Key::Char(_)))
+                    .for_each(|_| {
+                        self.delete_cur_char();
+                    }),
             }
         }
 
@@ -565,12 +3844,30 @@ impl TextEditor {
                 self.cur_line = cur_line;
             }
             match action.action {
-                Action::Insert => action.contents.iter().for_each(|&a| {
-                    if cfg!(test) {
-                        println!("restoring insert key:{:?}", a);
-                    }
-                    Mode::handle_insert(self, a);
-                }),
+                Action::Insert => {
+                    // replay as in insert mode, where the cursor may move past
+                    // the last char of the line
+                    let mode = std::mem::replace(&mut self.mode, Mode::Insert);
+                    action.contents.iter().for_each(|&a| {
+                        if cfg!(test) {
+                            println!("restoring insert key:{:?}", a);
+                        }
+                        Mode::handle_insert(self, a);
+                    });
+                    self.mode = mode;
+                }
+                Action::Replace(old) => {
+                    let new: String = action
+                        .contents
+                        .iter()
+                        .filter_map(|key| match key {
+                            Key::Char(c) => Some(*c),
+                            _ => None,
+                        })
+                        .collect();
+                    let new = new.split('\n').map(str::to_string).collect();
+                    self.replace_lines(self.cur_line - 1, old.len(), new);
+                }
                 Action::Delete => {
                     action.contents.iter().for_each(|&_a| {
                         // consider restoring `dd`
@@ -589,9 +3886,12 @@ impl TextEditor {
     }
 
     fn len_of_line_at(&self, line: usize) -> usize {
-        if self.mode == Mode::Normal || self.mode == Mode::Visual || self.mode == Mode::Command {
+        if matches!(
+            self.mode,
+            Mode::Normal | Mode::Visual | Mode::Command | Mode::Search
+        ) {
             1.max(self.text.len_of_line_at(line))
-        } else if self.mode == Mode::Insert {
+        } else if matches!(self.mode, Mode::Insert | Mode::Replace) {
             1.max(self.text.len_of_line_at(line) + 1)
         } else {
             unimplemented!()
@@ -612,6 +3912,12 @@ impl TextEditor {
     fn move_to_start_of_line(&mut self) {
         self.cur_pos.x = 1;
     }
+    // `g_`, the last char that is not blank, the start of a blank line
+    fn move_to_last_char_of_line(&mut self) {
+        let text = self.text.line_at(self.cur_line - 1);
+        let len = text.trim_end_matches(Self::is_blank).chars().count();
+        self.cur_pos.x = len.max(1);
+    }
     fn move_to_first_char_of_line(&mut self) {
         self.cur_pos.x = 1;
         while self.cur_pos.x < self.len_of_cur_line() {
@@ -626,11 +3932,13 @@ impl TextEditor {
         if self.cur_pos.x < self.len_of_cur_line() {
             self.cur_pos.x += 1;
         }
+        self.scroll_to_col(self.cur_pos.x);
     }
     fn dec_x(&mut self) {
         if self.cur_pos.x > 1 {
             self.cur_pos.x -= 1;
         }
+        self.scroll_to_col(self.cur_pos.x);
     }
     fn inc_y(&mut self) {
         if self.cur_pos.y < self.max_y().min(self.text_length() as u16).into() {
@@ -654,60 +3962,151 @@ impl TextEditor {
             self.cur_line -= 1;
         }
     }
-    fn forward_to_end_of_cur_word(&mut self) {
-        assert!(Self::is_alphabet(self.cur_char()));
-        while Self::is_alphabet(self.cur_char()) {
-            let old_line = self.cur_line;
-            if !self.forward_to_next_char() {
-                return;
+    // words of the text in the order they first appear
+    fn collect_words(&self) -> Vec<String> {
+        let mut words: Vec<String> = vec![];
+        for i in 0..self.text_length() {
+            let line = self.text.line_at(i);
+            for word in line.split(|c| Self::word_class(c) != 1) {
+                if !word.is_empty() && !words.iter().any(|w| w == word) {
+                    words.push(word.to_string());
+                }
             }
-            if self.cur_line != old_line {
-                break;
+        }
+        words
+    }
+
+    // `Ctrl-n` and `Ctrl-p`, complete the word before the cursor with the
+    // next or previous word of the text starting with it
+    pub fn complete_keyword(&mut self, forward: bool) {
+        let (prefix, candidates, shown) = match self.keyword_completion.take() {
+            Some((prefix, candidates, shown)) => (prefix, candidates, Some(shown)),
+            None => {
+                let line: Vec<char> = self.text.line_at(self.cur_line - 1).chars().collect();
+                let col = (self.cur_pos.x - 1).min(line.len());
+                let mut start = col;
+                while start > 0 && Self::word_class(line[start - 1]) == 1 {
+                    start -= 1;
+                }
+                let prefix: String = line[start..col].iter().collect();
+                let candidates: Vec<String> = self
+                    .collect_words()
+                    .into_iter()
+                    .filter(|word| word.starts_with(&prefix) && *word != prefix)
+                    .collect();
+                if prefix.is_empty() || candidates.is_empty() {
+                    self.show_message("Pattern not found");
+                    return;
+                }
+                (prefix, candidates, None)
             }
+        };
+        let len = candidates.len();
+        let next = match shown {
+            Some(i) if forward => (i + 1) % len,
+            Some(i) => (i + len - 1) % len,
+            None if forward => 0,
+            None => len - 1,
+        };
+        // typed as keys, so that the insertion is undone as a whole
+        let typed = prefix.chars().count();
+        let old = shown.map_or(typed, |i| candidates[i].chars().count());
+        for _ in typed..old {
+            Mode::handle_insert(self, Key::Backspace);
+        }
+        for c in candidates[next].chars().skip(typed) {
+            Mode::handle_insert(self, Key::Char(c));
+        }
+        self.keyword_completion = Some((prefix, candidates, next));
+    }
+
+    // column (from 0) where the word before the cursor starts, blanks
+    // right before the cursor belong to the word, for `Ctrl-w`
+    fn start_of_word_before_cursor(&self) -> usize {
+        let line: Vec<char> = self.text.line_at(self.cur_line - 1).chars().collect();
+        let mut start = (self.cur_pos.x - 1).min(line.len());
+        while start > 0 && Self::is_blank(line[start - 1]) {
+            start -= 1;
+        }
+        if start == 0 {
+            return 0;
+        }
+        let class = Self::word_class(line[start - 1]);
+        while start > 0 && Self::word_class(line[start - 1]) == class {
+            start -= 1;
+        }
+        start
+    }
+    // class of `c` for `w`, `b` and `e`: 0 for blanks, and a word is a run
+    // of keyword chars (1) or of other non-blank chars (2)
+    fn word_class(c: char) -> u8 {
+        if c == 0 as char || Self::is_blank(c) {
+            0
+        } else if Self::is_alphabet(c) || c == '_' {
+            1
+        } else {
+            2
         }
-        self.backward_to_next_char();
     }
-    fn forward_to_start_of_cur_word(&mut self) {
-        assert!(Self::is_alphabet(self.cur_char()));
-        while Self::is_alphabet(self.cur_char()) {
-            let old_line = self.cur_line;
+    // class of `c` for `W`, `B` and `E`, a WORD being any run of non-blanks
+    fn big_word_class(c: char) -> u8 {
+        if c == 0 as char || Self::is_blank(c) {
+            0
+        } else {
+            1
+        }
+    }
+    fn backward_to_start_of_next_word(&mut self, class: fn(char) -> u8) {
+        if !self.backward_to_next_char() {
+            return;
+        }
+        while class(self.cur_char()) == 0 {
             if !self.backward_to_next_char() {
                 return;
             }
-            if self.cur_line != old_line {
-                break;
-            }
         }
-        self.forward_to_next_char();
+        let word = class(self.cur_char());
+        while self.cur_pos.x > 1
+            && class(self.text.char_at(self.cur_line - 1, self.cur_pos.x - 2)) == word
+        {
+            self.cur_pos.x -= 1;
+        }
     }
-    fn backward_to_start_of_next_word(&mut self) {
-        self.backward_to_next_char();
-        if Self::is_alphabet(self.cur_char()) {
-            self.forward_to_start_of_cur_word();
-        } else {
-            // we are currently in blank char, need to find the next word
-            while !Self::is_alphabet(self.cur_char()) {
-                self.backward_to_next_char();
+    // `ge` and `gE`, a line break ends a word as blanks do
+    fn backward_to_end_of_prev_word(&mut self, class: fn(char) -> u8) {
+        let word = class(self.cur_char());
+        let line = self.cur_line;
+        while word != 0 && class(self.cur_char()) == word && self.cur_line == line {
+            if !self.backward_to_next_char() {
+                return;
+            }
+        }
+        while class(self.cur_char()) == 0 {
+            if !self.backward_to_next_char() {
+                return;
             }
         }
-        self.forward_to_start_of_cur_word();
     }
-    fn forward_to_end_of_next_word(&mut self) {
-        self.forward_to_next_char();
-        if Self::is_alphabet(self.cur_char()) {
-            self.forward_to_end_of_cur_word();
-        } else {
-            // we are currently at non-alphabetic char, need to
-            //      find the next alphabetic char
-            while !Self::is_alphabet(self.cur_char()) {
-                self.forward_to_next_char();
+    fn forward_to_end_of_next_word(&mut self, class: fn(char) -> u8) {
+        if !self.forward_to_next_char() {
+            return;
+        }
+        while class(self.cur_char()) == 0 {
+            if !self.forward_to_next_char() {
+                return;
             }
         }
-        self.forward_to_end_of_cur_word();
+        let word = class(self.cur_char());
+        while self.cur_pos.x < self.len_of_cur_line()
+            && class(self.text.char_at(self.cur_line - 1, self.cur_pos.x)) == word
+        {
+            self.cur_pos.x += 1;
+        }
     }
-    fn forward_to_start_of_next_word(&mut self) {
-        while Self::is_alphabet(self.cur_char()) {
-            let old_line = self.cur_line;
+    fn forward_to_start_of_next_word(&mut self, class: fn(char) -> u8) {
+        let word = class(self.cur_char());
+        let old_line = self.cur_line;
+        while word != 0 && class(self.cur_char()) == word {
             if !self.forward_to_next_char() {
                 return;
             }
@@ -716,8 +4115,10 @@ impl TextEditor {
             }
         }
         // we are currently in blank char, need to find the next word
-        while !Self::is_alphabet(self.cur_char()) {
-            self.forward_to_next_char();
+        while class(self.cur_char()) == 0 {
+            if !self.forward_to_next_char() {
+                return;
+            }
         }
     }
     fn backward_to_next_char(&mut self) -> bool {
@@ -731,14 +4132,14 @@ impl TextEditor {
                 }
                 self.cur_line -= 1;
                 self.cur_pos.x = self.len_of_cur_line();
-                return true;
+                true
             } else {
                 // we hit the beginning of the file, just do nothing
-                return false;
+                false
             }
         } else {
             self.cur_pos.x -= 1;
-            return true;
+            true
         }
     }
     fn forward_to_next_char(&mut self) -> bool {
@@ -754,41 +4155,78 @@ impl TextEditor {
                 }
                 self.cur_line += 1;
                 self.cur_pos.x = 1;
-                return true;
+                true
             } else {
                 // we hit the end of the file, just do nothing
-                return false;
+                false
             }
         } else {
             self.cur_pos.x += 1;
-            return true;
+            true
         }
     }
-    fn new_line_ahead(&mut self) {
+    // leading spaces and tabs of `line`, which a new line opened from it inherits
+    fn leading_whitespace(line: &str) -> String {
+        line.chars()
+            .take_while(|c| *c == ' ' || *c == '\t')
+            .collect()
+    }
+    fn new_line_ahead(&mut self, indent: &str) {
         self.text
-            .add_line_before(self.cur_pos.y - 1, "".to_string());
+            .add_line_before(self.cur_line - 1, indent.to_string());
+        self.shift_folds(self.cur_line - 1, 1);
         self.move_to_start_of_line();
+        self.cur_pos.x = indent.len() + 1;
         if self.text_length() < self.terminal_size.1 as usize - 1 {
             self.view.expand_upper();
         }
     }
-    fn new_line_behind(&mut self) {
+    fn new_line_behind(&mut self, indent: &str) {
         self.text
-            .new_line_at(self.cur_pos.y - 1, self.len_of_cur_line());
+            .new_line_at(self.cur_line - 1, self.len_of_cur_line());
+        self.shift_folds(self.cur_line, 1);
+        self.text
+            .append_str_at(self.cur_line, 0, indent.to_string());
         self.inc_y();
         self.move_to_start_of_line();
+        self.cur_pos.x = indent.len() + 1;
         if self.text_length() < self.terminal_size.1 as usize - 1 {
             self.view.expand_upper();
         }
     }
-    fn new_line(&mut self) {
-        self.text
-            .new_line_at(self.cur_pos.y - 1, self.cur_pos.x - 1);
+    // split the current line at the cursor; the new line gets the indent of
+    // the current one unless an action is being replayed, whose recorded
+    // keys already contain it. Returns the inserted indent
+    fn new_line(&mut self) -> String {
+        let indent = if self.processing_action {
+            String::new()
+        } else {
+            Self::leading_whitespace(&self.text.line_at(self.cur_line - 1))
+        };
+        self.text.new_line_at(self.cur_line - 1, self.cur_pos.x - 1);
+        self.shift_folds(self.cur_line, 1);
+        self.text.append_str_at(self.cur_line, 0, indent.clone());
         self.inc_y();
         self.move_to_start_of_line();
+        self.cur_pos.x = indent.len() + 1;
         if self.text_length() < self.terminal_size.1 as usize - 1 {
             self.view.expand_upper();
         }
+        indent
+    }
+    fn cur_indent(&self) -> String {
+        Self::leading_whitespace(&self.text.line_at(self.cur_line - 1))
+    }
+    // start an insert action at the beginning of the current line, recording
+    // the auto-inserted `indent` first so that undo removes it as well
+    fn add_indented_insert_action(&mut self, indent: &str) {
+        let pos = Coordinates {
+            x: 1,
+            y: self.cur_pos.y,
+        };
+        self.action_stack
+            .add_action(Action::Insert, self.cur_line, pos);
+        self.action_stack.append_string_to_top(indent.to_string());
     }
     fn cur_char(&mut self) -> char {
         self.text.char_at(self.cur_line - 1, self.cur_pos.x - 1)
@@ -804,13 +4242,272 @@ impl TextEditor {
     }
     pub fn delete_line_at(&mut self, index: usize) -> String {
         let res = self.text.delete_line_at(index);
+        self.drop_folded_lines(index, 1);
         if self.text_length() < self.terminal_size.1 as usize - 1 {
             self.view.shrink_upper();
         }
         res
     }
+
+    // make `change` at the cursor and keep it for `.`. The keys typed after
+    // `ciw` are replayed, or recorded when leaving insert mode
+    pub fn repeat_change(&mut self, change: LastChange) -> Mode {
+        let mut mode = Mode::Normal;
+        match &change {
+            LastChange::DeleteWords(count) => self.delete_words(*count),
+            LastChange::DeleteInnerWord => {
+                self.delete_inner_word();
+                self.cur_pos.x = self.cur_pos.x.min(self.len_of_cur_line());
+            }
+            LastChange::ChangeInnerWord(keys) => {
+                self.delete_inner_word();
+                mode = self.insert_keys(keys);
+            }
+            LastChange::ChangeMatch(forward, keys) => {
+                if !self.select_match(*forward) {
+                    return mode;
+                }
+                self.delete_selected();
+                self.set_visual_mode(SelectView::None);
+                mode = self.insert_keys(keys);
+            }
+            LastChange::Shift(count, right) => {
+                let first = self.cur_line - 1;
+                let last = (first + count).min(self.text_length()) - 1;
+                self.shift_lines(first, last, 1, *right);
+            }
+        }
+        self.last_change = Some(change);
+        mode
+    }
+
+    // the insert of a change: the keys are typed as in insert mode, or
+    // insert mode is entered to type them when there are none yet
+    fn insert_keys(&mut self, keys: &[Key]) -> Mode {
+        self.action_stack
+            .add_action(Action::Insert, self.cur_line, self.cur_pos);
+        if keys.is_empty() {
+            return Mode::Insert;
+        }
+        let mode = std::mem::replace(&mut self.mode, Mode::Insert);
+        for &key in keys {
+            Mode::handle_insert(self, key);
+        }
+        self.mode = mode;
+        self.dec_x();
+        Mode::Normal
+    }
+
+    // leaving insert mode after `ciw` or `cgn`, keep what was typed for `.`
+    pub fn finish_change(&mut self) {
+        if let Some(LastChange::ChangeInnerWord(keys) | LastChange::ChangeMatch(_, keys)) =
+            &mut self.last_change
+        {
+            if keys.is_empty() {
+                if let Some(action) = self.action_stack.current() {
+                    *keys = action.contents;
+                }
+            }
+        }
+    }
+
+    // `dw`: delete from the cursor to the start of the `count`th word after
+    // it, or to the end of the line, into the unnamed register
+    fn delete_words(&mut self, count: usize) {
+        let line = self.cur_line - 1;
+        let chars: Vec<char> = self.text.line_at(line).chars().collect();
+        let start = self.cur_pos.x - 1;
+        let mut end = start;
+        for _ in 0..count {
+            let Some(&c) = chars.get(end) else {
+                break;
+            };
+            let class = Self::word_class(c);
+            while class != 0 && end < chars.len() && Self::word_class(chars[end]) == class {
+                end += 1;
+            }
+            while end < chars.len() && Self::word_class(chars[end]) == 0 {
+                end += 1;
+            }
+        }
+        if end == start {
+            return;
+        }
+        let removed = self.text.remove_str_at(line, start, end - start);
+        if !self.processing_action {
+            self.registers.push_delete(Register {
+                text: removed.clone(),
+                linewise: false,
+            });
+            self.action_stack
+                .add_action(Action::Delete, self.cur_line, self.cur_pos);
+            self.action_stack.append_string_to_top(removed);
+        }
+        self.cur_pos.x = self.cur_pos.x.min(self.len_of_cur_line());
+    }
+
+    // where `count` times `)` or `(` goes from the cursor: the start of a
+    // sentence, or else the end or the start of the text
+    fn sentence_target(&self, count: usize, forward: bool) -> (Coordinates, bool) {
+        let mut at = Coordinates {
+            x: self.cur_line - 1,
+            y: self.cur_pos.x - 1,
+        };
+        for _ in 0..count {
+            match self.text.sentence_boundary(at, forward) {
+                Some(start) => at = start,
+                None if forward => {
+                    let x = self.text_length() - 1;
+                    let y = self.text.len_of_line_at(x).saturating_sub(1);
+                    return (Coordinates { x, y }, false);
+                }
+                None => return (Coordinates { x: 0, y: 0 }, true),
+            }
+        }
+        (at, true)
+    }
+
+    // `(` and `)`
+    fn move_by_sentences(&mut self, count: usize, forward: bool) {
+        let (at, _) = self.sentence_target(count, forward);
+        self.jump_to(at.x + 1, at.y + 1);
+    }
+
+    // `d)` and `d(`, up to the start of a sentence, which is kept
+    fn delete_sentences(&mut self, count: usize, forward: bool) {
+        let cursor = Coordinates {
+            x: self.cur_line - 1,
+            y: self.cur_pos.x - 1,
+        };
+        let (target, is_start) = self.sentence_target(count, forward);
+        if target == cursor {
+            return;
+        }
+        // the char before `at`, the last of the line above for the first
+        let before = |at: Coordinates| match at.y {
+            0 => Coordinates {
+                x: at.x - 1,
+                y: self.text.len_of_line_at(at.x - 1).saturating_sub(1),
+            },
+            y => Coordinates { x: at.x, y: y - 1 },
+        };
+        let (start, end) = match (forward, is_start) {
+            (true, true) => (cursor, before(target)),
+            (true, false) => (cursor, target),
+            (false, _) => (target, before(cursor)),
+        };
+        self.jump_to(start.x + 1, start.y + 1);
+        let contents = self.delete_text_range(start, end);
+        self.jump_to(self.cur_line, start.y + 1);
+        if contents.is_empty() || self.processing_action {
+            return;
+        }
+        self.registers.push_delete(Register {
+            text: contents.clone(),
+            linewise: false,
+        });
+        self.action_stack
+            .add_action(Action::Delete, self.cur_line, self.cur_pos);
+        self.action_stack.append_string_to_top(contents);
+    }
+
+    // first and last column (from 0) of the `iw` object at column `col` of
+    // `line`: the run of chars of the class of the one there, be it keyword
+    // chars, punctuation or blanks
+    fn word_range_at(&self, line: usize, col: usize) -> Option<(usize, usize)> {
+        let chars: Vec<char> = self.text.line_at(line).chars().collect();
+        let class = Self::word_class(*chars.get(col)?);
+        let mut start = col;
+        while start > 0 && Self::word_class(chars[start - 1]) == class {
+            start -= 1;
+        }
+        let mut end = col;
+        while end + 1 < chars.len() && Self::word_class(chars[end + 1]) == class {
+            end += 1;
+        }
+        Some((start, end))
+    }
+
+    // `diw` and `ciw`: delete the word under the cursor into the unnamed
+    // register, false when the line is empty
+    pub fn delete_inner_word(&mut self) -> bool {
+        let line = self.cur_line - 1;
+        let Some((start, end)) = self.word_range_at(line, self.cur_pos.x - 1) else {
+            return false;
+        };
+        self.cur_pos.x = start + 1;
+        let removed = self.text.remove_str_at(line, start, end + 1 - start);
+        if !self.processing_action {
+            self.registers.push_delete(Register {
+                text: removed.clone(),
+                linewise: false,
+            });
+            self.action_stack
+                .add_action(Action::Delete, self.cur_line, self.cur_pos);
+            self.action_stack.append_string_to_top(removed);
+        }
+        true
+    }
+
+    // delete from the cursor to the end of the line, recorded for undo;
+    // nothing is deleted when the cursor is past the end of the line
+    pub fn delete_to_end_of_line(&mut self) -> bool {
+        let removed = self
+            .text
+            .truncate_line_at(self.cur_line - 1, self.cur_pos.x - 1);
+        if removed.is_empty() {
+            return false;
+        }
+        if !self.processing_action {
+            self.action_stack
+                .add_action(Action::Delete, self.cur_line, self.cur_pos);
+            self.action_stack.append_string_to_top(removed);
+        }
+        true
+    }
+
+    // `[count]dd`: delete `count` lines from the cursor down, clamped to the
+    // lines left, as one undoable action
+    pub fn delete_lines(&mut self, count: usize) {
+        let first = self.cur_line - 1;
+        let count = count.clamp(1, self.text_length() - first);
+        let lines: Vec<String> = (0..count).map(|_| self.delete_cur_line()).collect();
+        let mut removed = lines.join("\n");
+        if !self.processing_action {
+            self.registers.push_delete(Register {
+                text: removed.clone() + "\n",
+                linewise: true,
+            });
+        }
+        let mut pos = Coordinates {
+            x: 1,
+            y: self.cur_pos.y,
+        };
+        if self.text_length() == 0 {
+            // the lines are put back into the empty line left behind
+            self.text.push_line(String::new());
+        } else if first < self.text_length() {
+            removed.push('\n');
+        } else {
+            // the last lines are gone, so undo appends them to the new last line
+            self.dec_y();
+            removed.insert(0, '\n');
+            pos = Coordinates {
+                x: self.text.line_at(first - 1).chars().count() + 1,
+                y: self.cur_pos.y,
+            };
+        }
+        if !self.processing_action {
+            self.action_stack
+                .add_action(Action::Delete, self.cur_line, pos);
+            self.action_stack.append_string_to_top(removed);
+        }
+        self.move_to_first_char_of_line();
+    }
+
     pub fn delete_cur_line(&mut self) -> String {
         let res = self.text.delete_line_at(self.cur_line - 1);
+        self.drop_folded_lines(self.cur_line - 1, 1);
         if self.text_length() < self.terminal_size.1 as usize - 1 {
             self.view.shrink_upper();
         }
@@ -826,12 +4523,12 @@ impl TextEditor {
             }
             None
         } else {
-            self.text.delete_at(self.cur_line - 1, self.cur_pos.x)
+            self.text.delete_at(self.cur_line - 1, self.cur_pos.x - 1)
         }
     }
 
     fn append_char_at_cur(&mut self, c: char) {
-        write!(stderr(), "append {c}\n").unwrap();
+        self.debug(format_args!("append {c}"));
         if c == '\n' {
             self.new_line();
         } else {
@@ -849,6 +4546,21 @@ impl TextEditor {
         }
     }
 
+    // insert `c` as it is, even a line break or Esc. It is recorded after a
+    // `Ctrl-v` so that replaying the keys doesn't take it for a command,
+    // but for the chars a code may start with, which aren't any
+    pub fn insert_literal(&mut self, c: char) {
+        self.text
+            .insert_at(self.cur_line - 1, self.cur_pos.x - 1, c);
+        self.inc_x();
+        if !self.processing_action {
+            if !(c == 'u' || c.is_ascii_digit()) {
+                self.action_stack.append_key_to_top(Key::Ctrl('v'));
+            }
+            self.action_stack.append_key_to_top(Key::Char(c));
+        }
+    }
+
     fn insert_char_at(&mut self, c: char, x: usize, y: usize) {
         if c == '\n' {
             self.new_line();
@@ -856,33 +4568,81 @@ impl TextEditor {
             self.text.insert_at(x, y, c)
         }
     }
-    fn run(&mut self) {
+    // a failure to draw ends the editor, its terminal is restored when it
+    // is dropped
+    fn run(&mut self, keys: &mut dyn KeySource) -> Result<(), EditorError> {
+        self.set_cursor_style(cursor_style_for(self.mode));
         self.flush();
-        self.out.flush().unwrap();
-        let stdin = stdin();
-        for c in stdin.keys() {
-            self.mode = self.mode.clone().handle(self, c.unwrap());
+        self.out.flush().map_err(EditorError::Terminal)?;
+        while let Some(key) = keys.next_key() {
+            self.mode = self.mode.clone().handle(self, key);
             if self.mode == Mode::Exit {
+                self.save_positions();
                 break;
             }
+            // the size is checked once per key, so a resized window is
+            // redrawn on the next key press
+            if let Some((width, height)) = keys.terminal_size() {
+                if (width, height) != (self.terminal_size.0, self.terminal_size.1) {
+                    self.resize(width, height);
+                }
+            }
             self.flush();
-            self.out.flush().unwrap();
+            self.out.flush().map_err(EditorError::Terminal)?;
         }
+        Ok(())
     }
 }
 
 fn main() {
-    let args: Vec<String> = args().collect();
-    if args.len() < 2 {
+    let mut file_name = None;
+    let mut modifiable = true;
+    let mut readonly = false;
+    let mut session = None;
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-M" => modifiable = false,
+            "-R" => readonly = true,
+            "-S" => session = args.next(),
+            _ => file_name = Some(arg),
+        }
+    }
+    let session = match session.as_deref().map(Session::read).transpose() {
+        Ok(session) => session,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    // a session is enough to start with
+    let file_name = file_name.or_else(|| {
+        let session = session.as_ref()?;
+        Some(session.buffers[0].file_name.clone())
+    });
+    let Some(file_name) = file_name else {
         println!("Please provide file name as arguments");
         std::process::exit(0);
-    }
+    };
 
-    if !std::path::Path::new(&args[1]).exists() {
-        println!("file {} doesn't exist!", args[1]);
-        std::process::exit(0);
+    let mut editor = match TextEditor::new(&file_name) {
+        Ok(editor) => editor,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    editor.apply_config(&Config::load());
+    editor.modifiable = modifiable;
+    editor.readonly |= readonly;
+    if let Some(session) = session {
+        editor.restore_session(session);
+    }
+    let result = editor.run(&mut stdin().keys());
+    // leave raw mode and the alternate screen before printing
+    drop(editor);
+    if let Err(err) = result {
+        eprintln!("{err}");
+        std::process::exit(1);
     }
-
-    let mut editor = TextEditor::new(&args[1]);
-    editor.run();
 }
@@ -1,12 +1,16 @@
 use termion::event::Key;
 
-use crate::{command::Action, CharacterView, Coordinates, LineView, SelectView, TextEditor};
+use crate::{
+    command::Action, register::Register, CharacterView, Coordinates, CursorEdit, LineView,
+    SelectView, TextEditor,
+};
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Mode {
     Normal,
     Visual,
     Insert,
+    Replace,
     Command,
     Search,
     Exit,
@@ -18,6 +22,7 @@ impl std::fmt::Display for Mode {
             Mode::Normal => "NORMAL",
             Mode::Visual => "VISUAL",
             Mode::Insert => "INSERT",
+            Mode::Replace => "REPLACE",
             Mode::Command => "COMMAND",
             Mode::Search => "SEARCH",
             Mode::Exit => "EXIT",
@@ -28,30 +33,101 @@ impl std::fmt::Display for Mode {
 }
 
 impl Mode {
+    // a key that changes the mode also changes the cursor to the one of
+    // the new mode
     pub fn handle(&self, editor: &mut TextEditor, key: Key) -> Self {
+        let mode = self.handle_key(editor, key);
+        if editor.action_stack.take_changed() {
+            editor.modified = true;
+        }
+        if mode != *self {
+            editor.set_cursor_style(crate::cursor_style_for(mode));
+        }
+        mode
+    }
+
+    fn handle_key(&self, editor: &mut TextEditor, key: Key) -> Self {
+        if *self != Mode::Command && *self != Mode::Search {
+            editor.clear_message();
+        }
+        if let Some((_, keys)) = &mut editor.recording {
+            keys.push(key);
+        }
+        if editor.substituting.is_some() {
+            editor.substitute_interactive(key);
+            return Mode::Normal;
+        }
         match self {
             Mode::Normal => Self::handle_normal(editor, key),
             Mode::Visual => Self::handle_visual(editor, key),
             Mode::Insert => Self::handle_insert(editor, key),
+            Mode::Replace => Self::handle_replace(editor, key),
             Mode::Command => Self::handle_command(editor, key),
             Mode::Search => Self::handle_command(editor, key),
             Mode::Exit => unreachable!(),
         }
     }
 
-    fn pre_handle_normal(editor: &mut TextEditor, key: Key) -> bool {
+    fn pre_handle_normal(editor: &mut TextEditor, key: Key) -> Option<Mode> {
         match key {
+            // `"2` names the register the next `p` puts
+            Key::Char(_) if editor.task.last_task() == Some(&Key::Char('"')) => {
+                editor.task.push(key)
+            }
+            Key::Char('w')
+                if editor.task.ends_with("di")
+                    || editor.task.ends_with("ci")
+                    || editor.task.last_task() == Some(&Key::Char('d')) =>
+            {
+                editor.task.push(key)
+            }
+            // `d(` and `d)`, but not after the register name of `"d`
+            Key::Char('(' | ')')
+                if editor.task.last_task() == Some(&Key::Char('d'))
+                    && editor.task.register_after('"').is_none() =>
+            {
+                editor.task.push(key)
+            }
             Key::Char(c @ '0'..='9') => {
                 if c == '0' {
                     if editor.task.has_num() {
                         editor.task.push(key);
                     } else {
-                        return false;
+                        return None;
                     }
                 } else {
                     editor.task.push(key);
                 }
             }
+            Key::Char(_)
+                if editor.task.ends_with("gq")
+                    || editor.task.ends_with("gqa")
+                    || editor.task.ends_with("gqi") =>
+            {
+                editor.task.push(key)
+            }
+            Key::Char(_) if matches!(editor.task.last_task(), Some(Key::Char('q' | '@'))) => {
+                editor.task.push(key)
+            }
+            Key::Char('j' | 'k' | 'e' | 'E' | 'J' | '_')
+                if editor.task.last_task() == Some(&Key::Char('g')) =>
+            {
+                editor.task.push(key)
+            }
+            Key::Char('t' | 'b' | 'a' | 'f' | 'R' | 'M')
+                if editor.task.last_task() == Some(&Key::Char('z')) =>
+            {
+                editor.task.push(key)
+            }
+            // the motion of `zf`
+            Key::Char('j' | 'k' | 'G') if editor.task.to_string().contains("zf") => {
+                editor.task.push(key)
+            }
+            _ if editor.task.to_string().contains("zf") && key != Key::Esc => {
+                editor.task.clear();
+                editor.show_message("zf: only j, k and G are supported as motions");
+                return Some(Mode::Normal);
+            }
             Key::Char('j')
             | Key::Char('k')
             | Key::Char('h')
@@ -59,39 +135,116 @@ impl Mode {
             | Key::Char('e')
             | Key::Char('w')
             | Key::Char('b')
+            | Key::Char('E')
+            | Key::Char('W')
+            | Key::Char('B')
             | Key::Char(' ')
             | Key::Backspace
             | Key::Left
             | Key::Right
             | Key::Down
-            | Key::Up => {
-                if editor.task.has_num() {
-                    editor.task.push(key)
-                } else {
-                    return false;
-                }
+            | Key::Up
+                if editor.task.has_num() =>
+            {
+                editor.task.push(key)
             }
-            Key::Char('i') | Key::Char('a') => {
-                if editor.task.len() > 0 {
-                    editor.task.push(key);
-                } else {
-                    return false;
-                }
+            Key::Char('i') | Key::Char('a') if editor.task.len() > 0 && !editor.task.is_count() => {
+                editor.task.push(key);
+            }
+            // the count is kept until the insert ends, see `repeat_insert`
+            Key::Char('i' | 'a' | 'I' | 'A' | 'o' | 'O') if editor.task.is_count() => {
+                editor.task.push(key);
+                return None;
+            }
+            Key::Char('c')
+            | Key::Char('d')
+            | Key::Char('y')
+            | Key::Char('g')
+            | Key::Char('z')
+            | Key::Char('@')
+            | Key::Char('"')
+            | Key::Char('>')
+            | Key::Char('<')
+            | Key::Char('=') => editor.task.push(key),
+            Key::Char('q') if editor.recording.is_none() => editor.task.push(key),
+            Key::Char('G') if editor.task.last_task() == Some(&Key::Char('=')) => {
+                editor.task.push(key)
+            }
+            Key::Char('n') | Key::Char('N') | Key::Char('v') | Key::Char('q')
+                if editor.task.last_task() == Some(&Key::Char('g')) =>
+            {
+                editor.task.push(key)
             }
-            Key::Char('c') | Key::Char('d') | Key::Char('y') => editor.task.push(key),
             _ => {
-                return false;
+                if matches!(
+                    editor.task.last_task(),
+                    Some(Key::Char('g' | 'z' | '=' | 'q' | '@' | '"' | '>' | '<'))
+                ) || editor.task.to_string().contains("gq")
+                    || editor.task.to_string().contains("zf")
+                    || editor.task.register_after('"').is_some() && key != Key::Char('p')
+                {
+                    editor.task.clear();
+                }
+                return None;
             }
         }
 
-        editor.try_perform_task();
-        true
+        Some(editor.try_perform_task())
+    }
+
+    fn is_modifying(key: Key) -> bool {
+        matches!(
+            key,
+            Key::Char('x')
+                | Key::Char('D')
+                | Key::Char('C')
+                | Key::Char('s')
+                | Key::Char('S')
+                | Key::Char('o')
+                | Key::Char('O')
+                | Key::Char('i')
+                | Key::Char('a')
+                | Key::Char('A')
+                | Key::Char('I')
+                | Key::Char('R')
+                | Key::Char('p')
+                | Key::Char('J')
+                | Key::Char('u')
+                | Key::Char('.')
+                | Key::Char('c')
+                | Key::Char('d')
+                | Key::Char('=')
+                | Key::Char('>')
+                | Key::Char('<')
+                | Key::Ctrl('r')
+                | Key::Ctrl('a')
+                | Key::Ctrl('x')
+        )
     }
 
     pub fn handle_normal(editor: &mut TextEditor, key: Key) -> Self {
+        // the key after `g`, `z`, `q`, `"` or `@` is part of that command, as
+        // the `a` of `za` and `qa`. `gJ` checks for itself
+        let modifying = Self::is_modifying(key)
+            && !matches!(
+                editor.task.last_task(),
+                Some(Key::Char('g' | 'z' | 'q' | '"' | '@'))
+            );
+        if modifying && !editor.can_modify() {
+            editor.task.clear();
+            return Mode::Normal;
+        }
+        // a change of its own, which sets `last_change` again if `.` is to
+        // repeat it as a command
+        if modifying
+            && !matches!(key, Key::Char('.' | 'u') | Key::Ctrl('r'))
+            && !editor.processing_action
+        {
+            editor.last_change = None;
+        }
         if !editor.processing_task {
-            if Self::pre_handle_normal(editor, key) {
-                return Mode::Normal;
+            if let Some(mode) = Self::pre_handle_normal(editor, key) {
+                return mode;
             }
         }
         match key {
@@ -101,11 +254,11 @@ impl Mode {
                 Mode::Normal
             }
             Key::Char('j') | Key::Down => {
-                editor.inc_y();
+                editor.next_line();
                 Mode::Normal
             }
             Key::Char('k') | Key::Up => {
-                editor.dec_y();
+                editor.prev_line();
                 Mode::Normal
             }
             Key::Char('l') | Key::Right => {
@@ -115,7 +268,6 @@ impl Mode {
             Key::Char('A') => {
                 editor.change_mode_immediately(Mode::Insert);
                 editor.move_to_end_of_line();
-                editor.set_cursor_style(crate::CursorStyle::Bar);
                 editor
                     .action_stack
                     .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
@@ -124,30 +276,115 @@ impl Mode {
             Key::Char('I') => {
                 editor.change_mode_immediately(Mode::Insert);
                 editor.move_to_first_char_of_line();
-                editor.set_cursor_style(crate::CursorStyle::Bar);
                 editor
                     .action_stack
                     .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
                 Mode::Insert
             }
+            // with a count, the end of the line `count - 1` lines below
             Key::Char('$') => {
+                let count = editor.task.num().unwrap_or(1);
+                editor.task.clear();
+                editor.jump_to(editor.cur_line + count - 1, 1);
                 editor.move_to_end_of_line();
                 Mode::Normal
             }
+            Key::Char('(' | ')') => {
+                let count = editor.task.num().unwrap_or(1);
+                editor.task.clear();
+                editor.move_by_sentences(count, key == Key::Char(')'));
+                Mode::Normal
+            }
+            Key::Char('^') => {
+                editor.task.clear();
+                editor.move_to_first_char_of_line();
+                Mode::Normal
+            }
             Key::Char('0') => {
                 editor.move_to_start_of_line();
                 Mode::Normal
             }
+            // `+` and Enter go to the first non-blank of a line below, `-`
+            // of a line above
+            Key::Char('+' | '\n' | '-') => {
+                let count = editor.task.num().unwrap_or(1);
+                editor.task.clear();
+                let line = if key == Key::Char('-') {
+                    editor.cur_line.saturating_sub(count)
+                } else {
+                    editor.cur_line + count
+                };
+                editor.jump_to(line, 1);
+                editor.move_to_first_char_of_line();
+                Mode::Normal
+            }
             Key::Char('e') => {
-                editor.forward_to_end_of_next_word();
+                editor.forward_to_end_of_next_word(TextEditor::word_class);
+                Mode::Normal
+            }
+            Key::Char('E') => {
+                editor.forward_to_end_of_next_word(TextEditor::big_word_class);
                 Mode::Normal
             }
             Key::Char('w') => {
-                editor.forward_to_start_of_next_word();
+                editor.forward_to_start_of_next_word(TextEditor::word_class);
+                Mode::Normal
+            }
+            Key::Char('W') => {
+                editor.forward_to_start_of_next_word(TextEditor::big_word_class);
                 Mode::Normal
             }
             Key::Char('b') => {
-                editor.backward_to_start_of_next_word();
+                editor.backward_to_start_of_next_word(TextEditor::word_class);
+                Mode::Normal
+            }
+            Key::Char('B') => {
+                editor.backward_to_start_of_next_word(TextEditor::big_word_class);
+                Mode::Normal
+            }
+            Key::Ctrl('d') => {
+                editor.add_cursor_at_next_match();
+                Mode::Normal
+            }
+            Key::Char('q') => {
+                editor.stop_recording();
+                Mode::Normal
+            }
+            Key::Ctrl('a') | Key::Ctrl('x') => {
+                let count = editor.task.num().unwrap_or(1) as i64;
+                editor.task.clear();
+                editor.add_to_number(if key == Key::Ctrl('a') { count } else { -count });
+                Mode::Normal
+            }
+            Key::Char('R') => {
+                editor.replacing = Some((editor.text.line_at(editor.cur_line - 1), vec![]));
+                Mode::Replace
+            }
+            Key::Ctrl('o') => {
+                editor.jump_back();
+                Mode::Normal
+            }
+            // the terminal sends `Ctrl-i` as a tab
+            Key::Ctrl('i') | Key::Char('\t') => {
+                editor.jump_forward();
+                Mode::Normal
+            }
+            Key::Ctrl('f') => {
+                editor.page_down();
+                Mode::Normal
+            }
+            Key::Ctrl('b') => {
+                editor.page_up();
+                Mode::Normal
+            }
+            Key::Ctrl('e') | Key::Ctrl('y') => {
+                let count = editor.task.num().unwrap_or(1);
+                editor.task.clear();
+                editor.scroll_lines(count, key == Key::Ctrl('e'));
+                Mode::Normal
+            }
+            Key::Esc => {
+                editor.cursors.clear();
                 Mode::Normal
             }
             Key::Ctrl('r') => {
@@ -161,14 +398,21 @@ impl Mode {
                 Mode::Normal
             }
             Key::Char('.') => {
-                editor.repeating_action = true;
-                editor.restore_action(editor.action_stack.current());
+                let count = editor.task.num().unwrap_or(1);
+                editor.task.clear();
+                for _ in 0..count {
+                    if let Some(change) = editor.last_change.clone() {
+                        editor.repeat_change(change);
+                    } else {
+                        editor.repeating_action = true;
+                        editor.restore_action(editor.action_stack.current());
+                    }
+                }
                 Mode::Normal
             }
             Key::Char('a') => {
                 editor.change_mode_immediately(Mode::Insert);
                 editor.inc_x();
-                editor.set_cursor_style(crate::CursorStyle::Bar);
                 editor
                     .action_stack
                     .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
@@ -179,7 +423,11 @@ impl Mode {
                 Mode::Normal
             }
             Key::Char('x') => {
+                let (line, col) = (editor.cur_line - 1, editor.cur_pos.x - 1);
                 let c = editor.delete_cur_char();
+                if c.is_some() {
+                    editor.edit_at_cursors(CursorEdit::Delete, line, col);
+                }
                 if !editor.processing_action {
                     editor
                         .action_stack
@@ -188,13 +436,44 @@ impl Mode {
                 if let Some(c) = c {
                     if !editor.processing_action {
                         editor.action_stack.append_key_to_top(Key::Char(c));
+                        editor.registers.set_unnamed(Register {
+                            text: c.to_string(),
+                            linewise: false,
+                        });
                     }
                 }
                 Mode::Normal
             }
+            Key::Char('p') => {
+                let name = editor.task.register_after('"').unwrap_or('"');
+                editor.task.clear();
+                editor.put_after(name);
+                Mode::Normal
+            }
+            Key::Char('J') => {
+                let count = editor.task.num().unwrap_or(2);
+                editor.task.clear();
+                editor.join_lines(count, true);
+                Mode::Normal
+            }
+            Key::Char('D') => {
+                if editor.delete_to_end_of_line() {
+                    editor.cur_pos.x = 1.max(editor.cur_pos.x - 1);
+                }
+                Mode::Normal
+            }
+            Key::Char('C') => {
+                if !editor.delete_to_end_of_line() {
+                    return Mode::Normal;
+                }
+                editor.change_mode_immediately(Mode::Insert);
+                editor
+                    .action_stack
+                    .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                Mode::Insert
+            }
             Key::Char('s') => {
                 editor.delete_cur_char();
-                editor.set_cursor_style(crate::CursorStyle::Bar);
                 // FIXME: substitute action conclude both insert and delete
                 editor
                     .action_stack
@@ -202,13 +481,11 @@ impl Mode {
                 Mode::Insert
             }
             Key::Char('S') => {
+                let indent = editor.cur_indent();
                 editor.delete_cur_line();
-                editor.set_cursor_style(crate::CursorStyle::Bar);
-                editor.new_line_ahead();
+                editor.new_line_ahead(&indent);
                 // FIXME: substitute action conclude both insert and delete
-                editor
-                    .action_stack
-                    .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                editor.add_indented_insert_action(&indent);
                 Mode::Insert
             }
             Key::Char(' ') => {
@@ -216,23 +493,18 @@ impl Mode {
                 Mode::Normal
             }
             Key::Char('o') => {
-                editor.set_cursor_style(crate::CursorStyle::Bar);
-                editor.new_line_behind();
-                editor
-                    .action_stack
-                    .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                let indent = editor.cur_indent();
+                editor.new_line_behind(&indent);
+                editor.add_indented_insert_action(&indent);
                 Mode::Insert
             }
             Key::Char('O') => {
-                editor.set_cursor_style(crate::CursorStyle::Bar);
-                editor.new_line_ahead();
-                editor
-                    .action_stack
-                    .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                let indent = editor.cur_indent();
+                editor.new_line_ahead(&indent);
+                editor.add_indented_insert_action(&indent);
                 Mode::Insert
             }
             Key::Char('i') => {
-                editor.set_cursor_style(crate::CursorStyle::Bar);
                 if !editor.processing_action {
                     editor
                         .action_stack
@@ -241,10 +513,26 @@ impl Mode {
                 Mode::Insert
             }
             Key::Char(':') => {
-                editor.set_cursor_style(crate::CursorStyle::Bar);
+                editor.clear_message();
                 Mode::Command
             }
-            Key::Char('/') => Mode::Search,
+            Key::Char('/') => {
+                editor.clear_message();
+                editor.search_origin = Some((editor.cur_line, editor.cur_pos));
+                Mode::Search
+            }
+            Key::Char('n') => {
+                editor.search_next(editor.search_forward);
+                Mode::Normal
+            }
+            Key::Char('N') => {
+                editor.search_next(!editor.search_forward);
+                Mode::Normal
+            }
+            Key::Char('*') | Key::Char('#') => {
+                editor.search_word_under_cursor(key == Key::Char('*'));
+                Mode::Normal
+            }
             Key::Char('v') => {
                 let mut pos = editor.cur_pos;
                 pos = Coordinates {
@@ -262,6 +550,23 @@ impl Mode {
                 editor.set_visual_mode(mode);
                 Mode::Visual
             }
+            Key::Ctrl('v') => {
+                let pos = Coordinates {
+                    x: editor.cur_pos.x - 1,
+                    y: editor.cur_line - 1,
+                };
+                editor.set_visual_mode(SelectView::BlockView(CharacterView {
+                    start: pos,
+                    end: pos,
+                }));
+                Mode::Visual
+            }
+            Key::Char('G') => {
+                editor.push_jump();
+                editor.jump_to(editor.text_length(), 1);
+                editor.move_to_first_char_of_line();
+                Mode::Normal
+            }
             Key::Char('V') => {
                 let mode = SelectView::LineView(LineView {
                     start: editor.cur_line - 1,
@@ -275,9 +580,100 @@ impl Mode {
     }
 
     fn handle_visual(editor: &mut TextEditor, key: Key) -> Self {
+        if matches!(
+            key,
+            Key::Char('c' | 'd' | '=' | '>' | '<' | 'I' | 'A' | 'u' | 'U' | '~' | 'r')
+        ) {
+            if !editor.can_modify() {
+                return Mode::Visual;
+            }
+            editor.last_change = None;
+        }
+        // a pending `g` applies to this key only
+        let after_g = editor.task.last_task() == Some(&Key::Char('g'));
+        if after_g {
+            editor.task.clear();
+        }
+        // `r` replaces every selected char with the next one typed, but for
+        // Enter which would break lines
+        if editor.task.last_task() == Some(&Key::Char('r')) {
+            editor.task.clear();
+            let Key::Char(c) = key else {
+                return Mode::Visual;
+            };
+            if c == '\n' {
+                return Mode::Visual;
+            }
+            editor.map_range(|_| c);
+            editor.set_visual_mode(SelectView::None);
+            return Mode::Normal;
+        }
         let mode = match key {
             Key::Esc => {
-                editor.set_cursor_style(crate::CursorStyle::Block);
+                editor.task.clear();
+                editor.set_visual_mode(SelectView::None);
+                return Mode::Normal;
+            }
+            Key::Char('g') if after_g => {
+                editor.jump_to(1, 1);
+                editor.move_to_first_char_of_line();
+                Mode::Visual
+            }
+            Key::Char('g') => {
+                editor.task.push(key);
+                Mode::Visual
+            }
+            Key::Char('G') => {
+                editor.jump_to(editor.text_length(), 1);
+                editor.move_to_first_char_of_line();
+                Mode::Visual
+            }
+            // a count, for `>` and `<`
+            Key::Char(c @ '0'..='9') if c != '0' || editor.task.has_num() => {
+                editor.task.push(key);
+                Mode::Visual
+            }
+            Key::Char(c @ ('>' | '<')) => {
+                let count = editor.task.num().unwrap_or(1);
+                editor.task.clear();
+                if let Some((first, last)) = editor.selected_lines() {
+                    editor.shift_lines(first, last, count, c == '>');
+                }
+                editor.set_visual_mode(SelectView::None);
+                return Mode::Normal;
+            }
+            Key::Char('=') => {
+                if let Some((first, last)) = editor.selected_lines() {
+                    editor.filter_lines(first, last);
+                }
+                editor.set_visual_mode(SelectView::None);
+                return Mode::Normal;
+            }
+            Key::Char('c') if after_g => {
+                if let Some((first, last)) = editor.selected_lines() {
+                    editor.toggle_comment(first, last);
+                }
+                editor.set_visual_mode(SelectView::None);
+                return Mode::Normal;
+            }
+            Key::Char('q') if after_g => {
+                if let Some((first, last)) = editor.selected_lines() {
+                    if editor.can_modify() {
+                        editor.format_lines(first, last);
+                    }
+                }
+                editor.set_visual_mode(SelectView::None);
+                return Mode::Normal;
+            }
+            Key::Char('z') => {
+                editor.task.push(key);
+                Mode::Visual
+            }
+            Key::Char('f') if editor.task.last_task() == Some(&Key::Char('z')) => {
+                editor.task.clear();
+                if let Some((first, last)) = editor.selected_lines() {
+                    editor.create_fold(first, last);
+                }
                 editor.set_visual_mode(SelectView::None);
                 return Mode::Normal;
             }
@@ -295,12 +691,22 @@ impl Mode {
                 Mode::Visual
             }
             Key::Char('l') | Key::Right => {
-                editor.inc_x();
+                if editor.is_virtual_block() {
+                    editor.cur_pos.x += 1;
+                } else {
+                    editor.inc_x();
+                }
                 Mode::Visual
             }
+            Key::Char(c @ ('I' | 'A'))
+                if matches!(editor.select_view, SelectView::BlockView(_)) =>
+            {
+                editor.start_block_insert(c == 'A');
+                editor.set_visual_mode(SelectView::None);
+                return Mode::Insert;
+            }
             Key::Char('c') => {
                 editor.delete_selected();
-                editor.set_cursor_style(crate::CursorStyle::Bar);
                 editor.set_visual_mode(SelectView::None);
                 editor
                     .action_stack
@@ -309,7 +715,29 @@ impl Mode {
             }
             Key::Char('d') => {
                 editor.delete_selected();
-                editor.set_cursor_style(crate::CursorStyle::Block);
+                editor.set_visual_mode(SelectView::None);
+                return Mode::Normal;
+            }
+            Key::Char('o') => {
+                editor.swap_selection_ends();
+                Mode::Visual
+            }
+            Key::Char('r') => {
+                editor.task.clear();
+                editor.task.push(key);
+                Mode::Visual
+            }
+            Key::Char(c @ ('u' | 'U' | '~')) => {
+                match c {
+                    'u' => editor.map_range(|c| c.to_lowercase().next().unwrap_or(c)),
+                    'U' => editor.map_range(|c| c.to_uppercase().next().unwrap_or(c)),
+                    _ => editor.map_range(TextEditor::toggle_case),
+                }
+                editor.set_visual_mode(SelectView::None);
+                return Mode::Normal;
+            }
+            Key::Char('y') => {
+                editor.yank_selected();
                 editor.set_visual_mode(SelectView::None);
                 return Mode::Normal;
             }
@@ -320,54 +748,110 @@ impl Mode {
         mode
     }
     pub fn handle_insert(editor: &mut TextEditor, key: Key) -> Self {
+        if matches!(key, Key::Char(_) | Key::Backspace | Key::Delete) && !editor.can_modify() {
+            return Mode::Insert;
+        }
+        if !matches!(key, Key::Ctrl('n') | Key::Ctrl('p')) {
+            editor.keyword_completion = None;
+        }
+        if let Some(code) = editor.literal.take() {
+            return Self::handle_literal(editor, code, key);
+        }
         match key {
             Key::Char(c) => {
-                if c == '\n' {
-                    editor.new_line();
-                } else if c == '\t' {
+                if c == '\t' && editor.expandtab {
+                    // insert the spaces one by one so that undo removes them
+                    for _ in 0..editor.tab_width {
+                        Self::handle_insert(editor, Key::Char(' '));
+                    }
+                    return Mode::Insert;
+                }
+                if editor.autopairs && !editor.processing_action && editor.cursors.is_empty() {
+                    if Self::is_closing(c) && editor.cur_char() == c {
+                        // type over the closing char instead of doubling it
+                        editor.inc_x();
+                        editor.action_stack.append_key_to_top(Key::Right);
+                        return Mode::Insert;
+                    }
                     let x = editor.cur_line - 1;
                     let y = editor.cur_pos.x - 1;
-                    for _ in 0..4 {
-                        editor.text.insert_at(x, y, ' ');
+                    // a quote right after a word is an apostrophe, as in `don't`
+                    let after_word = y > 0 && {
+                        let prev = editor.text.char_at(x, y - 1);
+                        prev.is_alphanumeric() || prev == '_'
+                    };
+                    let close =
+                        Self::closing_pair(c).filter(|_| !(after_word && matches!(c, '\'' | '"')));
+                    if let Some(close) = close {
+                        editor.text.insert_at(x, y, c);
+                        editor.text.insert_at(x, y + 1, close);
                         editor.inc_x();
+                        // recorded so that replaying the keys leaves the cursor
+                        // between the pair, as it is now
+                        for key in [Key::Char(c), Key::Char(close), Key::Left] {
+                            editor.action_stack.append_key_to_top(key);
+                        }
+                        return Mode::Insert;
                     }
+                }
+                let mut recorded = vec![key];
+                if c == '\n' {
+                    // secondary cursors only follow edits within a line
+                    editor.cursors.clear();
+                    // the auto-inserted indent is recorded as typed keys
+                    let indent = editor.new_line();
+                    recorded.extend(indent.chars().map(Key::Char));
                 } else {
                     let x = editor.cur_line - 1;
                     let y = editor.cur_pos.x - 1;
                     editor.text.insert_at(x, y, c);
                     editor.inc_x();
+                    editor.edit_at_cursors(CursorEdit::Insert(c), x, y);
                 }
                 if !editor.processing_action {
-                    editor.action_stack.append_key_to_top(key);
+                    for key in recorded {
+                        editor.action_stack.append_key_to_top(key);
+                    }
+                    if !TextEditor::is_blank(c) && editor.cursors.is_empty() {
+                        editor.wrap_typed_word();
+                    }
                 }
                 Mode::Insert
             }
             Key::Left => {
                 editor.dec_x();
-                editor
-                    .action_stack
-                    .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                if !editor.processing_action {
+                    editor
+                        .action_stack
+                        .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                }
                 Mode::Insert
             }
             Key::Down => {
                 editor.inc_y();
-                editor
-                    .action_stack
-                    .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                if !editor.processing_action {
+                    editor
+                        .action_stack
+                        .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                }
                 Mode::Insert
             }
             Key::Up => {
                 editor.dec_y();
-                editor
-                    .action_stack
-                    .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                if !editor.processing_action {
+                    editor
+                        .action_stack
+                        .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                }
                 Mode::Insert
             }
             Key::Right => {
                 editor.inc_x();
-                editor
-                    .action_stack
-                    .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                if !editor.processing_action {
+                    editor
+                        .action_stack
+                        .add_action(Action::Insert, editor.cur_line, editor.cur_pos);
+                }
                 Mode::Insert
             }
             Key::Backspace => {
@@ -375,73 +859,281 @@ impl Mode {
                 let y = editor.cur_pos.x - 1;
                 if y == 0 {
                     if x != 0 {
-                        editor.delete_line_at(x);
+                        editor.cursors.clear();
+                        // join the current line onto the previous one
+                        let contents = editor.delete_line_at(x);
                         editor.dec_y();
-                        editor.move_to_end_of_line();
+                        let len = editor.text.len_of_line_at(x - 1);
+                        editor.text.append_str_at(x - 1, len, contents);
+                        editor.cur_pos.x = len + 1;
                     }
                 } else {
-                    editor.text.delete_at(x, y);
+                    let prev = editor.text.char_at(x, y - 1);
+                    let next = editor.cur_char();
+                    let pair = editor.autopairs
+                        && editor.cursors.is_empty()
+                        && Self::closing_pair(prev) == Some(next);
+                    editor.text.delete_at(x, y - 1);
                     editor.dec_x();
+                    editor.edit_at_cursors(CursorEdit::Backspace, x, y);
+                    if pair {
+                        // an empty pair is deleted as a whole
+                        editor.text.delete_at(x, y - 1);
+                        if !editor.processing_action
+                            && editor.action_stack.current().is_some_and(|action| {
+                                action.contents.ends_with(&[
+                                    Key::Char(prev),
+                                    Key::Char(next),
+                                    Key::Left,
+                                ])
+                            })
+                        {
+                            editor.action_stack.discard_key_on_top();
+                            editor.action_stack.discard_key_on_top();
+                        }
+                    }
                 }
                 if !editor.processing_action {
                     editor.action_stack.discard_key_on_top();
                 }
                 Mode::Insert
             }
+            Key::Ctrl('n') | Key::Ctrl('p') => {
+                editor.complete_keyword(key == Key::Ctrl('n'));
+                Mode::Insert
+            }
+            Key::Ctrl('v') => {
+                editor.literal = Some(String::new());
+                Mode::Insert
+            }
+            Key::Ctrl('w') | Key::Ctrl('u') => {
+                let col = editor.cur_pos.x - 1;
+                let start = if key == Key::Ctrl('w') {
+                    editor.start_of_word_before_cursor()
+                } else {
+                    // the indent is kept, unless the cursor is already in it
+                    let indent = editor.cur_indent().chars().count();
+                    if col > indent {
+                        indent
+                    } else {
+                        0
+                    }
+                };
+                // backspaces join lines at the line start and keep the undo
+                // history of the insertion right
+                for _ in 0..(col - start).max(1) {
+                    Self::handle_insert(editor, Key::Backspace);
+                }
+                Mode::Insert
+            }
             Key::Delete => {
                 editor.delete_cur_char();
                 // TODO: add action
                 Mode::Insert
             }
             Key::Esc => {
+                editor.repeat_insert();
                 editor.dec_x();
-                editor.set_cursor_style(crate::CursorStyle::Block);
+                editor.finish_block_insert();
+                editor.finish_change();
+                editor.cursors.clear();
                 Mode::Normal
             }
             Key::Ctrl('q') => Mode::Exit,
             _ => Mode::Insert,
         }
     }
-    fn handle_command(editor: &mut TextEditor, key: Key) -> Self {
+    pub fn handle_replace(editor: &mut TextEditor, key: Key) -> Self {
+        let x = editor.cur_line - 1;
+        let y = editor.cur_pos.x - 1;
+        let Some((old, replaced)) = &mut editor.replacing else {
+            return Mode::Normal;
+        };
         match key {
+            Key::Char('\n') => (),
             Key::Char(c) => {
-                if c == '\n' {
-                    match editor.try_perform_command() {
-                        Some(mode) => return mode,
-                        _ => {}
-                    }
-                } else {
-                    editor.bar_text.push_char_at_line(0, c);
-                }
-                editor.mode
+                // past the end of the line the char is appended
+                let typed_over = editor.text.remove_str_at(x, y, 1).chars().next();
+                editor.text.insert_str_padded(x, y, &c.to_string());
+                replaced.push(typed_over);
+                editor.inc_x();
             }
             Key::Backspace => {
-                editor.bar_text.pop_char_at_line(0);
-                Mode::Command
+                // put back the char typed over, the cursor only moves left
+                // over the text that was there before `R`
+                if let Some(typed_over) = replaced.pop() {
+                    editor.text.remove_str_at(x, y - 1, 1);
+                    if let Some(c) = typed_over {
+                        editor.text.insert_str_padded(x, y - 1, &c.to_string());
+                    }
+                }
+                editor.dec_x();
             }
             Key::Esc => {
-                editor.bar_text.delete_line_at(0);
-                editor.set_cursor_style(crate::CursorStyle::Block);
-                Mode::Normal
+                let old = old.clone();
+                editor.replacing = None;
+                let new = editor.text.line_at(x);
+                if new != old {
+                    editor.action_stack.add_action(
+                        Action::Replace(vec![old]),
+                        editor.cur_line,
+                        editor.cur_pos,
+                    );
+                    editor.action_stack.append_string_to_top(new);
+                }
+                editor.dec_x();
+                return Mode::Normal;
             }
-            Key::Ctrl('q') => Mode::Exit,
-            _ => editor.mode,
+            Key::Ctrl('q') => return Mode::Exit,
+            _ => (),
         }
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    fn init(lines: Vec<String>) -> TextEditor {
-        return TextEditor::new_from_vec(&lines);
+        Mode::Replace
     }
 
-    fn handle_keys(editor: &mut TextEditor, keys: Vec<Key>) {
-        let mut mode = Mode::Normal;
-        for c in keys {
+    // the keys after `Ctrl-v`: a char inserted as it is, or a code point
+    // given as up to 3 decimal digits, or `u` and up to 4 hexadecimal ones.
+    // A code ends early at another key, which is then handled as usual
+    fn handle_literal(editor: &mut TextEditor, mut code: String, key: Key) -> Self {
+        let hex = code.starts_with('u');
+        let (radix, max) = if hex { (16, 4) } else { (10, 3) };
+        let digits = &code[hex as usize..];
+        match key {
+            Key::Char('u') if code.is_empty() => {
+                editor.literal = Some("u".to_string());
+                return Mode::Insert;
+            }
+            Key::Char(c) if c.is_digit(radix) && digits.len() < max => {
+                code.push(c);
+                if code.len() - (hex as usize) < max {
+                    editor.literal = Some(code);
+                } else {
+                    Self::insert_code(editor, &code);
+                }
+                return Mode::Insert;
+            }
+            _ if !code.is_empty() => {
+                Self::insert_code(editor, &code);
+                return Self::handle_insert(editor, key);
+            }
+            _ => (),
+        }
+        let c = match key {
+            // Enter is read as a line break, a carriage return is inserted
+            Key::Char('\n') => '\r',
+            Key::Char(c) => c,
+            Key::Esc => '\x1b',
+            Key::Backspace => '\x7f',
+            Key::Ctrl(c) if c.is_ascii() => (c as u8 & 0x1f) as char,
+            _ => return Mode::Insert,
+        };
+        editor.insert_literal(c);
+        Mode::Insert
+    }
+
+    fn insert_code(editor: &mut TextEditor, code: &str) {
+        let hex = code.starts_with('u');
+        let digits = &code[hex as usize..];
+        if digits.is_empty() {
+            editor.insert_literal('u');
+            return;
+        }
+        let radix = if hex { 16 } else { 10 };
+        let c = u32::from_str_radix(digits, radix)
+            .ok()
+            .and_then(char::from_u32);
+        if let Some(c) = c {
+            editor.insert_literal(c);
+        }
+    }
+
+    // closing counterpart of a bracket or quote typed with `autopairs` on
+    fn closing_pair(c: char) -> Option<char> {
+        match c {
+            '(' => Some(')'),
+            '[' => Some(']'),
+            '{' => Some('}'),
+            '"' => Some('"'),
+            '\'' => Some('\''),
+            _ => None,
+        }
+    }
+    fn is_closing(c: char) -> bool {
+        matches!(c, ')' | ']' | '}' | '"' | '\'')
+    }
+    fn handle_command(editor: &mut TextEditor, key: Key) -> Self {
+        if key == Key::Char('\t') && editor.mode == Mode::Command {
+            editor.complete_command();
+            return editor.mode;
+        }
+        editor.completion = None;
+        match key {
+            Key::Char(c) => {
+                if c == '\n' {
+                    if let Some(mode) = editor.try_perform_command() {
+                        return mode;
+                    }
+                } else {
+                    editor.history_pos = None;
+                    editor.bar_text.push_char_at_line(0, c);
+                    editor.preview_search();
+                }
+                editor.mode
+            }
+            Key::Backspace => {
+                editor.history_pos = None;
+                editor.bar_text.pop_char_at_line(0);
+                editor.preview_search();
+                editor.mode
+            }
+            Key::Up | Key::Down if editor.mode == Mode::Command => {
+                editor.browse_history(key == Key::Up);
+                editor.mode
+            }
+            Key::Esc => {
+                editor.history_pos = None;
+                if let Some(pos) = editor.saved_pos.take() {
+                    editor.cur_pos = pos;
+                }
+                editor.restore_search_origin();
+                editor.bar_text.delete_line_at(0);
+                Mode::Normal
+            }
+            Key::Ctrl('q') => Mode::Exit,
+            _ => editor.mode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::undo_file_of;
+
+    fn init(lines: Vec<String>) -> TextEditor {
+        TextEditor::new_from_vec(&lines)
+    }
+
+    // what the editor writes, still readable once boxed as its output
+    #[derive(Clone, Default)]
+    struct Capture(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn handle_keys(editor: &mut TextEditor, keys: Vec<Key>) {
+        let mut mode = Mode::Normal;
+        editor.mode = mode;
+        for c in keys {
             mode = mode.handle(editor, c);
+            // keep the editor in sync like `TextEditor::run` does
+            editor.mode = mode;
             if mode == Mode::Exit {
                 break;
             }
@@ -449,6 +1141,12 @@ mod tests {
         }
     }
 
+    fn lines(editor: &TextEditor) -> Vec<String> {
+        (0..editor.text_length())
+            .map(|i| editor.text.line_at(i))
+            .collect()
+    }
+
     fn exit(editor: &mut TextEditor) {
         handle_keys(editor, vec![Key::Ctrl('q')]);
     }
@@ -502,6 +1200,34 @@ mod tests {
         exit(&mut editor);
     }
 
+    #[test]
+    fn backward_to_end_of_word_test() {
+        let mut editor = init(vec!["one two three".to_string(), "a.b four".to_string()]);
+        editor.jump_to(1, 9);
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('e')]);
+        assert_eq!(editor.cur_pos.x, 7);
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('e')]);
+        assert_eq!(editor.cur_pos.x, 3);
+        // stop at the start of the file
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('e')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 1));
+
+        editor.jump_to(2, 6);
+        let keys = vec![Key::Char('2'), Key::Char('g'), Key::Char('e')];
+        handle_keys(&mut editor, keys);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 2));
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('e')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 1));
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('e')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 13));
+
+        editor.jump_to(2, 6);
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('E')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 3));
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('E')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 13));
+    }
+
     #[test]
     fn move_between_word() {
         let mut editor = init(vec!["hello".to_string(), "world".to_string()]);
@@ -529,6 +1255,35 @@ mod tests {
         exit(&mut editor);
     }
 
+    #[test]
+    fn move_between_big_word() {
+        let mut editor = init(vec!["foo.bar baz".to_string(), "  x,y".to_string()]);
+
+        handle_keys(&mut editor, vec![Key::Char('w')]);
+        assert_eq!(editor.cur_char(), '.');
+        handle_keys(&mut editor, vec![Key::Char('w')]);
+        assert_eq!(editor.cur_pos.x, 5);
+        handle_keys(&mut editor, vec![Key::Char('0'), Key::Char('W')]);
+        assert_eq!(editor.cur_pos.x, 9);
+        handle_keys(&mut editor, vec![Key::Char('B')]);
+        assert_eq!(editor.cur_pos.x, 1);
+        handle_keys(&mut editor, vec![Key::Char('E')]);
+        assert_eq!(editor.cur_pos.x, 7);
+        handle_keys(&mut editor, vec![Key::Char('e')]);
+        assert_eq!(editor.cur_pos.x, 11);
+
+        // WORDs continue on the next line
+        handle_keys(&mut editor, vec![Key::Char('W')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 3));
+        handle_keys(&mut editor, vec![Key::Char('B')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 9));
+        handle_keys(&mut editor, vec![Key::Char('2'), Key::Char('E')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 5));
+        // and stop at the end of the text
+        handle_keys(&mut editor, vec![Key::Char('W')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 5));
+    }
+
     #[test]
     fn delete_in_insert() {
         let mut editor = init(vec!["hello".to_string(), "world".to_string()]);
@@ -658,9 +1413,16 @@ mod tests {
             Key::Esc,
         ];
         handle_keys(&mut editor, keys);
-        // FIXME: why this test is failed?
-        // assert_eq!(editor.text.line_at(0), "he");
+        assert_eq!(editor.text.line_at(0), "he");
         assert_eq!(editor.text.line_at(1), "world");
+
+        // a whole line selected char-wise is emptied, not removed
+        let mut editor = init(vec!["foo".to_string(), "bar".to_string()]);
+        let keys = "vlld".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.to_string(), "\nbar");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), "foo\nbar");
     }
 
     #[test]
@@ -676,4 +1438,2913 @@ mod tests {
         handle_keys(&mut editor, keys);
         assert_eq!(editor.text.line_at(0), "eo");
     }
+
+    #[test]
+    fn undo_by_time_test() {
+        use crate::command::UndoStep;
+        use std::{
+            cell::Cell,
+            rc::Rc,
+            time::{Duration, Instant},
+        };
+
+        let mut editor = init(vec!["hello".to_string(), "world".to_string()]);
+        let now = Rc::new(Cell::new(Instant::now()));
+        let clock = now.clone();
+        editor.action_stack.set_clock(Box::new(move || clock.get()));
+
+        handle_keys(&mut editor, vec![Key::Char('x')]);
+        now.set(now.get() + Duration::from_secs(120));
+        handle_keys(&mut editor, vec![Key::Char('x')]);
+        now.set(now.get() + Duration::from_secs(30));
+        handle_keys(&mut editor, vec![Key::Char('x')]);
+        assert_eq!(editor.text.line_at(0), "lo");
+
+        // only the last two deletions happened within a minute
+        editor.undo_by(UndoStep::Time(Duration::from_secs(60)));
+        assert_eq!(editor.text.line_at(0), "ello");
+
+        editor.redo_by(UndoStep::Time(Duration::from_secs(120)));
+        assert_eq!(editor.text.line_at(0), "llo");
+        editor.redo_by(UndoStep::Count(1));
+        assert_eq!(editor.text.line_at(0), "lo");
+        editor.undo_by(UndoStep::Count(3));
+        assert_eq!(editor.text.line_at(0), "hello");
+    }
+
+    #[test]
+    fn select_search_match_test() {
+        let mut editor = init(vec!["foo bar foo".to_string(), "bar foo".to_string()]);
+        editor.last_search = Some("foo".to_string());
+
+        // the match under the cursor is selected first
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('n')]);
+        assert_eq!(
+            editor.select_view,
+            SelectView::CharacterView(CharacterView {
+                start: Coordinates { x: 0, y: 0 },
+                end: Coordinates { x: 2, y: 0 },
+            })
+        );
+        assert_eq!(editor.cur_pos.x, 3);
+        editor.set_visual_mode(SelectView::None);
+
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('d'), Key::Char('g'), Key::Char('n')],
+        );
+        assert_eq!(editor.text.line_at(0), " bar foo");
+        assert_eq!(editor.select_view, SelectView::None);
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('d'), Key::Char('g'), Key::Char('n')],
+        );
+        assert_eq!(editor.text.line_at(0), " bar ");
+
+        // gN searches backward and wraps around to the last line
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('0'), Key::Char('g'), Key::Char('N')],
+        );
+        assert_eq!(editor.cur_line, 2);
+        assert_eq!(
+            editor.select_view,
+            SelectView::CharacterView(CharacterView {
+                start: Coordinates { x: 4, y: 1 },
+                end: Coordinates { x: 6, y: 1 },
+            })
+        );
+
+        // `.` changes the next match as `cgn` did
+        let mut editor = init(vec!["foo bar foo".to_string(), "a foo".to_string()]);
+        let mut keys: Vec<Key> = "/foo\ncgnX".chars().map(Key::Char).collect();
+        keys.extend([Key::Esc, Key::Char('.')]);
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "foo bar X");
+        assert_eq!(editor.text.line_at(1), "a X");
+        handle_keys(&mut editor, vec![Key::Char('.')]);
+        assert_eq!(editor.text.line_at(0), "X bar X");
+        handle_keys(&mut editor, vec![Key::Char('u'), Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "foo bar X");
+    }
+
+    #[test]
+    fn incsearch_test() {
+        let mut editor = init(vec![
+            "one".to_string(),
+            "foo x".to_string(),
+            "bar foo".to_string(),
+        ]);
+        let keys = ":set incsearch\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert!(editor.incsearch);
+
+        let mut mode = Mode::Normal;
+        let mut type_key = |editor: &mut TextEditor, key| {
+            mode = mode.handle(editor, key);
+            editor.mode = mode;
+            (editor.cur_line, editor.cur_pos.x)
+        };
+        type_key(&mut editor, Key::Char('/'));
+        assert_eq!(type_key(&mut editor, Key::Char('b')), (3, 1));
+        assert_eq!(type_key(&mut editor, Key::Backspace), (1, 1));
+        assert_eq!(type_key(&mut editor, Key::Char('x')), (2, 5));
+        // canceling goes back to where the search started
+        assert_eq!(type_key(&mut editor, Key::Esc), (1, 1));
+        assert_eq!(editor.mode, Mode::Normal);
+
+        type_key(&mut editor, Key::Char('/'));
+        assert_eq!(type_key(&mut editor, Key::Char('f')), (2, 1));
+        assert_eq!(type_key(&mut editor, Key::Char('o')), (2, 1));
+        assert_eq!(type_key(&mut editor, Key::Char('\n')), (2, 1));
+        assert_eq!(editor.mode, Mode::Normal);
+    }
+
+    #[test]
+    fn create_new_file_test() {
+        let path = std::env::temp_dir().join("vim_rs_create_new_file_test.txt");
+        let _ = std::fs::remove_file(&path);
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        assert_eq!(editor.text_length(), 1);
+        assert_eq!(editor.text.line_at(0), "");
+        assert_eq!(editor.view.upper_line(), 1);
+
+        let keys = vec![Key::Char('i'), Key::Char('h'), Key::Char('i'), Key::Esc];
+        handle_keys(&mut editor, keys);
+        editor.flush_to_disk().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hi\n");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(undo_file_of(path.to_str().unwrap())).unwrap();
+    }
+
+    #[test]
+    fn run_with_scripted_keys_test() {
+        let mut editor = init(vec!["hello".to_string(), "world".to_string()]);
+        let mut keys = vec![
+            Key::Char('x'),
+            Key::Char('A'),
+            Key::Char('!'),
+            Key::Esc,
+            Key::Ctrl('q'),
+            Key::Char('x'),
+        ]
+        .into_iter();
+        editor.run(&mut keys).unwrap();
+        assert_eq!(editor.mode, Mode::Exit);
+        assert_eq!(editor.text.line_at(0), "ello!");
+        // keys after the quit key are never read
+        assert_eq!(keys.next(), Some(Key::Char('x')));
+    }
+
+    #[test]
+    fn horizontal_scroll_test() {
+        let long_line: String = (0..100).map(|i| (b'a' + i % 26) as char).collect();
+        let mut editor = init(vec![long_line.clone(), "world".to_string()]);
+        assert_eq!(editor.terminal_size.0, 80);
+
+        handle_keys(&mut editor, vec![Key::Char('l'); 79]);
+        assert_eq!(editor.view.left_col, 0);
+        handle_keys(&mut editor, vec![Key::Char('l'); 10]);
+        assert_eq!(editor.view.left_col, 10);
+        assert_eq!(editor.cur_char(), long_line.chars().nth(89).unwrap());
+
+        handle_keys(&mut editor, vec![Key::Char('h'); 80]);
+        assert_eq!(editor.view.left_col, 9);
+        assert_eq!(editor.cur_char(), long_line.chars().nth(9).unwrap());
+    }
+
+    #[test]
+    fn backspace_at_line_start_test() {
+        let mut editor = init(vec!["hello".to_string(), "world".to_string()]);
+
+        let keys = vec![Key::Char('j'), Key::Char('i'), Key::Backspace, Key::Esc];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text_length(), 1);
+        assert_eq!(editor.text.line_at(0), "helloworld");
+        assert_eq!(editor.cur_char(), 'o');
+
+        let keys = vec![
+            Key::Char('0'),
+            Key::Char('l'),
+            Key::Char('l'),
+            Key::Char('i'),
+            Key::Char('\n'),
+            Key::Backspace,
+            Key::Char('-'),
+            Key::Esc,
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text_length(), 1);
+        assert_eq!(editor.text.line_at(0), "he-lloworld");
+    }
+
+    #[test]
+    fn auto_indent_test() {
+        let mut editor = init(vec!["    foo".to_string()]);
+
+        let keys = vec![Key::Char('o'), Key::Char('b'), Key::Esc];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(1), "    b");
+
+        let keys = vec![Key::Char('O'), Key::Char('a'), Key::Esc];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(1), "    a");
+
+        // the indent is undone together with the typed text
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(1), "");
+
+        let keys = vec![
+            Key::Char('j'),
+            Key::Char('I'),
+            Key::Char('\n'),
+            Key::Char('c'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(2), "    ");
+        assert_eq!(editor.text.line_at(3), "    cb");
+        handle_keys(&mut editor, vec![Key::Esc, Key::Char('u')]);
+        assert_eq!(editor.text_length(), 3);
+        assert_eq!(editor.text.line_at(2), "    b");
+    }
+
+    #[test]
+    fn autopairs_test() {
+        let mut editor = init(vec!["".to_string()]);
+
+        let keys = vec![Key::Char('i'), Key::Char('(')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "()");
+        assert_eq!(editor.cur_pos.x, 2);
+
+        // the closing char is typed over instead of doubled
+        let keys = vec![Key::Char('a'), Key::Char(')'), Key::Char(';'), Key::Esc];
+        let mut mode = Mode::Insert;
+        for key in keys {
+            mode = mode.handle(&mut editor, key);
+            editor.mode = mode;
+        }
+        assert_eq!(editor.text.line_at(0), "(a);");
+
+        // undo and redo see the same text as was typed
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "");
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text.line_at(0), "(a);");
+
+        let keys = vec![Key::Char('A'), Key::Char('"'), Key::Backspace, Key::Esc];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "(a);");
+
+        // no closing quote after a word char
+        let mut editor = init(vec!["".to_string()]);
+        handle_keys(
+            &mut editor,
+            "idon't say x\"".chars().map(Key::Char).collect(),
+        );
+        assert_eq!(editor.text.line_at(0), "don't say x\"");
+        handle_keys(&mut editor, "A '".chars().map(Key::Char).collect());
+        assert_eq!(editor.text.line_at(0), "don't say x\" ''");
+
+        editor.autopairs = false;
+        handle_keys(&mut editor, vec![Key::Char('A'), Key::Char('['), Key::Esc]);
+        assert_eq!(editor.text.line_at(0), "don't say x\" ''[");
+    }
+
+    #[test]
+    fn toggle_comment_test() {
+        let mut editor = init(vec![
+            "fn main() {".to_string(),
+            "    let a = 1;".to_string(),
+            "".to_string(),
+            "    // let b = 2;".to_string(),
+            "}".to_string(),
+        ]);
+        editor.file_name = "test.rs".to_string();
+
+        let gcc = vec![
+            Key::Char('j'),
+            Key::Char('g'),
+            Key::Char('c'),
+            Key::Char('c'),
+        ];
+        handle_keys(&mut editor, gcc);
+        assert_eq!(editor.text.line_at(1), "    // let a = 1;");
+        assert_eq!(editor.cur_line, 2);
+        let gcc = vec![Key::Char('g'), Key::Char('c'), Key::Char('c')];
+        handle_keys(&mut editor, gcc.clone());
+        assert_eq!(editor.text.line_at(1), "    let a = 1;");
+
+        // each toggle is undone in one step
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(1), "    // let a = 1;");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(1), "    let a = 1;");
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text.line_at(1), "    // let a = 1;");
+
+        // the selection is uncommented only when all of it is commented
+        let keys = vec![
+            Key::Char('V'),
+            Key::Char('j'),
+            Key::Char('j'),
+            Key::Char('g'),
+            Key::Char('c'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.mode, Mode::Normal);
+        assert_eq!(editor.text.line_at(1), "    let a = 1;");
+        assert_eq!(editor.text.line_at(2), "");
+        assert_eq!(editor.text.line_at(3), "    let b = 2;");
+        let keys = vec![
+            Key::Char('V'),
+            Key::Char('k'),
+            Key::Char('g'),
+            Key::Char('c'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "// fn main() {");
+        assert_eq!(editor.text.line_at(1), "    // let a = 1;");
+
+        // and a selection of lines is undone at once too
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "fn main() {");
+        assert_eq!(editor.text.line_at(1), "    let a = 1;");
+    }
+
+    #[test]
+    fn multi_cursor_test() {
+        let mut editor = init(vec![
+            "foo bar".to_string(),
+            "food foo".to_string(),
+            "foo".to_string(),
+        ]);
+
+        // `food` isn't a whole word match
+        let keys = vec![Key::Char('l'), Key::Ctrl('d'), Key::Ctrl('d')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(
+            editor.cursors,
+            vec![Coordinates { x: 1, y: 6 }, Coordinates { x: 2, y: 1 }]
+        );
+        handle_keys(&mut editor, vec![Key::Ctrl('d')]);
+        assert_eq!(editor.cursors.len(), 2);
+
+        let keys = vec![Key::Char('i'), Key::Char('-'), Key::Char('-')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "f--oo bar");
+        assert_eq!(editor.text.line_at(1), "food f--oo");
+        assert_eq!(editor.text.line_at(2), "f--oo");
+
+        let mut mode = Mode::Insert;
+        for key in [Key::Backspace, Key::Esc] {
+            mode = mode.handle(&mut editor, key);
+            editor.mode = mode;
+        }
+        assert_eq!(editor.text.line_at(1), "food f-oo");
+        assert_eq!(editor.text.line_at(2), "f-oo");
+        assert!(editor.cursors.is_empty());
+
+        // cursors on the same line keep their place relative to each other
+        let mut editor = init(vec!["ab ab ab".to_string()]);
+        let keys = vec![Key::Ctrl('d'), Key::Ctrl('d'), Key::Char('x')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "b b b");
+        assert_eq!(
+            editor.cursors,
+            vec![Coordinates { x: 0, y: 2 }, Coordinates { x: 0, y: 4 }]
+        );
+        handle_keys(&mut editor, vec![Key::Esc]);
+        assert!(editor.cursors.is_empty());
+    }
+
+    #[test]
+    fn delete_to_end_of_line_test() {
+        let mut editor = init(vec!["hello".to_string(), "".to_string()]);
+
+        let keys = vec![Key::Char('l'), Key::Char('l'), Key::Char('D')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "he");
+        assert_eq!(editor.cur_pos.x, 2);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "hello");
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text.line_at(0), "he");
+
+        let keys = vec![
+            Key::Char('0'),
+            Key::Char('l'),
+            Key::Char('C'),
+            Key::Char('i'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.mode, Mode::Insert);
+        assert_eq!(editor.text.line_at(0), "hi");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "h");
+
+        // nothing to delete on an empty line
+        handle_keys(&mut editor, vec![Key::Char('j'), Key::Char('D')]);
+        assert_eq!(editor.text.line_at(1), "");
+        handle_keys(&mut editor, vec![Key::Char('C')]);
+        assert_eq!(editor.mode, Mode::Normal);
+    }
+
+    #[test]
+    fn add_word_to_dictionary_test() {
+        let mut editor = init(vec!["the vimrs editor".to_string()]);
+        assert!(editor.spell.is_misspelled("vimrs"));
+        let keys = vec![Key::Char('w'), Key::Char('z'), Key::Char('g')];
+        handle_keys(&mut editor, keys);
+        assert!(!editor.spell.is_misspelled("vimrs"));
+        assert_eq!(editor.task.len(), 0);
+
+        let keys = ":set spelllang=xx\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert!(!editor.bar_text.line_at(0).is_empty());
+    }
+
+    #[test]
+    fn delete_lines_test() {
+        let lines: Vec<String> = (1..=5).map(|i| format!("line {i}")).collect();
+        let mut editor = init(lines.clone());
+
+        let keys = vec![
+            Key::Char('j'),
+            Key::Char('3'),
+            Key::Char('d'),
+            Key::Char('d'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text_length(), 2);
+        assert_eq!(editor.text.to_string(), "line 1\nline 5");
+        assert_eq!(editor.cur_line, 2);
+
+        // all the lines come back in one step
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), lines.join("\n"));
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text.to_string(), "line 1\nline 5");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+
+        // the count is clamped to the lines left
+        let keys = vec![
+            Key::Char('j'),
+            Key::Char('9'),
+            Key::Char('d'),
+            Key::Char('d'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.to_string(), "line 1\nline 2");
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), lines.join("\n"));
+
+        let keys = vec![
+            Key::Char('k'),
+            Key::Char('c'),
+            Key::Char('c'),
+            Key::Char('x'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.mode, Mode::Insert);
+        assert_eq!(editor.text.line_at(0), "x");
+        assert_eq!(editor.text_length(), 5);
+    }
+
+    #[test]
+    fn equalprg_test() {
+        let lines = vec![
+            "fn main() {".to_string(),
+            "let a = 1;".to_string(),
+            "}".to_string(),
+        ];
+        let mut editor = init(lines.clone());
+        let gg_eq_g = vec![
+            Key::Char('g'),
+            Key::Char('g'),
+            Key::Char('='),
+            Key::Char('G'),
+        ];
+
+        // without `equalprg` lines are indented by the brackets around them
+        handle_keys(&mut editor, gg_eq_g.clone());
+        assert_eq!(editor.text.line_at(1), "    let a = 1;");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), lines.join("\n"));
+
+        let keys = ":set equalprg=tr a-z A-Z\n"
+            .chars()
+            .map(Key::Char)
+            .collect();
+        handle_keys(&mut editor, keys);
+        handle_keys(&mut editor, gg_eq_g.clone());
+        assert_eq!(editor.text.to_string(), "FN MAIN() {\nLET A = 1;\n}");
+        assert_eq!(editor.cur_line, 1);
+
+        // the whole filter is undone and redone in one step
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), lines.join("\n"));
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text.to_string(), "FN MAIN() {\nLET A = 1;\n}");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+
+        // a formatter that changes the number of lines
+        editor.equalprg = "head -n 1".to_string();
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('2'), Key::Char('='), Key::Char('=')],
+        );
+        assert_eq!(editor.text.to_string(), "fn main() {\n}");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), lines.join("\n"));
+
+        // a failing formatter leaves the buffer alone
+        editor.equalprg = "echo oops; exit 1".to_string();
+        handle_keys(&mut editor, gg_eq_g);
+        assert_eq!(editor.text.to_string(), lines.join("\n"));
+        assert!(!editor.bar_text.line_at(0).is_empty());
+    }
+
+    #[test]
+    fn visual_block_test() {
+        let lines = vec!["abcdef".to_string(), "ghijkl".to_string(), "mn".to_string()];
+        let mut editor = init(lines.clone());
+
+        // a 2x3 block from `b` to `j`
+        let keys = vec![
+            Key::Char('l'),
+            Key::Ctrl('v'),
+            Key::Char('l'),
+            Key::Char('l'),
+            Key::Char('j'),
+        ];
+        handle_keys(&mut editor, keys.clone());
+        assert!(editor.is_select_start(1, 0));
+        assert!(editor.is_select_start(3, 1));
+        assert!(!editor.is_select_start(4, 0));
+        assert!(!editor.is_select_start(0, 1));
+        editor.mode = Mode::Visual.handle(&mut editor, Key::Char('d'));
+        assert_eq!(editor.text.to_string(), "aef\ngkl\nmn");
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 2));
+        assert_eq!(editor.mode, Mode::Normal);
+
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), lines.join("\n"));
+
+        // a line shorter than the block loses what it has of it
+        let keys = vec![
+            Key::Char('l'),
+            Key::Ctrl('v'),
+            Key::Char('l'),
+            Key::Char('l'),
+            Key::Char('j'),
+            Key::Char('j'),
+            Key::Char('d'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.to_string(), "aef\ngkl\nm");
+
+        // with virtualedit=block the block may extend past the line end
+        let mut editor = init(vec!["ab".to_string(), "abcdef".to_string()]);
+        editor.virtualedit_block = true;
+        let keys = vec![
+            Key::Char('l'),
+            Key::Ctrl('v'),
+            Key::Char('l'),
+            Key::Char('l'),
+            Key::Char('j'),
+            Key::Char('d'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.to_string(), "a\naef");
+    }
+
+    #[test]
+    fn visual_yank_test() {
+        let lines = vec!["hello".to_string(), "world".to_string()];
+        let mut editor = init(lines.clone());
+
+        let keys = vec![
+            Key::Char('l'),
+            Key::Char('l'),
+            Key::Char('l'),
+            Key::Char('v'),
+            Key::Char('j'),
+            Key::Char('h'),
+            Key::Char('y'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(
+            editor.registers.unnamed(),
+            Some(&Register {
+                text: "lo\nwor".to_string(),
+                linewise: false,
+            })
+        );
+        assert_eq!(editor.text.to_string(), lines.join("\n"));
+        assert_eq!(editor.mode, Mode::Normal);
+        assert_eq!(editor.select_view, SelectView::None);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 4));
+
+        let keys = vec![Key::Char('V'), Key::Char('j'), Key::Char('y')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(
+            editor.registers.unnamed(),
+            Some(&Register {
+                text: "hello\nworld\n".to_string(),
+                linewise: true,
+            })
+        );
+    }
+
+    #[test]
+    fn swap_selection_ends_test() {
+        let mut editor = init(vec!["hello world".to_string(), "foo".to_string()]);
+
+        let keys = vec![
+            Key::Char('l'),
+            Key::Char('v'),
+            Key::Char('l'),
+            Key::Char('l'),
+            Key::Char('o'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.cur_pos.x, 2);
+        // the selection is the same, now extended from its start
+        let mut mode = Mode::Visual;
+        mode = mode.handle(&mut editor, Key::Char('h'));
+        assert_eq!(mode, Mode::Visual);
+        assert_eq!(
+            editor.select_view,
+            SelectView::CharacterView(CharacterView {
+                start: Coordinates { x: 3, y: 0 },
+                end: Coordinates { x: 0, y: 0 },
+            })
+        );
+
+        let keys = vec![Key::Char('V'), Key::Char('j'), Key::Char('o')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.cur_line, 1);
+        assert_eq!(
+            editor.select_view,
+            SelectView::LineView(LineView { start: 1, end: 0 })
+        );
+    }
+
+    #[test]
+    fn visual_shift_test() {
+        let mut editor = init(vec![
+            "a".to_string(),
+            "  b".to_string(),
+            "".to_string(),
+            "c".to_string(),
+        ]);
+        editor.tab_width = 4;
+        editor.expandtab = true;
+        handle_keys(&mut editor, "Vjj>".chars().map(Key::Char).collect());
+        assert_eq!(editor.mode, Mode::Normal);
+        assert_eq!(editor.select_view, SelectView::None);
+        assert_eq!(lines(&editor), ["    a", "      b", "", "c"]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 5));
+
+        // `gv` selects the same lines again, shifted twice by the count
+        handle_keys(&mut editor, "gv2>".chars().map(Key::Char).collect());
+        assert_eq!(
+            lines(&editor),
+            ["            a", "              b", "", "c"]
+        );
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(lines(&editor), ["    a", "      b", "", "c"]);
+        handle_keys(&mut editor, "jvjj3<".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor), ["    a", "b", "", "c"]);
+
+        editor.expandtab = false;
+        handle_keys(&mut editor, "V>".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor)[..2], ["    a", "\tb"]);
+    }
+
+    #[test]
+    fn block_insert_test() {
+        let text = ["foo", "bar", "baz", "qux"];
+        let mut editor = init(text.iter().map(|w| w.to_string()).collect());
+        let mut keys = vec![
+            Key::Ctrl('v'),
+            Key::Char('j'),
+            Key::Char('j'),
+            Key::Char('I'),
+        ];
+        keys.extend("// ".chars().map(Key::Char));
+        keys.push(Key::Esc);
+        handle_keys(&mut editor, keys);
+        assert_eq!(lines(&editor), ["// foo", "// bar", "// baz", "qux"]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 1));
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(lines(&editor), text);
+
+        let mut keys = vec![
+            Key::Char('j'),
+            Key::Char('l'),
+            Key::Ctrl('v'),
+            Key::Char('j'),
+        ];
+        keys.extend("A-".chars().map(Key::Char));
+        keys.push(Key::Esc);
+        handle_keys(&mut editor, keys);
+        assert_eq!(lines(&editor), ["foo", "ba-r", "ba-z", "qux"]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 3));
+
+        // a line break typed ends the block insert at the first line
+        let mut keys = vec![
+            Key::Char('G'),
+            Key::Ctrl('v'),
+            Key::Char('k'),
+            Key::Char('I'),
+        ];
+        keys.extend("a\nb".chars().map(Key::Char));
+        keys.push(Key::Esc);
+        handle_keys(&mut editor, keys);
+        assert_eq!(lines(&editor), ["foo", "ba-r", "a", "bba-z", "qux"]);
+
+        // `A` pads short lines, `I` leaves them alone
+        let mut editor = init(vec!["abc".to_string(), "a".to_string()]);
+        editor.virtualedit_block = true;
+        let mut keys = vec![
+            Key::Char('l'),
+            Key::Char('l'),
+            Key::Ctrl('v'),
+            Key::Char('j'),
+        ];
+        keys.extend("A!".chars().map(Key::Char));
+        keys.push(Key::Esc);
+        handle_keys(&mut editor, keys.clone());
+        assert_eq!(lines(&editor), ["abc!", "a  !"]);
+        handle_keys(&mut editor, vec![Key::Char('u'), Key::Char('0')]);
+        keys[4] = Key::Char('I');
+        handle_keys(&mut editor, keys);
+        assert_eq!(lines(&editor), ["ab!c", "a"]);
+    }
+
+    #[test]
+    fn reselect_last_visual_test() {
+        let mut editor = init(vec!["hello world".to_string(), "foo".to_string()]);
+
+        let keys = vec![Key::Char('v'), Key::Char('l'), Key::Char('l'), Key::Esc];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.select_view, SelectView::None);
+        handle_keys(&mut editor, vec![Key::Char('0'), Key::Char('j')]);
+
+        let keys = vec![Key::Char('g'), Key::Char('v')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.mode, Mode::Visual);
+        let selection = SelectView::CharacterView(CharacterView {
+            start: Coordinates { x: 0, y: 0 },
+            end: Coordinates { x: 2, y: 0 },
+        });
+        assert_eq!(editor.select_view, selection);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 3));
+
+        // deleting it leaves it past the end of the now shorter line
+        let keys = vec![
+            Key::Char('$'),
+            Key::Char('v'),
+            Key::Char('h'),
+            Key::Char('d'),
+        ];
+        handle_keys(&mut editor, vec![Key::Esc]);
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "hello wor");
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('v')]);
+        assert_eq!(
+            editor.select_view,
+            SelectView::CharacterView(CharacterView {
+                start: Coordinates { x: 8, y: 0 },
+                end: Coordinates { x: 8, y: 0 },
+            })
+        );
+    }
+
+    #[test]
+    fn scroll_position_test() {
+        let lines: Vec<String> = (1..=100).map(|i| format!("line {i}")).collect();
+        let mut editor = init(lines);
+        let height = editor.max_y() as usize;
+
+        editor.jump_to(50, 1);
+        handle_keys(&mut editor, vec![Key::Char('z'), Key::Char('z')]);
+        assert_eq!(editor.cur_line, 50);
+        let (lower, upper) = (editor.view.lower_line(), editor.view.upper_line());
+        assert_eq!(upper - lower, height);
+        assert!((49 - lower).abs_diff(upper - 50) <= 1);
+        assert_eq!(editor.cur_pos.y, 50 - lower);
+
+        handle_keys(&mut editor, vec![Key::Char('z'), Key::Char('t')]);
+        assert_eq!(editor.view.lower_line(), 49);
+        assert_eq!(editor.cur_pos.y, 1);
+
+        handle_keys(&mut editor, vec![Key::Char('z'), Key::Char('b')]);
+        assert_eq!(editor.view.upper_line(), 50);
+        assert_eq!(editor.cur_pos.y, height);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (50, 1));
+
+        // clamped at the edges of the file
+        editor.jump_to(3, 1);
+        handle_keys(&mut editor, vec![Key::Char('z'), Key::Char('b')]);
+        assert_eq!(editor.view.lower_line(), 0);
+        assert_eq!(editor.cur_pos.y, 3);
+        editor.jump_to(98, 1);
+        handle_keys(&mut editor, vec![Key::Char('z'), Key::Char('t')]);
+        assert_eq!(editor.view.upper_line(), 100);
+        assert_eq!(editor.cur_pos.y, 98 - editor.view.lower_line());
+    }
+
+    #[test]
+    fn page_scroll_test() {
+        let lines: Vec<String> = (1..=100).map(|i| format!("line {i}")).collect();
+        let mut editor = init(lines);
+        let page = editor.max_y() as usize - 1;
+
+        handle_keys(&mut editor, vec![Key::Ctrl('f')]);
+        assert_eq!(editor.view.lower_line(), page);
+        assert_eq!((editor.cur_line, editor.cur_pos.y), (page + 1, 1));
+        handle_keys(&mut editor, vec![Key::Ctrl('f')]);
+        assert_eq!(editor.view.lower_line(), 2 * page);
+        assert_eq!((editor.cur_line, editor.cur_pos.y), (2 * page + 1, 1));
+
+        handle_keys(&mut editor, vec![Key::Ctrl('b')]);
+        assert_eq!(editor.view.lower_line(), page);
+        assert_eq!(editor.view.upper_line(), 2 * page + 1);
+        assert_eq!(
+            (editor.cur_line, editor.cur_pos.y),
+            (2 * page + 1, page + 1)
+        );
+
+        // the view stops at the last line
+        let keys = vec![Key::Ctrl('f'); 5];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.view.upper_line(), 100);
+        assert_eq!(editor.cur_line, 100);
+        assert_eq!(editor.cur_pos.y, 100 - editor.view.lower_line());
+
+        // and at the first one
+        let keys = vec![Key::Ctrl('b'); 6];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.view.lower_line(), 0);
+        assert_eq!((editor.cur_line, editor.cur_pos.y), (1, 1));
+    }
+
+    #[test]
+    fn buffers_test() {
+        let first = std::env::temp_dir().join("vim_rs_buffers_test_1.txt");
+        let second = std::env::temp_dir().join("vim_rs_buffers_test_2.txt");
+        std::fs::write(&first, "first\n").unwrap();
+        std::fs::write(&second, "second\nfile\n").unwrap();
+        let (first, second) = (first.to_str().unwrap(), second.to_str().unwrap());
+        let mut editor = TextEditor::new_from_file(first);
+        handle_keys(&mut editor, vec![Key::Char('x'), Key::Char('l')]);
+        assert_eq!(editor.text.line_at(0), "irst");
+        assert_eq!(editor.cur_pos.x, 2);
+
+        let keys = format!(":e {second}\n").chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.file_name, second);
+        assert_eq!(editor.text_length(), 2);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 1));
+        handle_keys(&mut editor, vec![Key::Char('j')]);
+
+        handle_keys(&mut editor, ":ls\n".chars().map(Key::Char).collect());
+        let list = &editor.dialogs[0].contents;
+        assert_eq!(list[0], format!("  1    + \"{first}\""));
+        assert_eq!(list[1], format!("  2 %a   \"{second}\""));
+
+        // each buffer keeps its own text, cursor and undo history
+        handle_keys(&mut editor, ":bn\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.file_name, first);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 2));
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "first");
+        handle_keys(&mut editor, ":bp\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.file_name, second);
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "second");
+
+        // opening a file again switches to its buffer
+        let keys = format!(":e {first}\n").chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.file_name, first);
+        assert_eq!(editor.buffers.len(), 2);
+        exit(&mut editor);
+    }
+
+    #[test]
+    fn modified_test() {
+        let mut editor = init(vec!["abc".to_string(), "def".to_string()]);
+        handle_keys(&mut editor, vec![Key::Char('i'), Key::Esc, Key::Char('u')]);
+        assert!(!editor.modified);
+        handle_keys(&mut editor, vec![Key::Char('x')]);
+        assert!(editor.modified);
+
+        // Ex commands mark the buffer as well
+        let mut editor = init(vec!["abc".to_string(), "def".to_string()]);
+        handle_keys(&mut editor, ":1m$\n".chars().map(Key::Char).collect());
+        assert!(editor.modified);
+    }
+
+    #[test]
+    fn trim_on_write_test() {
+        let path = std::env::temp_dir().join("vim_rs_trim_on_write_test.txt");
+        std::fs::write(&path, "a  \n\tb\t\n").unwrap();
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        handle_keys(&mut editor, ":w\n".chars().map(Key::Char).collect());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a  \n\tb\t\n");
+
+        handle_keys(
+            &mut editor,
+            ":set trimonwrite\n:w\n".chars().map(Key::Char).collect(),
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\n\tb\n");
+        assert_eq!(editor.text.line_at(0), "a");
+        assert!(!editor.modified);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(undo_file_of(path.to_str().unwrap()));
+    }
+
+    #[test]
+    fn debug_log_test() {
+        let path = std::env::temp_dir().join("vim_rs_debug_log_test.log");
+        let _ = std::fs::remove_file(&path);
+        let mut editor = init(vec!["hello".to_string()]);
+        assert_eq!(editor.debug_log, None);
+        handle_keys(&mut editor, "vld".chars().map(Key::Char).collect());
+        editor.flush();
+        assert!(!path.exists());
+
+        let set = format!(":set debuglog={}\n", path.display());
+        handle_keys(&mut editor, set.chars().map(Key::Char).collect());
+        handle_keys(&mut editor, "vld".chars().map(Key::Char).collect());
+        editor.flush();
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.contains("add action delete at (1,"), "{log}");
+        assert!(log.contains("saved_pos None"), "{log}");
+
+        handle_keys(
+            &mut editor,
+            ":set nodebuglog\n".chars().map(Key::Char).collect(),
+        );
+        handle_keys(&mut editor, "vld".chars().map(Key::Char).collect());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), log);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restore_position_test() {
+        let dir = std::env::temp_dir().join("vim_rs_restore_position_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        let path = path.to_str().unwrap();
+        let lines: Vec<String> = (1..=40).map(|i| format!("line {i}")).collect();
+        std::fs::write(path, lines.join("\n")).unwrap();
+
+        let mut editor = TextEditor::new_from_file(path);
+        editor.positions = Some(dir.join("positions"));
+        handle_keys(&mut editor, ":30\nw:q\n".chars().map(Key::Char).collect());
+        editor.save_positions();
+
+        let mut editor = TextEditor::new_from_file(path);
+        editor.positions = Some(dir.join("positions"));
+        editor.restore_position();
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (30, 6));
+        assert!(editor.view.lower_line() > 0);
+
+        // forgotten once the file is shorter
+        std::fs::write(path, "line 1\n").unwrap();
+        let mut editor = TextEditor::new_from_file(path);
+        editor.positions = Some(dir.join("positions"));
+        editor.restore_position();
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn session_test() {
+        let dir = std::env::temp_dir().join("vim_rs_session_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let lines: Vec<String> = (1..=60).map(|i| format!("line {i}")).collect();
+        std::fs::write(file("a.txt"), lines.join("\n")).unwrap();
+        std::fs::write(file("b.txt"), "b\n").unwrap();
+
+        let mut editor = TextEditor::new_from_file(&file("a.txt"));
+        let keys = format!(":50\nw:e {}\n:mksession {}\n", file("b.txt"), file("s"));
+        handle_keys(&mut editor, keys.chars().map(Key::Char).collect());
+        assert_eq!(editor.bar_text.line_at(0), "");
+
+        let mut editor = TextEditor::new_from_file(&file("b.txt"));
+        let keys = format!(":source {}\n", file("s"));
+        handle_keys(&mut editor, keys.chars().map(Key::Char).collect());
+        assert_eq!(editor.buffers.len(), 2);
+        assert_eq!(editor.file_name, file("b.txt"));
+        handle_keys(&mut editor, ":bn\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.file_name, file("a.txt"));
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (50, 6));
+        assert_eq!(editor.view.lower_line(), 50 - 23);
+
+        // a missing file is opened empty
+        std::fs::remove_file(file("b.txt")).unwrap();
+        let mut editor = TextEditor::new_from_file(&file("a.txt"));
+        let keys = format!(":so {}\n", file("s"));
+        handle_keys(&mut editor, keys.chars().map(Key::Char).collect());
+        assert_eq!(editor.file_name, file("b.txt"));
+        assert_eq!(editor.text.line_at(0), "");
+        assert!(editor.bar_text.line_at(0).contains("no such file"));
+        let keys = format!(":so {}\n", file("none"));
+        handle_keys(&mut editor, keys.chars().map(Key::Char).collect());
+        assert!(editor.bar_text.line_at(0).starts_with("E484"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recent_files_test() {
+        let dir = std::env::temp_dir().join("vim_rs_recent_files_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        std::fs::write(file("a.txt"), "a\n").unwrap();
+        std::fs::write(file("b.txt"), "b\n").unwrap();
+
+        let mut editor = TextEditor::new_from_file(&file("a.txt"));
+        editor.recent = Some(dir.join("recent"));
+        handle_keys(&mut editor, ":recent\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.bar_text.line_at(0), "No recent files");
+        let keys = format!(":e {}\n:bp\n:w\n:recent\n", file("b.txt"));
+        handle_keys(&mut editor, keys.chars().map(Key::Char).collect());
+        let dialog = editor.dialogs.last().unwrap();
+        assert_eq!(
+            dialog.contents,
+            [
+                format!("  1 {}", file("a.txt")),
+                format!("  2 {}", file("b.txt"))
+            ]
+        );
+
+        handle_keys(&mut editor, ":recent 2\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.file_name, file("b.txt"));
+        handle_keys(&mut editor, ":recent 3\n".chars().map(Key::Char).collect());
+        assert!(editor.bar_text.line_at(0).starts_with("E475"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cursor_style_test() {
+        let mut editor = init(vec!["hello".to_string()]);
+        let capture = Capture::default();
+        editor.out = Box::new(capture.clone());
+        let written = || String::from_utf8(capture.0.take()).unwrap();
+
+        handle_keys(&mut editor, vec![Key::Char('i')]);
+        assert_eq!(written(), termion::cursor::BlinkingBar.to_string());
+        // a key that keeps the mode leaves the cursor alone
+        editor.mode = Mode::Insert.handle(&mut editor, Key::Char('a'));
+        assert_eq!(written(), "");
+        editor.mode = Mode::Insert.handle(&mut editor, Key::Esc);
+        assert!(written().contains(&termion::cursor::BlinkingBlock.to_string()));
+        editor.mode = Mode::Normal.handle(&mut editor, Key::Char('R'));
+        assert!(written().contains(&termion::cursor::BlinkingUnderline.to_string()));
+        assert_eq!(
+            editor.status_line().split(" -- ").next(),
+            Some("-- REPLACE")
+        );
+
+        editor.mode = Mode::Replace.handle(&mut editor, Key::Esc);
+        editor.mode = Mode::Normal.handle(&mut editor, Key::Ctrl('v'));
+        assert!(editor.status_line().starts_with("-- VISUAL BLOCK -- "));
+        editor.mode = Mode::Visual.handle(&mut editor, Key::Esc);
+        editor.mode = Mode::Normal.handle(&mut editor, Key::Char('V'));
+        assert!(editor.status_line().starts_with("-- VISUAL LINE -- "));
+    }
+
+    #[test]
+    fn multibyte_line_test() {
+        let mut editor = init(vec!["café crème".to_string(), "naïve".to_string()]);
+
+        handle_keys(&mut editor, vec![Key::Char('$')]);
+        assert_eq!((editor.cur_pos.x, editor.cur_char()), (10, 'e'));
+        handle_keys(&mut editor, vec![Key::Char('h'), Key::Char('h')]);
+        assert_eq!(editor.cur_char(), 'è');
+        handle_keys(&mut editor, vec![Key::Char('w')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 1));
+
+        handle_keys(&mut editor, vec![Key::Char('A'), Key::Char('!'), Key::Esc]);
+        assert_eq!(editor.text.line_at(1), "naïve!");
+        assert_eq!(editor.cur_char(), '!');
+        handle_keys(&mut editor, vec![Key::Char('h'), Key::Char('x')]);
+        assert_eq!(editor.text.line_at(1), "naïv!");
+    }
+
+    #[test]
+    fn wide_char_test() {
+        let mut editor = init(vec!["日本語ab".to_string(), "x😀y".to_string()]);
+
+        handle_keys(&mut editor, vec![Key::Char('l')]);
+        assert_eq!((editor.cur_pos.x, editor.cur_char()), (2, '本'));
+        assert_eq!(editor.display_width(0, editor.cur_pos.x - 1), 2);
+        handle_keys(&mut editor, vec![Key::Char('l'), Key::Char('l')]);
+        assert_eq!((editor.cur_pos.x, editor.cur_char()), (4, 'a'));
+        assert_eq!(editor.display_width(0, editor.cur_pos.x - 1), 6);
+        assert_eq!(editor.display_width(1, 2), 3);
+        assert_eq!(editor.glyph_at_display_col("日本", 2), '本');
+        assert_eq!(editor.glyph_at_display_col("日本", 3), ' ');
+
+        // the cursor goes right of the three wide chars
+        let capture = Capture::default();
+        editor.out = Box::new(capture.clone());
+        editor.update_pos();
+        let written = String::from_utf8(capture.0.take()).unwrap();
+        assert_eq!(written, termion::cursor::Goto(7, 1).to_string());
+    }
+
+    #[test]
+    fn format_test() {
+        let long = "the quick brown fox jumps over the lazy dog".to_string();
+        let mut editor = init(vec![long.clone(), "".to_string(), "  a b".to_string()]);
+        editor.set_option("textwidth=16").unwrap();
+
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('g'), Key::Char('q'), Key::Char('q')],
+        );
+        assert_eq!(
+            editor.text.to_string(),
+            "the quick brown\nfox jumps over\nthe lazy dog\n\n  a b"
+        );
+        assert!((0..3).all(|i| editor.text.line_at(i).len() <= 16));
+        assert_eq!(editor.cur_line, 3);
+        assert!(editor.task.to_string().is_empty());
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), long);
+        assert_eq!(editor.text_length(), 3);
+
+        // the paragraph with the blank line after it, the indent is kept
+        editor.set_option("tw=3").unwrap();
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('G'),
+                Key::Char('g'),
+                Key::Char('q'),
+                Key::Char('a'),
+                Key::Char('p'),
+            ],
+        );
+        assert_eq!(editor.text.to_string(), format!("{long}\n\n  a\n  b"));
+        assert!(editor.registers.recorded('a').is_none());
+
+        editor.set_option("tw=20").unwrap();
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('g'),
+                Key::Char('g'),
+                Key::Char('V'),
+                Key::Char('g'),
+                Key::Char('q'),
+            ],
+        );
+        assert_eq!(editor.text.line_at(0), "the quick brown fox");
+        assert_eq!(editor.mode, Mode::Normal);
+
+        // line motions format the lines they go over
+        let mut editor = init(vec![
+            "a".to_string(),
+            "b".to_string(),
+            "c".to_string(),
+            "d".to_string(),
+        ]);
+        handle_keys(&mut editor, "gqj".chars().map(Key::Char).collect());
+        assert_eq!(editor.text.to_string(), "a b\nc\nd");
+        handle_keys(&mut editor, "jgqk".chars().map(Key::Char).collect());
+        assert_eq!(editor.text.to_string(), "a b c\nd");
+        handle_keys(&mut editor, "gggqG".chars().map(Key::Char).collect());
+        assert_eq!(editor.text.to_string(), "a b c d");
+        handle_keys(&mut editor, "gqw".chars().map(Key::Char).collect());
+        assert!(editor.bar_text.line_at(0).starts_with("gq: only"));
+        assert!(editor.task.to_string().is_empty());
+    }
+
+    #[test]
+    fn auto_wrap_test() {
+        let mut editor = init(vec!["  ".to_string()]);
+        editor.set_option("textwidth=20").unwrap();
+        let typed = "one two three four five six seven";
+        let mut keys = vec![Key::Char('A')];
+        keys.extend(typed.chars().map(Key::Char));
+        keys.push(Key::Esc);
+        handle_keys(&mut editor, keys);
+        assert_eq!(
+            editor.text.to_string(),
+            "  one two three four\n  five six seven"
+        );
+        assert_eq!((editor.cur_line, editor.cur_char()), (2, 'n'));
+
+        // undone in steps, the break being one of them
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), "  one two three four\n  f");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), "  one two three four f");
+
+        // a word longer than the width is not broken
+        let mut editor = init(vec!["".to_string()]);
+        editor.set_option("tw=5").unwrap();
+        let mut keys = vec![Key::Char('i')];
+        keys.extend("abcdefgh".chars().map(Key::Char));
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.to_string(), "abcdefgh");
+    }
+
+    #[test]
+    fn visual_case_test() {
+        let mut editor = init(vec!["hello world".to_string(), "Foo bar".to_string()]);
+
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('w'),
+                Key::Char('v'),
+                Key::Char('l'),
+                Key::Char('l'),
+                Key::Char('l'),
+                Key::Char('l'),
+                Key::Char('U'),
+            ],
+        );
+        assert_eq!(editor.text.line_at(0), "hello WORLD");
+        assert_eq!((editor.mode, editor.cur_pos.x), (Mode::Normal, 7));
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "hello world");
+
+        // a span over two lines, from the start of the first one
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('v'),
+                Key::Char('j'),
+                Key::Char('h'),
+                Key::Char('~'),
+            ],
+        );
+        assert_eq!(editor.text.to_string(), "HELLO WORLD\nfoo bar");
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('V'), Key::Char('j'), Key::Char('u')],
+        );
+        assert_eq!(editor.text.to_string(), "hello world\nfoo bar");
+
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('g'),
+                Key::Char('g'),
+                Key::Ctrl('v'),
+                Key::Char('j'),
+                Key::Char('l'),
+                Key::Char('r'),
+                Key::Char('x'),
+            ],
+        );
+        assert_eq!(editor.text.to_string(), "xxllo world\nxxo bar");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), "hello world\nfoo bar");
+
+        // no line breaks are put into a line
+        let keys = vec![
+            Key::Char('v'),
+            Key::Char('l'),
+            Key::Char('r'),
+            Key::Char('\n'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.to_string(), "hello world\nfoo bar");
+        assert_eq!(editor.text_length(), 2);
+    }
+
+    #[test]
+    fn select_all_test() {
+        let lines: Vec<String> = (1..=4).map(|i| format!("line {i}")).collect();
+        let mut editor = init(lines.clone());
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('j'),
+                Key::Char('g'),
+                Key::Char('g'),
+                Key::Char('V'),
+                Key::Char('G'),
+                Key::Char('d'),
+            ],
+        );
+        assert_eq!(editor.text.to_string(), "");
+        assert_eq!(editor.mode, Mode::Normal);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), lines.join("\n"));
+
+        // the view follows the selection to the last line
+        let lines: Vec<String> = (1..=50).map(|i| format!("line {i}")).collect();
+        let mut editor = init(lines.clone());
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('g'),
+                Key::Char('g'),
+                Key::Char('V'),
+                Key::Char('G'),
+            ],
+        );
+        assert_eq!(editor.cur_line, 50);
+        assert_eq!(editor.view.upper_line(), 50);
+        assert!((0..50).all(|line| editor.is_select_start(0, line)));
+        editor.mode = Mode::Visual.handle(&mut editor, Key::Char('y'));
+        assert_eq!(
+            editor.registers.unnamed().unwrap().text,
+            lines.join("\n") + "\n"
+        );
+    }
+
+    #[test]
+    fn numbered_registers_test() {
+        let lines = ["one", "two", "three", "four"].map(str::to_string).to_vec();
+        let mut editor = init(lines);
+        let dd = vec![Key::Char('d'), Key::Char('d')];
+        for _ in 0..3 {
+            handle_keys(&mut editor, dd.clone());
+        }
+        let register = |editor: &TextEditor, name| editor.registers.get(name).unwrap().text.clone();
+        assert_eq!(register(&editor, '1'), "three\n");
+        assert_eq!(register(&editor, '2'), "two\n");
+        assert_eq!(register(&editor, '3'), "one\n");
+        assert!(editor.registers.get('4').is_none());
+
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('"'), Key::Char('2'), Key::Char('p')],
+        );
+        assert_eq!(editor.text.to_string(), "four\ntwo");
+        assert!(editor.task.to_string().is_empty());
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('"'), Key::Char('5'), Key::Char('p')],
+        );
+        assert_eq!(editor.text.to_string(), "four\ntwo");
+        // a `"` not followed by `p` is dropped
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('"'),
+                Key::Char('3'),
+                Key::Char('x'),
+                Key::Char('p'),
+            ],
+        );
+        assert_eq!(editor.text.to_string(), "four\nwto");
+
+        // visual deletes over lines shift the registers too
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('g'),
+                Key::Char('g'),
+                Key::Char('V'),
+                Key::Char('d'),
+            ],
+        );
+        assert_eq!(register(&editor, '1'), "four\n");
+        assert_eq!(register(&editor, '2'), "three\n");
+    }
+
+    #[test]
+    fn inner_word_test() {
+        let mut editor = init(vec!["foo::bar   baz".to_string()]);
+        let diw = vec![Key::Char('d'), Key::Char('i'), Key::Char('w')];
+
+        let mut keys = vec![Key::Char('3'), Key::Char('l')];
+        keys.extend(diw.clone());
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "foobar   baz");
+        assert_eq!((editor.cur_pos.x, editor.cur_char()), (4, 'b'));
+        assert_eq!(editor.registers.unnamed().unwrap().text, "::");
+
+        // a run of blanks
+        let mut keys = vec![Key::Char('4'), Key::Char('l')];
+        keys.extend(diw.clone());
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "foobarbaz");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "foobar   baz");
+
+        // the last word, the cursor stays on the line
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('$'),
+                Key::Char('c'),
+                Key::Char('i'),
+                Key::Char('w'),
+            ],
+        );
+        assert_eq!(editor.mode, Mode::Insert);
+        editor.mode = Mode::Insert.handle(&mut editor, Key::Char('x'));
+        assert_eq!(editor.text.line_at(0), "foobar   x");
+        handle_keys(&mut editor, vec![Key::Char('0')]);
+        handle_keys(&mut editor, diw);
+        assert_eq!(
+            (editor.text.line_at(0), editor.cur_pos.x),
+            ("   x".to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn repeat_change_test() {
+        let mut editor = init(vec!["one two three four".to_string(), "  five".to_string()]);
+
+        handle_keys(&mut editor, vec![Key::Char('d'), Key::Char('w')]);
+        assert_eq!(editor.text.line_at(0), "two three four");
+        handle_keys(&mut editor, vec![Key::Char('w'), Key::Char('.')]);
+        assert_eq!(editor.text.line_at(0), "two four");
+        // the last word goes up to the end of the line
+        handle_keys(&mut editor, vec![Key::Char('.')]);
+        assert_eq!(editor.text.line_at(0), "two ");
+        handle_keys(&mut editor, vec![Key::Char('u'), Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "two three four");
+
+        let mut keys = vec![
+            Key::Char('0'),
+            Key::Char('c'),
+            Key::Char('i'),
+            Key::Char('w'),
+        ];
+        keys.extend("six".chars().map(Key::Char));
+        keys.extend([Key::Esc, Key::Char('w'), Key::Char('.')]);
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "six six four");
+        assert_eq!(editor.cur_char(), 'x');
+        // the delete and the insert are undone one by one, as after `ciw`
+        handle_keys(&mut editor, vec![Key::Char('u'), Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "six three four");
+
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('>'),
+                Key::Char('>'),
+                Key::Char('j'),
+                Key::Char('.'),
+            ],
+        );
+        assert_eq!(editor.text.to_string(), "    six three four\n      five");
+
+        // any other change is repeated as before
+        handle_keys(&mut editor, vec![Key::Char('x'), Key::Char('.')]);
+        assert_eq!(editor.text.line_at(1), "      ve");
+    }
+
+    #[test]
+    fn fold_test() {
+        let mut editor = init(vec![
+            "one".to_string(),
+            "two".to_string(),
+            "three".to_string(),
+            "four".to_string(),
+            "five".to_string(),
+        ]);
+        handle_keys(&mut editor, "jzf2j".chars().map(Key::Char).collect());
+        assert_eq!(editor.folds, vec![(1, 3, true)]);
+        assert_eq!(editor.cur_line, 2);
+
+        let capture = Capture::default();
+        editor.out = Box::new(capture.clone());
+        editor.print_text();
+        let screen = String::from_utf8(capture.0.borrow().clone()).unwrap();
+        assert_eq!(screen.matches("\r\n").count(), 3);
+        assert!(screen.contains("+--  3 lines: two"));
+        assert!(!screen.contains("three"));
+
+        // the closed fold is a single line for `j` and `k`
+        handle_keys(&mut editor, "j".chars().map(Key::Char).collect());
+        assert_eq!(editor.cur_line, 5);
+        handle_keys(&mut editor, "k".chars().map(Key::Char).collect());
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, "k".chars().map(Key::Char).collect());
+        assert_eq!(editor.cur_line, 1);
+
+        handle_keys(&mut editor, "jza".chars().map(Key::Char).collect());
+        assert_eq!(editor.folds, vec![(1, 3, false)]);
+        handle_keys(&mut editor, "j".chars().map(Key::Char).collect());
+        assert_eq!(editor.cur_line, 3);
+        handle_keys(&mut editor, "za".chars().map(Key::Char).collect());
+        assert_eq!(editor.folds, vec![(1, 3, true)]);
+        assert_eq!(editor.cur_line, 2);
+
+        let mut editor = init(vec![
+            "one".to_string(),
+            "two".to_string(),
+            "three".to_string(),
+        ]);
+        handle_keys(&mut editor, "Vj".chars().map(Key::Char).collect());
+        editor.mode = Mode::Visual.handle(&mut editor, Key::Char('z'));
+        editor.mode = Mode::Visual.handle(&mut editor, Key::Char('f'));
+        assert_eq!(editor.folds, vec![(0, 1, true)]);
+        assert_eq!(editor.mode, Mode::Normal);
+
+        // folds stay on their lines as lines come and go above them
+        let lines: Vec<String> = ["a", "b", "c", "d", "e"].map(String::from).to_vec();
+        let mut editor = init(lines);
+        handle_keys(&mut editor, "jzf2jggdd".chars().map(Key::Char).collect());
+        assert_eq!(editor.folds, vec![(0, 2, true)]);
+        handle_keys(&mut editor, "Ox".chars().map(Key::Char).collect());
+        assert_eq!(editor.folds, vec![(1, 3, true)]);
+        // `dd` deletes the whole closed fold
+        handle_keys(
+            &mut editor,
+            vec![Key::Esc, Key::Char('j'), Key::Char('d'), Key::Char('d')],
+        );
+        assert_eq!(editor.text.to_string(), "x\ne");
+        assert_eq!(editor.folds, vec![]);
+
+        handle_keys(&mut editor, "zfw".chars().map(Key::Char).collect());
+        assert_eq!(editor.folds, vec![]);
+        assert_eq!(editor.task.len(), 0);
+        assert_eq!(editor.cur_pos.x, 1);
+        assert!(editor.bar_text.line_at(0).starts_with("zf:"));
+
+        let mut editor = init(vec![
+            "fn main() {".to_string(),
+            "    one();".to_string(),
+            "    two();".to_string(),
+            "}".to_string(),
+        ]);
+        editor.set_option("foldmethod=indent").unwrap();
+        assert_eq!(editor.folds, vec![(1, 2, true)]);
+        handle_keys(&mut editor, "zR".chars().map(Key::Char).collect());
+        assert_eq!(editor.folds, vec![(1, 2, false)]);
+        handle_keys(&mut editor, "jjzM".chars().map(Key::Char).collect());
+        assert_eq!(editor.folds, vec![(1, 2, true)]);
+        assert_eq!(editor.cur_line, 2);
+        // the folds follow the text, keeping their state
+        editor.text.add_line_before(3, "    three();".to_string());
+        editor.print_text();
+        assert_eq!(editor.folds, vec![(1, 3, true)]);
+    }
+
+    #[test]
+    fn scroll_lines_test() {
+        let lines: Vec<String> = (1..=100).map(|i| format!("line {i}")).collect();
+        let mut editor = init(lines);
+        let height = editor.max_y() as usize;
+        handle_keys(&mut editor, "5j".chars().map(Key::Char).collect());
+
+        handle_keys(&mut editor, vec![Key::Ctrl('e'); 3]);
+        assert_eq!(editor.view.lower_line(), 3);
+        assert_eq!((editor.cur_line, editor.cur_pos.y), (6, 3));
+        // the cursor is pushed down with the top of the view
+        handle_keys(&mut editor, vec![Key::Char('4'), Key::Ctrl('e')]);
+        assert_eq!(editor.view.lower_line(), 7);
+        assert_eq!((editor.cur_line, editor.cur_pos.y), (8, 1));
+
+        handle_keys(&mut editor, vec![Key::Ctrl('y'); 2]);
+        assert_eq!(editor.view.lower_line(), 5);
+        assert_eq!((editor.cur_line, editor.cur_pos.y), (8, 3));
+        // and up with the bottom
+        handle_keys(&mut editor, vec![Key::Char('G'), Key::Ctrl('y')]);
+        assert_eq!(editor.view.lower_line(), 100 - height - 1);
+        assert_eq!(editor.cur_line, 99);
+
+        editor.set_option("scrolloff=2").unwrap();
+        handle_keys(&mut editor, "gg".chars().map(Key::Char).collect());
+        handle_keys(&mut editor, vec![Key::Ctrl('e')]);
+        assert_eq!(editor.view.lower_line(), 1);
+        assert_eq!(editor.cur_line, 4);
+        editor.flush();
+        assert_eq!(editor.view.lower_line(), 1);
+    }
+
+    #[test]
+    fn first_char_of_line_motions_test() {
+        let lines: Vec<String> = (0..40)
+            .map(|i| format!("{}x{i}", " ".repeat(i % 4)))
+            .collect();
+        let mut editor = init(lines);
+
+        handle_keys(&mut editor, vec![Key::Char('+')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 2));
+        handle_keys(&mut editor, vec![Key::Char('\n')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (3, 3));
+        handle_keys(&mut editor, vec![Key::Char('-')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 2));
+
+        // the view scrolls with the cursor
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('3'), Key::Char('0'), Key::Char('+')],
+        );
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (32, 4));
+        assert_eq!(editor.cur_line - editor.view.lower_line(), editor.cur_pos.y);
+        assert!(editor.cur_pos.y <= editor.max_y() as usize);
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('9'), Key::Char('9'), Key::Char('-')],
+        );
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 1));
+    }
+
+    #[test]
+    fn line_end_motions_test() {
+        let mut editor = init(vec![
+            "    indented  ".to_string(),
+            "trailing spaces   ".to_string(),
+            "\tlast".to_string(),
+        ]);
+        handle_keys(&mut editor, vec![Key::Char('$'), Key::Char('^')]);
+        assert_eq!(editor.cur_pos.x, 5);
+        assert_eq!(editor.cur_char(), 'i');
+
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('j'), Key::Char('g'), Key::Char('_')],
+        );
+        assert_eq!(editor.cur_pos.x, 15);
+        assert_eq!(editor.cur_char(), 's');
+        handle_keys(&mut editor, vec![Key::Char('$')]);
+        assert_eq!(editor.cur_pos.x, 18);
+
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('k'), Key::Char('3'), Key::Char('$')],
+        );
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (3, 5));
+        handle_keys(
+            &mut editor,
+            vec![
+                Key::Char('g'),
+                Key::Char('g'),
+                Key::Char('2'),
+                Key::Char('g'),
+                Key::Char('_'),
+            ],
+        );
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 15));
+    }
+
+    #[test]
+    fn sentence_motion_test() {
+        let mut editor = init(vec![
+            "One two. Three four! Five".to_string(),
+            "six? Seven.".to_string(),
+        ]);
+        handle_keys(&mut editor, vec![Key::Char(')')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 10));
+        handle_keys(&mut editor, vec![Key::Char('2'), Key::Char(')')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 6));
+        handle_keys(&mut editor, vec![Key::Char(')')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 11));
+        handle_keys(&mut editor, vec![Key::Char('(')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 6));
+        handle_keys(&mut editor, vec![Key::Char('('), Key::Char('(')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 10));
+
+        handle_keys(&mut editor, vec![Key::Char('d'), Key::Char(')')]);
+        assert_eq!(editor.text.line_at(0), "One two. Five");
+        assert_eq!(editor.registers.get('"').unwrap().text, "Three four! ");
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('$'), Key::Char('d'), Key::Char('(')],
+        );
+        assert_eq!(editor.text.line_at(0), "One two. e");
+        handle_keys(&mut editor, vec![Key::Char('u'), Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "One two. Three four! Five");
+
+        // a register named `d` is no delete
+        let mut editor = init(vec!["Hello there. Bye now.".to_string()]);
+        handle_keys(&mut editor, "\"d)".chars().map(Key::Char).collect());
+        assert_eq!(editor.text.line_at(0), "Hello there. Bye now.");
+        assert_eq!(editor.cur_pos.x, 14);
+    }
+
+    #[test]
+    fn write_error_test() {
+        let path = std::env::temp_dir().join("vim_rs_missing_dir/write_error_test.txt");
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        handle_keys(&mut editor, vec![Key::Char('i'), Key::Char('a'), Key::Esc]);
+        handle_keys(&mut editor, ":w\n".chars().map(Key::Char).collect());
+        let message = editor.bar_text.line_at(0);
+        assert!(
+            message.starts_with("E212: Can't open file for writing"),
+            "{message}"
+        );
+        assert!(editor.modified);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn read_error_test() {
+        let path = std::env::temp_dir().join("vim_rs_read_error_test.txt");
+        std::fs::write(&path, b"caf\xe9\n").unwrap();
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        assert!(editor.readonly);
+        handle_keys(&mut editor, ":w\n".chars().map(Key::Char).collect());
+        assert!(editor.bar_text.line_at(0).starts_with("E45"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"caf\xe9\n");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn write_command_test() {
+        let path = std::env::temp_dir().join("vim_rs_write_command_test.txt");
+        std::fs::write(&path, "hello\n").unwrap();
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        handle_keys(&mut editor, vec![Key::Char('x')]);
+
+        // a canceled command changes nothing
+        editor.mode = Mode::Normal;
+        let mut mode = Mode::Normal;
+        for key in [Key::Char(':'), Key::Char('w'), Key::Backspace, Key::Esc] {
+            mode = mode.handle(&mut editor, key);
+            editor.mode = mode;
+        }
+        assert_eq!(mode, Mode::Normal);
+        assert_eq!(editor.bar_text.len(), 0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello\n");
+
+        for key in [Key::Char(':'), Key::Char('w'), Key::Char('\n')] {
+            mode = mode.handle(&mut editor, key);
+            editor.mode = mode;
+        }
+        assert_eq!(mode, Mode::Normal);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "ello\n");
+
+        // the history is kept for the next time the file is opened, unless
+        // the file is changed in between
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "hello");
+        std::fs::write(&path, "other").unwrap();
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "other");
+
+        handle_keys(
+            &mut editor,
+            ":frobnicate\n".chars().map(Key::Char).collect(),
+        );
+        assert_eq!(editor.mode, Mode::Normal);
+        assert_eq!(
+            editor.bar_text.line_at(0),
+            "E492: Not an editor command: frobnicate"
+        );
+
+        // `:w path` writes a copy, over an existing file only with `!`
+        let copy = std::env::temp_dir().join("vim_rs_write_command_copy.txt");
+        let _ = std::fs::remove_file(&copy);
+        handle_keys(&mut editor, vec![Key::Char('x')]);
+        let write = |editor: &mut TextEditor, command: &str| {
+            let keys = format!(":{command} {}\n", copy.display());
+            handle_keys(editor, keys.chars().map(Key::Char).collect());
+        };
+        write(&mut editor, "w");
+        assert_eq!(std::fs::read_to_string(&copy).unwrap(), "ther");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "other");
+        assert!(editor.modified);
+        handle_keys(&mut editor, vec![Key::Char('x')]);
+        write(&mut editor, "w");
+        assert!(editor.bar_text.line_at(0).starts_with("E13"));
+        assert_eq!(std::fs::read_to_string(&copy).unwrap(), "ther");
+        write(&mut editor, "w!");
+        assert_eq!(std::fs::read_to_string(&copy).unwrap(), "her");
+        let _ = std::fs::remove_file(&copy);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(undo_file_of(path.to_str().unwrap()));
+    }
+
+    #[test]
+    fn line_ending_test() {
+        let path = std::env::temp_dir().join("vim_rs_line_ending_test.txt");
+        std::fs::write(&path, "one\r\ntwo\r\n").unwrap();
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        assert_eq!(editor.text.line_at(0), "one");
+        handle_keys(&mut editor, ":w\n".chars().map(Key::Char).collect());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\r\ntwo\r\n");
+
+        handle_keys(
+            &mut editor,
+            ":set ff=unix\n:w\n".chars().map(Key::Char).collect(),
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+
+        // no line ending is added to a last line without one
+        std::fs::write(&path, "one\r\ntwo").unwrap();
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        handle_keys(&mut editor, ":w\n".chars().map(Key::Char).collect());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\r\ntwo");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(undo_file_of(path.to_str().unwrap()));
+    }
+
+    #[test]
+    fn final_newline_test() {
+        let path = std::env::temp_dir().join("vim_rs_final_newline_test.txt");
+        std::fs::write(&path, "one\ntwo").unwrap();
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        handle_keys(&mut editor, vec![Key::Char('x')]);
+        handle_keys(&mut editor, ":w\n".chars().map(Key::Char).collect());
+        assert_eq!(std::fs::read(&path).unwrap(), b"ne\ntwo");
+
+        handle_keys(
+            &mut editor,
+            ":set eol\n:w\n".chars().map(Key::Char).collect(),
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"ne\ntwo\n");
+        handle_keys(
+            &mut editor,
+            ":set noeol\n:w\n".chars().map(Key::Char).collect(),
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"ne\ntwo");
+        let _ = std::fs::remove_file(undo_file_of(path.to_str().unwrap()));
+
+        // an empty file gets the line ending, as a new one does
+        std::fs::write(&path, "").unwrap();
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        handle_keys(&mut editor, "ione".chars().map(Key::Char).collect());
+        handle_keys(&mut editor, ":w\n".chars().map(Key::Char).collect());
+        assert_eq!(std::fs::read(&path).unwrap(), b"one\n");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(undo_file_of(path.to_str().unwrap()));
+    }
+
+    #[test]
+    fn command_history_test() {
+        let mut editor = init(vec!["hello".to_string()]);
+        let keys = ":set wrap\n:set nowrap\n:".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        let mut mode = editor.mode;
+        let mut type_key = |editor: &mut TextEditor, key| {
+            mode = mode.handle(editor, key);
+            editor.mode = mode;
+            editor.bar_text.line_at(0)
+        };
+        assert_eq!(type_key(&mut editor, Key::Up), "set nowrap");
+        assert_eq!(type_key(&mut editor, Key::Up), "set wrap");
+        assert_eq!(type_key(&mut editor, Key::Up), "set wrap");
+        assert_eq!(type_key(&mut editor, Key::Down), "set nowrap");
+        assert_eq!(type_key(&mut editor, Key::Down), "");
+
+        // the typed text filters the history
+        type_key(&mut editor, Key::Esc);
+        for c in ":set w".chars() {
+            type_key(&mut editor, Key::Char(c));
+        }
+        assert_eq!(type_key(&mut editor, Key::Up), "set wrap");
+        assert_eq!(type_key(&mut editor, Key::Up), "set wrap");
+        assert_eq!(type_key(&mut editor, Key::Down), "set w");
+        type_key(&mut editor, Key::Up);
+        type_key(&mut editor, Key::Char('\n'));
+        assert!(editor.wrap);
+        assert_eq!(editor.history, vec!["set nowrap", "set wrap"]);
+    }
+
+    #[test]
+    fn complete_command_test() {
+        let mut editor = init(vec!["hello".to_string()]);
+        let complete = |editor: &mut TextEditor, line: &str| {
+            let mut keys: Vec<Key> = format!(":{line}").chars().map(Key::Char).collect();
+            keys.push(Key::Char('\t'));
+            handle_keys(editor, keys);
+            editor.bar_text.line_at(0)
+        };
+        assert_eq!(complete(&mut editor, "col"), "colorscheme");
+        assert_eq!(complete(&mut editor, "del"), "delete");
+        assert_eq!(complete(&mut editor, "no"), "normal");
+        assert_eq!(complete(&mut editor, "sta"), "stats");
+        assert_eq!(complete(&mut editor, "e src/ma"), "e src/main.rs");
+        assert_eq!(complete(&mut editor, "e sr"), "e src/");
+        assert_eq!(complete(&mut editor, "e src/Ma"), "e src/Ma");
+
+        // Tab again goes through the candidates
+        assert_eq!(complete(&mut editor, "e src/m"), "e src/main.rs");
+        assert_eq!(
+            editor.dialogs[0].contents,
+            vec!["src/main.rs", "src/mode.rs"]
+        );
+        let mut mode = editor.mode;
+        for expected in ["e src/mode.rs", "e src/main.rs"] {
+            mode = mode.handle(&mut editor, Key::Char('\t'));
+            editor.mode = mode;
+            assert_eq!(editor.bar_text.line_at(0), expected);
+        }
+    }
+
+    #[test]
+    fn resize_test() {
+        let lines: Vec<String> = (1..=100).map(|i| format!("line {i}")).collect();
+        let mut editor = init(lines);
+        editor.jump_to(20, 1);
+        assert_eq!(
+            (editor.view.lower_line(), editor.view.upper_line()),
+            (0, 23)
+        );
+
+        // the cursor line is kept on screen
+        editor.resize(80, 11);
+        assert_eq!(editor.max_y(), 10);
+        assert_eq!(
+            (editor.view.lower_line(), editor.view.upper_line()),
+            (10, 20)
+        );
+        assert_eq!(editor.cur_pos.y, 10);
+
+        editor.resize(100, 41);
+        assert_eq!(
+            (editor.view.lower_line(), editor.view.upper_line()),
+            (10, 50)
+        );
+        assert_eq!(editor.cur_pos.y, 10);
+
+        // the command line moves to the new last row
+        handle_keys(&mut editor, vec![Key::Char(':')]);
+        editor.flush();
+        assert_eq!(editor.cur_pos.y, 41);
+    }
+
+    #[test]
+    fn delete_word_in_insert_test() {
+        let mut editor = init(vec!["".to_string(), "  foo.bar".to_string()]);
+        let mut keys: Vec<Key> = "ihello world".chars().map(Key::Char).collect();
+        keys.push(Key::Ctrl('w'));
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "hello ");
+        assert_eq!(editor.cur_pos.x, 7);
+
+        // the insertion is undone as a whole
+        handle_keys(&mut editor, vec![Key::Esc, Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "");
+
+        // words end at punctuation, and the line start joins lines
+        let keys = vec![Key::Char('j'), Key::Char('A'), Key::Ctrl('w')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(1), "  foo.");
+        for (expected, lines) in [("  foo", 2), ("  ", 2), ("", 2), ("", 1)] {
+            handle_keys(&mut editor, vec![Key::Char('A'), Key::Ctrl('w')]);
+            assert_eq!(editor.text.line_at(editor.cur_line - 1), expected);
+            assert_eq!(editor.text_length(), lines);
+        }
+    }
+
+    #[test]
+    fn delete_to_line_start_in_insert_test() {
+        let mut editor = init(vec!["    let a".to_string()]);
+        let mut keys: Vec<Key> = "A = 1;".chars().map(Key::Char).collect();
+        keys.push(Key::Ctrl('u'));
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "    ");
+        assert_eq!(editor.cur_pos.x, 5);
+
+        handle_keys(&mut editor, vec![Key::Char('A'), Key::Ctrl('u')]);
+        assert_eq!(editor.text.line_at(0), "");
+        assert_eq!(editor.cur_pos.x, 1);
+    }
+
+    #[test]
+    fn keyword_completion_test() {
+        let mut editor = init(vec!["foobar food, fo".to_string(), "".to_string()]);
+        let mut keys: Vec<Key> = "jifoo".chars().map(Key::Char).collect();
+        keys.push(Key::Ctrl('n'));
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(1), "foobar");
+
+        // repeating goes through the other matching words
+        let mut mode = Mode::Insert;
+        for (key, expected) in [
+            (Key::Ctrl('n'), "food"),
+            (Key::Ctrl('n'), "foobar"),
+            (Key::Ctrl('p'), "food"),
+        ] {
+            mode = mode.handle(&mut editor, key);
+            editor.mode = mode;
+            assert_eq!(editor.text.line_at(1), expected);
+        }
+        assert_eq!(editor.cur_pos.x, 5);
+
+        // a new completion starts after typing
+        for key in [Key::Char(' '), Key::Char('x'), Key::Ctrl('n')] {
+            mode = mode.handle(&mut editor, key);
+            editor.mode = mode;
+        }
+        assert_eq!(editor.text.line_at(1), "food x");
+        assert!(!editor.bar_text.line_at(0).is_empty());
+
+        handle_keys(&mut editor, vec![Key::Esc, Key::Char('u')]);
+        assert_eq!(editor.text.line_at(1), "");
+    }
+
+    #[test]
+    fn macro_test() {
+        let lines: Vec<String> = (1..=6).map(|i| format!("item {i}")).collect();
+        let mut editor = init(lines);
+        // append a comma and go to the next line
+        let mut keys: Vec<Key> = "qaA,".chars().map(Key::Char).collect();
+        keys.extend([Key::Esc, Key::Char('j'), Key::Char('q')]);
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "item 1,");
+        assert!(editor.recording.is_none());
+        assert_eq!(editor.registers.recorded('a').unwrap().len(), 4);
+
+        handle_keys(&mut editor, "@a".chars().map(Key::Char).collect());
+        assert_eq!(editor.text.line_at(1), "item 2,");
+        handle_keys(&mut editor, "2@a".chars().map(Key::Char).collect());
+        assert_eq!(editor.text.line_at(3), "item 4,");
+        handle_keys(&mut editor, "@@".chars().map(Key::Char).collect());
+        assert_eq!(editor.text.line_at(4), "item 5,");
+        assert_eq!(editor.text.line_at(5), "item 6");
+        assert_eq!(editor.cur_line, 6);
+        assert_eq!(editor.mode, Mode::Normal);
+
+        // unknown registers do nothing
+        handle_keys(&mut editor, "@b".chars().map(Key::Char).collect());
+        assert_eq!(editor.text.line_at(5), "item 6");
+        assert_eq!(editor.task.len(), 0);
+    }
+
+    #[test]
+    fn add_to_number_test() {
+        let mut editor = init(vec!["x = 9".to_string(), "a-1, 20".to_string()]);
+        handle_keys(&mut editor, vec![Key::Ctrl('a')]);
+        assert_eq!(editor.text.line_at(0), "x = 10");
+        assert_eq!(editor.cur_pos.x, 6);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "x = 9");
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text.line_at(0), "x = 10");
+
+        let keys = vec![Key::Char('j'), Key::Char('5'), Key::Ctrl('a')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(1), "a4, 20");
+        assert_eq!(editor.cur_pos.x, 2);
+        let keys = vec![
+            Key::Char('l'),
+            Key::Char('2'),
+            Key::Char('5'),
+            Key::Ctrl('x'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(1), "a4, -5");
+        assert_eq!(editor.cur_pos.x, 6);
+        assert_eq!(editor.task.len(), 0);
+
+        // nothing after the cursor
+        handle_keys(
+            &mut editor,
+            vec![Key::Char('$'), Key::Char('x'), Key::Ctrl('a')],
+        );
+        assert_eq!(editor.text.line_at(1), "a4, -");
+    }
+
+    #[test]
+    fn replace_mode_test() {
+        let mut editor = init(vec!["hello".to_string()]);
+        let keys = "lRipp".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.mode, Mode::Replace);
+        assert_eq!(editor.text.line_at(0), "hippo");
+        assert_eq!(editor.cur_pos.x, 5);
+
+        // typing past the end appends, backspace restores what was there
+        let mut mode = Mode::Replace;
+        for key in "os".chars().map(Key::Char).chain([Key::Backspace; 4]) {
+            mode = mode.handle(&mut editor, key);
+            editor.mode = mode;
+        }
+        assert_eq!(editor.text.line_at(0), "hillo");
+        assert_eq!(editor.cur_pos.x, 3);
+        for key in [Key::Char('e'), Key::Esc] {
+            mode = mode.handle(&mut editor, key);
+            editor.mode = mode;
+        }
+        assert_eq!(mode, Mode::Normal);
+        assert_eq!(editor.text.line_at(0), "hielo");
+        assert_eq!(editor.cur_pos.x, 3);
+
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "hello");
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text.line_at(0), "hielo");
+    }
+
+    #[test]
+    fn jump_list_test() {
+        let lines: Vec<String> = (1..=50).map(|i| format!("line {i}")).collect();
+        let mut editor = init(lines);
+        let keys = vec![Key::Char('5'), Key::Char('j'), Key::Char('G')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.cur_line, 50);
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('g')]);
+        assert_eq!(editor.cur_line, 1);
+
+        handle_keys(&mut editor, vec![Key::Ctrl('o')]);
+        assert_eq!(editor.cur_line, 50);
+        handle_keys(&mut editor, vec![Key::Ctrl('o')]);
+        assert_eq!(editor.cur_line, 6);
+        handle_keys(&mut editor, vec![Key::Ctrl('o')]);
+        assert_eq!(editor.cur_line, 6);
+        handle_keys(&mut editor, vec![Key::Char('\t')]);
+        assert_eq!(editor.cur_line, 50);
+        handle_keys(&mut editor, vec![Key::Char('\t')]);
+        assert_eq!(editor.cur_line, 1);
+        handle_keys(&mut editor, vec![Key::Char('\t')]);
+        assert_eq!(editor.cur_line, 1);
+
+        // a new jump drops the entries after the current one
+        handle_keys(&mut editor, vec![Key::Ctrl('o'), Key::Ctrl('o')]);
+        let keys = "/line 20\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.cur_line, 20);
+        assert_eq!(editor.jumps, vec![(6, 1)]);
+        handle_keys(&mut editor, vec![Key::Ctrl('o')]);
+        assert_eq!(editor.cur_line, 6);
+    }
+
+    #[test]
+    fn line_range_command_test() {
+        let numbered = || {
+            (1..=5)
+                .map(|i| format!("line {i}"))
+                .collect::<Vec<String>>()
+        };
+        let run = |editor: &mut TextEditor, command: &str| {
+            let keys = format!(":{command}\n").chars().map(Key::Char).collect();
+            handle_keys(editor, keys);
+            lines(editor)
+        };
+        let mut editor = init(numbered());
+        // the cursor is parked on the command line while typing
+        handle_keys(&mut editor, vec![Key::Char('j'), Key::Char(':')]);
+        editor.flush();
+        assert_eq!(run(&mut editor, "2,3d"), ["line 1", "line 4", "line 5"]);
+        assert_eq!((editor.cur_line, editor.cur_pos.y), (2, 2));
+        assert_eq!(run(&mut editor, "1m$"), ["line 4", "line 5", "line 1"]);
+        assert_eq!(editor.cur_line, 3);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "line 1");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text_length(), 5);
+
+        let mut editor = init(numbered());
+        let expected = [
+            "line 3", "line 4", "line 1", "line 2", "line 3", "line 4", "line 5",
+        ];
+        assert_eq!(run(&mut editor, "3,4t0"), expected);
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text_length(), 5);
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text_length(), 7);
+
+        let mut editor = init(numbered());
+        let expected = ["line 1", "line 4", "line 5", "line 2", "line 3"];
+        assert_eq!(run(&mut editor, "4,$m1"), expected);
+        assert_eq!(run(&mut editor, "2,4m3"), expected);
+        assert!(editor.bar_text.line_at(0).starts_with("E134"));
+        // numbers past the end are clamped
+        assert_eq!(run(&mut editor, "9"), expected);
+        assert_eq!(editor.cur_line, 5);
+        assert_eq!(run(&mut editor, "%d"), [""]);
+
+        let mut editor = init(numbered());
+        editor.modifiable = false;
+        assert_eq!(run(&mut editor, "2,3d"), numbered());
+        assert_eq!(editor.bar_text.line_at(0), TextEditor::NOT_MODIFIABLE);
+        assert_eq!(run(&mut editor, "1m$"), numbered());
+        assert_eq!(run(&mut editor, "1t0"), numbered());
+        assert!(!editor.modified);
+    }
+
+    #[test]
+    fn normal_command_test() {
+        let lines = vec![
+            "let a = 1".to_string(),
+            "let b = 2".to_string(),
+            "".to_string(),
+        ];
+        let mut editor = init(lines);
+        let keys = ":%normal A;\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.mode, Mode::Normal);
+        assert_eq!(editor.text.line_at(0), "let a = 1;");
+        assert_eq!(editor.text.line_at(1), "let b = 2;");
+        assert_eq!(editor.text.line_at(2), ";");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(2), "");
+
+        let keys = ":1,2norm wx\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "let  = 1;");
+        assert_eq!(editor.text.line_at(1), "let  = 2;");
+        let keys = ":normal  x\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(1), "lt  = 2;");
+    }
+
+    #[test]
+    fn list_test() {
+        let mut editor = init(vec!["a b\tc  ".to_string()]);
+        let glyphs = |editor: &TextEditor| editor.glyphs_of(&editor.text.line_at(0));
+        assert_eq!(glyphs(&editor).iter().collect::<String>(), "a b c  ");
+
+        handle_keys(&mut editor, ":set list\n".chars().map(Key::Char).collect());
+        assert_eq!(glyphs(&editor).iter().collect::<String>(), "a b→c··");
+        assert_eq!(editor.text.line_at(0), "a b\tc  ");
+        handle_keys(&mut editor, "$".chars().map(Key::Char).collect());
+        assert_eq!(editor.cur_pos.x, 7);
+
+        handle_keys(
+            &mut editor,
+            ":set nolist\n".chars().map(Key::Char).collect(),
+        );
+        assert!(!editor.list);
+    }
+
+    #[test]
+    fn trailing_whitespace_test() {
+        assert_eq!(TextEditor::trailing_whitespace("let a = 1;  \t"), 10..13);
+        assert_eq!(TextEditor::trailing_whitespace("  x"), 3..3);
+        assert_eq!(TextEditor::trailing_whitespace("   "), 0..3);
+        assert_eq!(TextEditor::trailing_whitespace("é "), 1..2);
+
+        let mut editor = init(vec!["a  ".to_string()]);
+        handle_keys(&mut editor, ":set hlws\n".chars().map(Key::Char).collect());
+        assert!(editor.highlight_trailing_ws);
+        editor.flush();
+        assert_eq!(editor.text.line_at(0), "a  ");
+        handle_keys(
+            &mut editor,
+            ":set nohlws\n".chars().map(Key::Char).collect(),
+        );
+        assert!(!editor.highlight_trailing_ws);
+    }
+
+    #[test]
+    fn spell_marks_test() {
+        let mut editor = init(vec!["the wrod".to_string()]);
+        let mark = termion::color::Bg(termion::color::LightRed).to_string();
+        let screen = |editor: &mut TextEditor| {
+            let capture = Capture::default();
+            editor.out = Box::new(capture.clone());
+            editor.print_text();
+            let screen = String::from_utf8(capture.0.borrow().clone()).unwrap();
+            screen
+        };
+        assert!(!screen(&mut editor).contains(&mark));
+
+        handle_keys(&mut editor, ":set spell\n".chars().map(Key::Char).collect());
+        assert!(screen(&mut editor).contains(&format!("{mark}wrod")));
+        assert!(!screen(&mut editor).contains(&format!("{mark}the")));
+
+        // `zg` takes the mark off
+        handle_keys(&mut editor, "wzg".chars().map(Key::Char).collect());
+        assert!(!screen(&mut editor).contains(&mark));
+    }
+
+    #[test]
+    fn stats_test() {
+        let lines = vec![
+            "fn main() {".to_string(),
+            "".to_string(),
+            "    é = 1;".to_string(),
+        ];
+        let mut editor = init(lines);
+        let keys = vec![
+            Key::Char('j'),
+            Key::Char('j'),
+            Key::Char('w'),
+            Key::Char('l'),
+        ];
+        handle_keys(&mut editor, keys);
+        let stats = editor.stats();
+        assert_eq!(stats.lines, 3);
+        assert_eq!(stats.words, 6);
+        assert_eq!(stats.chars, 23);
+        assert_eq!(stats.bytes, 24);
+        assert_eq!(stats.byte, 20);
+
+        handle_keys(&mut editor, ":stats\n".chars().map(Key::Char).collect());
+        assert_eq!(
+            editor.bar_text.line_at(0),
+            "Col 6 of 10; Line 3 of 3; Words 6; Chars 23; Byte 20 of 24"
+        );
+    }
+
+    #[test]
+    fn sort_test() {
+        let words = ["banana", "apple", "cherry"];
+        let mut editor = init(words.iter().map(|w| w.to_string()).collect());
+        handle_keys(&mut editor, ":sort\n".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor), ["apple", "banana", "cherry"]);
+        handle_keys(&mut editor, ":sort!\n".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor), ["cherry", "banana", "apple"]);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(lines(&editor), ["apple", "banana", "cherry"]);
+
+        let numbers = ["10 x", "head", "9", "-3", "  2"];
+        let mut editor = init(numbers.iter().map(|w| w.to_string()).collect());
+        handle_keys(&mut editor, ":2,$sort n\n".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor), ["10 x", "-3", "head", "  2", "9"]);
+
+        let mut editor = init(words.iter().map(|w| w.to_string()).collect());
+        editor.readonly = true;
+        handle_keys(&mut editor, ":sort\n".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor), words);
+        assert_eq!(editor.bar_text.line_at(0), TextEditor::READONLY);
+    }
+
+    #[test]
+    fn substitute_test() {
+        let text = ["a a", "a", "b a"];
+        let mut editor = init(text.iter().map(|w| w.to_string()).collect());
+        handle_keys(&mut editor, ":%s/a/xy/g\n".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor), ["xy xy", "xy", "b xy"]);
+        assert_eq!(editor.cur_line, 3);
+        handle_keys(&mut editor, ":s/q/x/\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.bar_text.line_at(0), "E486: Pattern not found: q");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(lines(&editor), text);
+
+        handle_keys(&mut editor, ":%s/a/x/gc\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.bar_text.line_at(0), "replace with x (y/n/a/q/l)?");
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 1));
+        handle_keys(&mut editor, "yn".chars().map(Key::Char).collect());
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 1));
+        assert_eq!(lines(&editor), ["x a", "a", "b a"]);
+        handle_keys(&mut editor, "yq".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor), ["x a", "x", "b a"]);
+        assert!(editor.substituting.is_none());
+        assert_eq!(editor.select_view, SelectView::None);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 1));
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(lines(&editor), text);
+
+        // without `g` only the first match of each line is asked about
+        handle_keys(&mut editor, ":%s/a/x/c\n".chars().map(Key::Char).collect());
+        handle_keys(&mut editor, "na".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor), ["a a", "x", "b x"]);
+        assert!(editor.substituting.is_none());
+    }
+
+    #[test]
+    fn trim_test() {
+        let text = ["a  ", "\tb\t \t", "  ", "c", "d "];
+        let mut editor = init(text.iter().map(|w| w.to_string()).collect());
+        handle_keys(&mut editor, "j$:trim\n".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor), ["a", "\tb", "", "c", "d"]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 2));
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(lines(&editor), text);
+
+        handle_keys(&mut editor, ":2,3trim\n".chars().map(Key::Char).collect());
+        assert_eq!(lines(&editor), ["a  ", "\tb", "", "c", "d "]);
+    }
+
+    #[test]
+    fn filter_command_test() {
+        let has_tr = std::process::Command::new("sh")
+            .args(["-c", "command -v tr"])
+            .output()
+            .is_ok_and(|output| output.status.success());
+        if !has_tr {
+            return;
+        }
+        let lines = vec!["one".to_string(), "two".to_string(), "three".to_string()];
+        let mut editor = init(lines);
+        let keys = ":2,$!tr a-z A-Z\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "one");
+        assert_eq!(editor.text.line_at(1), "TWO");
+        assert_eq!(editor.text.line_at(2), "THREE");
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(2), "three");
+
+        // a failing command changes nothing
+        let keys = ":%!echo oops >&2; exit 1\n"
+            .chars()
+            .map(Key::Char)
+            .collect();
+        handle_keys(&mut editor, keys);
+        assert!(editor.bar_text.line_at(0).contains("oops"));
+        assert_eq!(editor.text.line_at(1), "two");
+
+        editor.modifiable = false;
+        let keys = ":%!tr a-z A-Z\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "one");
+        assert_eq!(editor.bar_text.line_at(0), TextEditor::NOT_MODIFIABLE);
+    }
+
+    #[test]
+    fn undo_lines_below_last_test() {
+        let keys = |keys: &str| keys.chars().map(Key::Char).collect::<Vec<Key>>();
+        let mut editor = init(vec!["a".to_string(), "b".to_string()]);
+        handle_keys(&mut editor, keys(":1t$\n"));
+        assert_eq!(lines(&editor), ["a", "b", "a"]);
+        handle_keys(&mut editor, keys("u"));
+        assert_eq!(lines(&editor), ["a", "b"]);
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(lines(&editor), ["a", "b", "a"]);
+
+        let mut editor = init(vec!["a".to_string(), "b".to_string()]);
+        handle_keys(&mut editor, keys("Vyjp"));
+        assert_eq!(lines(&editor), ["a", "b", "a"]);
+        handle_keys(&mut editor, keys("u"));
+        assert_eq!(lines(&editor), ["a", "b"]);
+
+        let mut editor = init(vec!["a".to_string()]);
+        handle_keys(&mut editor, keys("G:r !echo x\n"));
+        assert_eq!(lines(&editor), ["a", "x"]);
+        handle_keys(&mut editor, keys("u"));
+        assert_eq!(lines(&editor), ["a"]);
+
+        let mut editor = init(vec!["a".to_string(), "b".to_string()]);
+        handle_keys(&mut editor, keys("ddp"));
+        assert_eq!(lines(&editor), ["b", "a"]);
+        handle_keys(&mut editor, keys("u"));
+        assert_eq!(lines(&editor), ["b"]);
+        handle_keys(&mut editor, keys("u"));
+        assert_eq!(lines(&editor), ["a", "b"]);
+    }
+
+    #[test]
+    fn read_command_test() {
+        let path = std::env::temp_dir().join("vim_rs_read_command_test.txt");
+        std::fs::write(&path, "inserted 1\ninserted 2\n").unwrap();
+        let mut editor = init(vec!["first".to_string(), "last".to_string()]);
+        let keys = format!(":r {}\n", path.display())
+            .chars()
+            .map(Key::Char)
+            .collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(
+            lines(&editor),
+            ["first", "inserted 1", "inserted 2", "last"]
+        );
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text_length(), 2);
+        assert_eq!(editor.text.line_at(1), "last");
+
+        let keys = ":$r !echo output\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text_length(), 3);
+        assert_eq!(editor.text.line_at(2), "output");
+        assert_eq!(editor.cur_line, 3);
+
+        // nothing to insert
+        handle_keys(&mut editor, ":r !true\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.text_length(), 3);
+        let keys = ":r /no/such/file\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert!(editor.bar_text.line_at(0).starts_with("E484"));
+        let _ = std::fs::remove_file(&path);
+
+        editor.readonly = true;
+        handle_keys(
+            &mut editor,
+            ":r !echo hi\n".chars().map(Key::Char).collect(),
+        );
+        assert_eq!(editor.text_length(), 3);
+        assert_eq!(editor.bar_text.line_at(0), TextEditor::READONLY);
+    }
+
+    #[test]
+    fn redo_after_new_edit_test() {
+        let mut editor = init(vec!["abc".to_string()]);
+        handle_keys(&mut editor, vec![Key::Char('x'), Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "abc");
+        let keys = vec![Key::Char('A'), Key::Char('d'), Key::Esc];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "abcd");
+
+        // the undone `x` is gone
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text.line_at(0), "abcd");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "abc");
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text.line_at(0), "abcd");
+    }
+
+    #[test]
+    fn count_insert_test() {
+        let mut editor = init(vec!["start".to_string()]);
+        let keys = "3ofoo".chars().map(Key::Char).chain([Key::Esc]).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text_length(), 4);
+        for line in 1..4 {
+            assert_eq!(editor.text.line_at(line), "foo");
+        }
+        assert_eq!(editor.cur_line, 4);
+
+        let mut editor = init(vec!["ab".to_string()]);
+        let keys = "l2ixy".chars().map(Key::Char).chain([Key::Esc]).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "axyxyb");
+        assert_eq!(editor.cur_pos.x, 5);
+
+        // the repeated text is a single change
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "ab");
+
+        let mut editor = init(vec!["abcdef".to_string()]);
+        let keys = vec![Key::Char('x'), Key::Char('3'), Key::Char('.')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "ef");
+    }
+
+    #[test]
+    fn swap_with_put_test() {
+        let mut editor = init(vec!["ab".to_string(), "cd".to_string()]);
+        handle_keys(&mut editor, vec![Key::Char('x'), Key::Char('p')]);
+        assert_eq!(editor.text.line_at(0), "ba");
+        assert_eq!(editor.cur_pos.x, 2);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "b");
+
+        let mut editor = init(vec![
+            "one".to_string(),
+            "two".to_string(),
+            "three".to_string(),
+        ]);
+        let keys = vec![Key::Char('d'), Key::Char('d'), Key::Char('p')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.to_string(), "two\none\nthree");
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), "two\nthree");
+
+        // a yanked line goes below the line it came from
+        let keys = vec![
+            Key::Char('j'),
+            Key::Char('d'),
+            Key::Char('d'),
+            Key::Char('p'),
+        ];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.to_string(), "two\nthree");
+    }
+
+    #[test]
+    fn join_lines_test() {
+        let mut editor = init(vec!["foo   ".to_string(), "   bar".to_string()]);
+        handle_keys(&mut editor, vec![Key::Char('J')]);
+        assert_eq!(editor.text.to_string(), "foo bar");
+        assert_eq!(editor.cur_pos.x, 4);
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.to_string(), "foo   \n   bar");
+
+        let mut editor = init(vec!["foo   ".to_string(), "bar".to_string()]);
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('J')]);
+        assert_eq!(editor.text.to_string(), "foo   bar");
+        assert_eq!(editor.cur_pos.x, 7);
+
+        // an empty line adds no space of its own
+        let lines = vec!["a", "", "  b", "c"];
+        let mut editor = init(lines.into_iter().map(str::to_string).collect());
+        let keys = vec![Key::Char('3'), Key::Char('J')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.to_string(), "a b\nc");
+        assert_eq!(editor.cur_pos.x, 2);
+    }
+
+    #[test]
+    fn status_line_test() {
+        let lines: Vec<String> = (1..=4).map(|i| format!("line {i}")).collect();
+        let mut editor = init(lines);
+        editor.file_name = "main.rs".to_string();
+        editor.highlighter = crate::HighLighter::new("main.rs");
+        let status = editor.status_line();
+        assert!(status.starts_with("main.rs [rust] "));
+        assert!(status.ends_with(" 1:1   25%"));
+        assert_eq!(status.chars().count(), 80);
+
+        let keys = vec![Key::Char('j'), Key::Char('l'), Key::Char('x')];
+        handle_keys(&mut editor, keys);
+        let status = editor.status_line();
+        assert!(status.starts_with("main.rs [+] [rust] "));
+        assert!(status.ends_with(" 2:2   50%"));
+
+        // the keys of a pending command
+        editor.set_option("showcmd").unwrap();
+        handle_keys(&mut editor, vec![Key::Char('2'), Key::Char('d')]);
+        assert!(editor.status_line().ends_with(" 2d   2:2   50%"));
+    }
+
+    #[test]
+    fn line_number_test() {
+        let lines: Vec<String> = (1..=40).map(|i| format!("line {i}")).collect();
+        let mut editor = init(lines);
+        assert_eq!(editor.number_width(), 0);
+        handle_keys(
+            &mut editor,
+            ":set number\n".chars().map(Key::Char).collect(),
+        );
+        assert!(editor.show_line_numbers);
+        assert_eq!(editor.number_width(), 4);
+        assert_eq!(editor.text_width(), 76);
+        assert_eq!(editor.line_number(4), "  5 ");
+
+        handle_keys(&mut editor, ":set rnu\njj".chars().map(Key::Char).collect());
+        assert_eq!(editor.line_number(0), "  2 ");
+        assert_eq!(editor.line_number(2), "  3 ");
+        handle_keys(&mut editor, ":set nonu\n".chars().map(Key::Char).collect());
+        assert!(!editor.show_line_numbers);
+        assert_eq!(editor.line_number(2), "  0 ");
+
+        let keys = ":set so=5\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.scrolloff, 5);
+        editor.jump_to(20, 1);
+        editor.set_view_top(0);
+        editor.flush();
+        assert_eq!(editor.view.upper_line(), 25);
+        assert_eq!(editor.cur_pos.y, 18);
+        editor.set_view_top(19);
+        editor.flush();
+        assert_eq!(editor.view.lower_line(), 14);
+        assert_eq!(editor.cur_pos.y, 6);
+
+        let keys = ":set so=x\n".chars().map(Key::Char).collect();
+        handle_keys(&mut editor, keys);
+        assert!(editor.bar_text.line_at(0).starts_with("E521"));
+    }
+
+    #[test]
+    fn color_column_test() {
+        let mut editor = init(vec!["\tab".to_string(), "x".repeat(100)]);
+        assert_eq!(editor.color_column_x(), None);
+        handle_keys(&mut editor, ":set cc=10\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.color_column_x(), Some(10));
+        handle_keys(&mut editor, ":set nu\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.color_column_x(), Some(14));
+        handle_keys(&mut editor, ":set cc=80\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.color_column_x(), None);
+
+        // scrolled to the right along with the text
+        editor.view.left_col = 10;
+        assert_eq!(editor.color_column_x(), Some(74));
+        editor.view.left_col = 80;
+        assert_eq!(editor.color_column_x(), None);
+
+        // a tab takes the display columns up to the next tab stop
+        editor.tab_width = 4;
+        assert_eq!(editor.glyph_at_display_col("\tab", 2), ' ');
+        assert_eq!(editor.glyph_at_display_col("\tab", 5), 'b');
+        assert_eq!(editor.glyph_at_display_col("\tab", 6), ' ');
+
+        handle_keys(&mut editor, ":set cc=x\n".chars().map(Key::Char).collect());
+        assert!(editor.bar_text.line_at(0).starts_with("E521"));
+        assert_eq!(editor.colorcolumn, 80);
+    }
+
+    #[test]
+    fn insert_literal_test() {
+        let mut editor = init(vec!["".to_string()]);
+        editor.expandtab = true;
+        let mut keys = vec![Key::Char('i'), Key::Ctrl('v'), Key::Char('\t')];
+        keys.extend([Key::Ctrl('v'), Key::Esc, Key::Ctrl('v'), Key::Char('(')]);
+        keys.extend([
+            Key::Ctrl('v'),
+            Key::Char('u'),
+            Key::Char('7'),
+            Key::Char('e'),
+        ]);
+        keys.extend([Key::Char('x'), Key::Ctrl('v')]);
+        keys.extend("065".chars().map(Key::Char));
+        keys.extend([Key::Ctrl('v'), Key::Char('u')]);
+        keys.extend("005A".chars().map(Key::Char));
+        keys.extend([Key::Ctrl('v'), Key::Char('7'), Key::Esc]);
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.mode, Mode::Normal);
+        assert_eq!(editor.text.line_at(0), "\t\x1b(~xAZ\x07");
+
+        // undone and redone as any other text
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "");
+        handle_keys(&mut editor, vec![Key::Ctrl('r')]);
+        assert_eq!(editor.text.line_at(0), "\t\x1b(~xAZ\x07");
+        assert_eq!(editor.glyphs_of("\x1b\x07")[..], ['␛', '␇']);
+    }
+
+    #[test]
+    fn ignorecase_search_test() {
+        let lines = vec![
+            "hello".to_string(),
+            "Hello".to_string(),
+            "HELLO".to_string(),
+        ];
+        let mut editor = init(lines);
+        handle_keys(&mut editor, "/hello\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.cur_line, 1);
+        assert!(editor.bar_text.line_at(0).is_empty());
+
+        handle_keys(&mut editor, ":set ic\nn".chars().map(Key::Char).collect());
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, vec![Key::Char('n')]);
+        assert_eq!(editor.cur_line, 3);
+
+        // an upper case letter makes the search match case again
+        handle_keys(&mut editor, ":set scs\ngg".chars().map(Key::Char).collect());
+        handle_keys(&mut editor, "/Hello\n".chars().map(Key::Char).collect());
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, vec![Key::Char('n')]);
+        assert_eq!(editor.cur_line, 2);
+        handle_keys(&mut editor, "/hello\nn".chars().map(Key::Char).collect());
+        assert_eq!(editor.cur_line, 1);
+    }
+
+    #[test]
+    fn search_word_under_cursor_test() {
+        let lines = vec![
+            "foo bar".to_string(),
+            "foobar foo".to_string(),
+            "a foo".to_string(),
+        ];
+        let mut editor = init(lines);
+        handle_keys(&mut editor, vec![Key::Char('l'), Key::Char('*')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 8));
+        assert_eq!(editor.last_search.as_deref(), Some("\\<foo\\>"));
+        handle_keys(&mut editor, vec![Key::Char('n')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (3, 3));
+        handle_keys(&mut editor, vec![Key::Char('n')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 1));
+
+        // `n` goes on in the direction of `#`
+        handle_keys(&mut editor, vec![Key::Char('#')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (3, 3));
+        handle_keys(&mut editor, vec![Key::Char('n')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 8));
+        handle_keys(&mut editor, vec![Key::Char('N')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (3, 3));
+        handle_keys(&mut editor, vec![Key::Char('N')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 1));
+
+        // no other whole word
+        handle_keys(&mut editor, vec![Key::Char('w'), Key::Char('*')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 5));
+        editor.jump_to(3, 2);
+        handle_keys(&mut editor, vec![Key::Char('*')]);
+        assert_eq!(editor.bar_text.line_at(0), "E348: No string under cursor");
+
+        // `gn` and `cgn` select whole words only after `*`
+        let lines = vec!["foo foobar".to_string(), "foobar foo".to_string()];
+        let mut editor = init(lines);
+        let mut keys: Vec<Key> = "*cgnX".chars().map(Key::Char).collect();
+        keys.extend([Key::Esc, Key::Char('.')]);
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "X foobar");
+        assert_eq!(editor.text.line_at(1), "foobar X");
+    }
+
+    #[test]
+    fn wrap_line_test() {
+        assert_eq!(crate::wrap_line("abcdefgh", 3), vec!["abc", "def", "gh"]);
+        assert_eq!(crate::wrap_line("abcdef", 3), vec!["abc", "def"]);
+        assert_eq!(crate::wrap_line("", 3), vec![""]);
+    }
+
+    #[test]
+    fn move_by_display_row_test() {
+        let long_line: String = (0..200).map(|i| (b'a' + i % 26) as char).collect();
+        let mut editor = init(vec![long_line, "world".to_string()]);
+        editor.wrap = true;
+
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('j')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 81));
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('j')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 161));
+        assert_eq!(editor.wrapped_cursor_pos(), (1, 3));
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('j')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (2, 1));
+        assert_eq!(editor.wrapped_cursor_pos(), (1, 4));
+
+        handle_keys(&mut editor, vec![Key::Char('g'), Key::Char('k')]);
+        assert_eq!((editor.cur_line, editor.cur_pos.x), (1, 161));
+        // plain `k`/`j` still move by line
+        handle_keys(&mut editor, vec![Key::Char('j')]);
+        assert_eq!(editor.cur_line, 2);
+    }
+
+    #[test]
+    fn tab_display_col_test() {
+        let mut editor = init(vec!["\tabc".to_string(), "ab\tc".to_string()]);
+
+        handle_keys(&mut editor, vec![Key::Char('l')]);
+        assert_eq!(editor.cur_char(), 'a');
+        assert_eq!(editor.display_width(0, editor.cur_pos.x - 1), 4);
+        handle_keys(&mut editor, vec![Key::Char('j'), Key::Char('$')]);
+        assert_eq!(editor.cur_char(), 'c');
+        assert_eq!(editor.display_width(1, editor.cur_pos.x - 1), 4);
+
+        editor.tab_width = 8;
+        assert_eq!(editor.display_width(0, 1), 8);
+        assert_eq!(editor.expand_tabs("ab\tc"), "ab      c");
+    }
+
+    #[test]
+    fn tab_options_test() {
+        let mut editor = init(vec!["hello".to_string(), "world".to_string()]);
+
+        let keys = vec![Key::Char('i'), Key::Char('\t'), Key::Esc];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "    hello");
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "hello");
+
+        editor.set_option("noexpandtab").unwrap();
+        editor.set_option("tabstop=8").unwrap();
+        assert!(editor.set_option("tabstop=0").is_err());
+        assert!(editor.set_option("nosuchoption").is_err());
+        let keys = vec![Key::Char('i'), Key::Char('\t'), Key::Esc];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "\thello");
+
+        // the cursor on 'h' is drawn after the tab
+        handle_keys(&mut editor, vec![Key::Char('l')]);
+        assert_eq!(editor.cur_char(), 'h');
+        assert_eq!(editor.display_width(0, editor.cur_pos.x - 1), 8);
+
+        handle_keys(&mut editor, vec![Key::Char('u')]);
+        assert_eq!(editor.text.line_at(0), "hello");
+    }
+
+    #[test]
+    fn nomodifiable_test() {
+        let mut editor = init(vec!["hello".to_string(), "world".to_string()]);
+        editor.modifiable = false;
+
+        let keys = vec![Key::Char('x'), Key::Char('d'), Key::Char('d'), Key::Esc];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "hello");
+        assert_eq!(editor.text_length(), 2);
+
+        let keys = vec![Key::Char('i'), Key::Char('a'), Key::Esc];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "hello");
+
+        // even when insert mode is already active
+        let mut mode = Mode::Insert;
+        for key in [Key::Char('a'), Key::Backspace, Key::Delete] {
+            mode = mode.handle(&mut editor, key);
+        }
+        assert_eq!(editor.text.line_at(0), "hello");
+        assert_eq!(editor.bar_text.line_at(0), TextEditor::NOT_MODIFIABLE);
+
+        // folds, macros and registers change no text
+        handle_keys(&mut editor, "zfjzazazMzR".chars().map(Key::Char).collect());
+        assert_eq!(editor.folds, vec![(0, 1, false)]);
+        handle_keys(&mut editor, "qajq@a".chars().map(Key::Char).collect());
+        assert_eq!(editor.registers.recorded('a'), Some(&vec![Key::Char('j')]));
+        assert_eq!(editor.cur_line, 2);
+        editor.clear_message();
+        handle_keys(&mut editor, vec![Key::Char('"'), Key::Char('a'), Key::Esc]);
+        assert_eq!(editor.bar_text.line_at(0), "");
+        handle_keys(&mut editor, "gggJ".chars().map(Key::Char).collect());
+        assert_eq!(editor.text_length(), 2);
+        assert_eq!(editor.bar_text.line_at(0), TextEditor::NOT_MODIFIABLE);
+
+        editor.modifiable = true;
+        handle_keys(&mut editor, vec![Key::Char('x')]);
+        assert_eq!(editor.text.line_at(0), "ello");
+    }
+
+    #[test]
+    fn readonly_test() {
+        let path = std::env::temp_dir().join("vim_rs_readonly_test.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let mut editor = TextEditor::new_from_file(path.to_str().unwrap());
+        assert!(!editor.readonly);
+        editor.set_option("readonly").unwrap();
+
+        let keys = vec![Key::Char('i'), Key::Char('a'), Key::Esc, Key::Char('x')];
+        handle_keys(&mut editor, keys);
+        assert_eq!(editor.text.line_at(0), "hello");
+        assert!(!editor.modified);
+        assert!(editor.status_line().contains(" [RO]"));
+
+        handle_keys(&mut editor, ":w\n".chars().map(Key::Char).collect());
+        assert!(editor.bar_text.line_at(0).starts_with("E45"));
+        editor.text = crate::Text::new();
+        editor.text.push_line("forced".to_string());
+        handle_keys(&mut editor, ":w!\n".chars().map(Key::Char).collect());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "forced");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(undo_file_of(path.to_str().unwrap()));
+    }
 }
@@ -0,0 +1,87 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+// files the index remembers, the ones left the longest ago are forgotten
+const MAX_ENTRIES: usize = 100;
+
+// `~/.config/vimrs/positions`, where the cursor is remembered for the files
+// last edited, one `line col path` per line, the latest at the end
+pub fn index_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/vimrs/positions"))
+}
+
+// files are known by their absolute path, whatever they were opened as
+pub fn absolute_path(file_name: &str) -> String {
+    fs::canonicalize(file_name)
+        .or_else(|_| env::current_dir().map(|dir| dir.join(file_name).components().collect()))
+        .map_or(file_name.to_string(), |path| path.display().to_string())
+}
+
+fn read(index: &Path) -> Vec<(usize, usize, String)> {
+    let contents = fs::read_to_string(index).unwrap_or_default();
+    contents
+        .lines()
+        .filter_map(|entry| {
+            let mut parts = entry.splitn(3, ' ');
+            let line = parts.next()?.parse().ok()?;
+            let col = parts.next()?.parse().ok()?;
+            Some((line, col, parts.next()?.to_string()))
+        })
+        .collect()
+}
+
+// line and column (both from 1) the cursor was at when the file was left
+pub fn load(index: &Path, file_name: &str) -> Option<(usize, usize)> {
+    let key = absolute_path(file_name);
+    read(index)
+        .into_iter()
+        .rev()
+        .find(|(_, _, path)| *path == key)
+        .map(|(line, col, _)| (line, col))
+}
+
+pub fn save(index: &Path, file_name: &str, line: usize, col: usize) -> Result<(), String> {
+    let key = absolute_path(file_name);
+    let mut entries = read(index);
+    entries.retain(|(_, _, path)| *path != key);
+    entries.push((line, col, key));
+    let skip = entries.len().saturating_sub(MAX_ENTRIES);
+    let contents: String = entries[skip..]
+        .iter()
+        .map(|(line, col, path)| format!("{line} {col} {path}\n"))
+        .collect();
+    if let Some(dir) = index.parent() {
+        fs::create_dir_all(dir).map_err(|err| format!("{}: {err}", dir.display()))?;
+    }
+    fs::write(index, contents).map_err(|err| format!("{}: {err}", index.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position_round_trip() {
+        let dir = env::temp_dir().join("vim_rs_positions_test");
+        let index = dir.join("positions");
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(load(&index, "a.txt"), None);
+
+        save(&index, "a.txt", 3, 7).unwrap();
+        save(&index, "dir/b with spaces.txt", 10, 1).unwrap();
+        save(&index, "a.txt", 5, 2).unwrap();
+        assert_eq!(load(&index, "a.txt"), Some((5, 2)));
+        assert_eq!(load(&index, "./a.txt"), Some((5, 2)));
+        assert_eq!(load(&index, "dir/b with spaces.txt"), Some((10, 1)));
+        assert_eq!(fs::read_to_string(&index).unwrap().lines().count(), 2);
+
+        for i in 0..MAX_ENTRIES {
+            save(&index, &format!("{i}.txt"), 1, 1).unwrap();
+        }
+        assert_eq!(load(&index, "a.txt"), None);
+        assert_eq!(load(&index, "0.txt"), Some((1, 1)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
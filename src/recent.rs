@@ -0,0 +1,59 @@
+use crate::positions::absolute_path;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+const MAX_FILES: usize = 20;
+
+// `~/.config/vimrs/recent`, the files last opened or written, one absolute
+// path per line, the latest first
+pub fn list_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/vimrs/recent"))
+}
+
+pub fn load(list: &Path) -> Vec<String> {
+    let contents = fs::read_to_string(list).unwrap_or_default();
+    contents.lines().map(str::to_string).collect()
+}
+
+// move the file to the top of the list, or add it there
+pub fn add(list: &Path, file_name: &str) -> Result<(), String> {
+    let path = absolute_path(file_name);
+    let mut files = load(list);
+    files.retain(|file| *file != path);
+    files.insert(0, path);
+    files.truncate(MAX_FILES);
+    if let Some(dir) = list.parent() {
+        fs::create_dir_all(dir).map_err(|err| format!("{}: {err}", dir.display()))?;
+    }
+    let contents: String = files.iter().map(|file| format!("{file}\n")).collect();
+    fs::write(list, contents).map_err(|err| format!("{}: {err}", list.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_files() {
+        let dir = env::temp_dir().join("vim_rs_recent_test");
+        let list = dir.join("recent");
+        let _ = fs::remove_dir_all(&dir);
+        assert!(load(&list).is_empty());
+
+        for file in ["/a", "/b", "/c", "/a"] {
+            add(&list, file).unwrap();
+        }
+        assert_eq!(load(&list), ["/a", "/c", "/b"]);
+
+        for i in 0..MAX_FILES {
+            add(&list, &format!("/{i}")).unwrap();
+        }
+        let files = load(&list);
+        assert_eq!(files.len(), MAX_FILES);
+        assert_eq!(files[0], format!("/{}", MAX_FILES - 1));
+        assert!(!files.contains(&"/a".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
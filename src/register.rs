@@ -0,0 +1,81 @@
+use std::collections::{HashMap, VecDeque};
+
+use termion::event::Key;
+
+// text yanked or deleted, and whether it is made of whole lines
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Register {
+    pub text: String,
+    pub linewise: bool,
+}
+
+#[derive(Default)]
+pub struct Registers {
+    unnamed: Option<Register>,
+    // `"1` to `"9`, deletes of a line or more, the latest first
+    numbered: VecDeque<Register>,
+    // keys recorded with `q`, by register name
+    recorded: HashMap<char, Vec<Key>>,
+}
+
+impl Registers {
+    pub fn set_unnamed(&mut self, register: Register) {
+        self.unnamed = Some(register);
+    }
+
+    pub fn unnamed(&self) -> Option<&Register> {
+        self.unnamed.as_ref()
+    }
+
+    // deleted text goes into the unnamed register, and when it spans lines
+    // into `"1` as well, shifting the older ones down to `"9`
+    pub fn push_delete(&mut self, register: Register) {
+        if register.linewise || register.text.contains('\n') {
+            self.numbered.push_front(register.clone());
+            self.numbered.truncate(9);
+        }
+        self.unnamed = Some(register);
+    }
+
+    // the register `name` stands for, `"` being the unnamed one
+    pub fn get(&self, name: char) -> Option<&Register> {
+        match name {
+            '"' => self.unnamed(),
+            '1'..='9' => self.numbered.get(name as usize - '1' as usize),
+            _ => None,
+        }
+    }
+
+    pub fn set_recorded(&mut self, name: char, keys: Vec<Key>) {
+        self.recorded.insert(name, keys);
+    }
+
+    pub fn recorded(&self, name: char) -> Option<&Vec<Key>> {
+        self.recorded.get(&name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbered_registers() {
+        let line = |text: &str| Register {
+            text: format!("{text}\n"),
+            linewise: true,
+        };
+        let mut registers = Registers::default();
+        for i in 0..10 {
+            registers.push_delete(line(&i.to_string()));
+        }
+        registers.push_delete(Register {
+            text: "x".to_string(),
+            linewise: false,
+        });
+        assert_eq!(registers.get('"').unwrap().text, "x");
+        assert_eq!(registers.get('1'), Some(&line("9")));
+        assert_eq!(registers.get('9'), Some(&line("1")));
+        assert_eq!(registers.get('a'), None);
+    }
+}
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+// the open files written by `:mksession`, and read back by `:source` or
+// the `-S` flag
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub buffers: Vec<SessionBuffer>,
+    // index of the active buffer in `buffers`
+    pub active: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionBuffer {
+    pub file_name: String,
+    // cursor line and column, both from 1
+    pub line: usize,
+    pub col: usize,
+    // first line (from 0) and column shown
+    pub top: usize,
+    pub left_col: usize,
+}
+
+impl Session {
+    pub fn write(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|err| err.to_string())?;
+        fs::write(path, json).map_err(|err| format!("E482: Can't create file {path}: {err}"))
+    }
+
+    pub fn read(path: &str) -> Result<Self, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|err| format!("E484: Can't open file {path}: {err}"))?;
+        let session: Session =
+            serde_json::from_str(&json).map_err(|err| format!("{path}: not a session: {err}"))?;
+        if session.active >= session.buffers.len() {
+            return Err(format!(
+                "{path}: not a session: no buffer {}",
+                session.active
+            ));
+        }
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_round_trip() {
+        let path = std::env::temp_dir().join("vim_rs_session_round_trip.json");
+        let path = path.to_str().unwrap();
+        let session = Session {
+            buffers: vec![
+                SessionBuffer {
+                    file_name: "a.txt".to_string(),
+                    line: 3,
+                    col: 2,
+                    top: 0,
+                    left_col: 0,
+                },
+                SessionBuffer {
+                    file_name: "src/b.rs".to_string(),
+                    line: 40,
+                    col: 1,
+                    top: 30,
+                    left_col: 4,
+                },
+            ],
+            active: 1,
+        };
+        session.write(path).unwrap();
+        assert_eq!(Session::read(path).unwrap(), session);
+
+        fs::write(path, "{\"buffers\": [], \"active\": 0}").unwrap();
+        assert!(Session::read(path).is_err());
+        fs::remove_file(path).unwrap();
+        assert!(Session::read(path).unwrap_err().starts_with("E484"));
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    ops::Range,
+    path::PathBuf,
+};
+
+// small word lists bundled with the editor, for when there is no other
+const WORD_LISTS: [(&str, &str); 1] = [("en", include_str!("spell/en.txt"))];
+
+// the English word list most systems have
+const SYSTEM_WORDS: &str = "/usr/share/dict/words";
+
+pub struct Spell {
+    lang: String,
+    words: HashSet<String>,
+    // words added with `zg`, saved to `spellfile` when it is set
+    added: HashSet<String>,
+    spellfile: Option<PathBuf>,
+    // where `{lang}.txt` word lists are looked for first
+    dir: Option<PathBuf>,
+    system_words: Option<PathBuf>,
+}
+
+impl Spell {
+    pub fn new() -> Self {
+        let dir = env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/vimrs/spell"));
+        Self::with_lists(dir, Some(PathBuf::from(SYSTEM_WORDS)))
+    }
+
+    fn with_lists(dir: Option<PathBuf>, system_words: Option<PathBuf>) -> Self {
+        let mut spell = Self {
+            lang: String::new(),
+            words: HashSet::new(),
+            added: HashSet::new(),
+            spellfile: None,
+            dir,
+            system_words,
+        };
+        spell.set_lang("en").unwrap();
+        spell
+    }
+
+    // the words of `~/.config/vimrs/spell/{lang}.txt` and, for `en`, of the
+    // system word list. The bundled list is used when neither is there
+    pub fn set_lang(&mut self, lang: &str) -> Result<(), String> {
+        let mut files: Vec<PathBuf> = self
+            .dir
+            .iter()
+            .map(|dir| dir.join(format!("{lang}.txt")))
+            .collect();
+        if lang == "en" {
+            files.extend(self.system_words.clone());
+        }
+        let mut words: HashSet<String> = files
+            .iter()
+            .filter_map(|file| fs::read_to_string(file).ok())
+            .flat_map(|list| {
+                list.lines()
+                    .map(|word| word.trim().to_lowercase())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|word| !word.is_empty())
+            .collect();
+        if words.is_empty() {
+            let Some((_, list)) = WORD_LISTS.iter().find(|(name, _)| *name == lang) else {
+                return Err(format!(
+                    "Warning: Cannot find word list \"{lang}.utf-8.spl\""
+                ));
+            };
+            words = list.lines().map(str::to_string).collect();
+        }
+        self.lang = lang.to_string();
+        self.words = words;
+        Ok(())
+    }
+
+    // words already in the file are loaded, a missing file is created on
+    // the first `zg`
+    pub fn set_spellfile(&mut self, path: &str) -> Result<(), String> {
+        match fs::read_to_string(path) {
+            Ok(contents) => self
+                .added
+                .extend(contents.lines().map(|word| word.to_lowercase())),
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => return Err(format!("E484: Can't open file {path}: {err}")),
+        }
+        self.spellfile = Some(PathBuf::from(path));
+        Ok(())
+    }
+
+    pub fn is_misspelled(&self, word: &str) -> bool {
+        let word = word.to_lowercase();
+        !self.words.contains(&word) && !self.added.contains(&word)
+    }
+
+    // the columns (in chars) of the words of `line` that are misspelled
+    pub fn misspelled_words(&self, line: &str) -> Vec<Range<usize>> {
+        let mut words = vec![];
+        let mut word = String::new();
+        let chars: Vec<char> = line.chars().collect();
+        for col in 0..=chars.len() {
+            let c = chars.get(col).copied().unwrap_or(' ');
+            // an apostrophe between letters, as in `don't`, is in the word
+            let apostrophe = c == '\''
+                && !word.is_empty()
+                && chars.get(col + 1).is_some_and(|c| c.is_alphabetic());
+            if c.is_alphabetic() || apostrophe {
+                word.push(c);
+                continue;
+            }
+            if !word.is_empty() && self.is_misspelled(&word) {
+                words.push(col - word.chars().count()..col);
+            }
+            word.clear();
+        }
+        words
+    }
+
+    pub fn add_word(&mut self, word: &str) -> Result<(), String> {
+        if !self.added.insert(word.to_lowercase()) {
+            return Ok(());
+        }
+        let Some(path) = &self.spellfile else {
+            return Ok(());
+        };
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{word}"))
+            .map_err(|err| format!("E482: Can't create file {}: {err}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spelllang() {
+        let mut spell = Spell::with_lists(None, None);
+        assert!(!spell.is_misspelled("The"));
+        assert!(spell.is_misspelled("teh"));
+        assert_eq!(spell.misspelled_words("Teh word, teh"), vec![0..3, 10..13]);
+        assert!(spell.set_lang("xx").is_err());
+        assert_eq!(spell.lang, "en");
+    }
+
+    #[test]
+    fn word_lists() {
+        let dir = std::env::temp_dir().join("vim_rs_word_lists_test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("en.txt"), "hello\nDon't\n").unwrap();
+        fs::write(dir.join("xx.txt"), "bonjour\n").unwrap();
+        fs::write(dir.join("words"), "world\n").unwrap();
+
+        // the user and system lists instead of the bundled one
+        let mut spell = Spell::with_lists(Some(dir.clone()), Some(dir.join("words")));
+        assert!(!spell.is_misspelled("Hello"));
+        assert!(!spell.is_misspelled("world"));
+        assert!(spell.is_misspelled("the"));
+        assert_eq!(spell.misspelled_words("don't 'hello' the"), vec![14..17]);
+        spell.set_lang("xx").unwrap();
+        assert!(!spell.is_misspelled("bonjour"));
+        assert!(spell.is_misspelled("hello"));
+
+        // the bundled list only when there is none
+        let spell = Spell::with_lists(Some(dir.join("missing")), Some(dir.join("missing")));
+        assert!(!spell.is_misspelled("the"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spellfile() {
+        let path = std::env::temp_dir().join("vim_rs_spellfile_test.add");
+        let _ = fs::remove_file(&path);
+        let mut spell = Spell::new();
+        spell.set_spellfile(path.to_str().unwrap()).unwrap();
+        spell.add_word("rustacean").unwrap();
+        assert!(!spell.is_misspelled("Rustacean"));
+
+        let mut spell = Spell::new();
+        assert!(spell.is_misspelled("rustacean"));
+        spell.set_spellfile(path.to_str().unwrap()).unwrap();
+        assert!(!spell.is_misspelled("rustacean"));
+        fs::remove_file(&path).unwrap();
+    }
+}
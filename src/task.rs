@@ -6,7 +6,7 @@ pub struct Task {
 }
 
 impl Task {
-    const MOVEMENT: [Key; 13] = [
+    const MOVEMENT: [Key; 16] = [
         Key::Char('j'),
         Key::Char('k'),
         Key::Char('h'),
@@ -14,6 +14,9 @@ impl Task {
         Key::Char('e'),
         Key::Char('w'),
         Key::Char('b'),
+        Key::Char('E'),
+        Key::Char('W'),
+        Key::Char('B'),
         Key::Char(' '),
         Key::Backspace,
         Key::Left,
@@ -40,6 +43,9 @@ impl Task {
         }
         Some(s)
     }
+    pub fn ends_with(&self, s: &str) -> bool {
+        self.to_string().ends_with(s)
+    }
     pub fn last_task(&self) -> Option<&Key> {
         self.tasks.last()
     }
@@ -62,7 +68,15 @@ impl Task {
                 s.push(c);
             }
         });
-        usize::from_str_radix(&s, 10).ok()
+        s.parse().ok()
+    }
+    // nothing but a count, as `3` before `3i`
+    pub fn is_count(&self) -> bool {
+        self.len() > 0
+            && self
+                .tasks
+                .iter()
+                .all(|key| matches!(key, Key::Char('0'..='9')))
     }
     pub fn clear(&mut self) {
         self.tasks.clear();
@@ -74,17 +88,22 @@ impl Task {
         false
     }
 
+    // the register named after `key`, as `a` in `qa` or `3@a`
+    pub fn register_after(&self, key: char) -> Option<char> {
+        match self.tasks[..] {
+            [.., Key::Char(k), Key::Char(name)] if k == key => Some(name),
+            _ => None,
+        }
+    }
+
     fn iter<F>(&self, mut f: F)
     where
-        F: FnMut(char) -> (),
+        F: FnMut(char),
     {
         for task in &self.tasks {
-            match task {
-                Key::Char(c) => {
-                    f(*c);
-                }
-                _ => {}
-            };
+            if let Key::Char(c) = task {
+                f(*c);
+            }
         }
     }
 }
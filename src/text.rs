@@ -4,22 +4,64 @@ pub struct Text {
     lines: Vec<String>,
 }
 
+// how the lines of a file are ended, kept when it is written back
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    // the ending most lines of `contents` have
+    pub fn detect(contents: &str) -> Self {
+        let crlf = contents.matches("\r\n").count();
+        if crlf > 0 && crlf * 2 >= contents.matches('\n').count() {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+}
+
+// byte offset of char column `col` in `line`, its end past the last char
+fn byte_index(line: &str, col: usize) -> usize {
+    line.char_indices()
+        .nth(col)
+        .map_or(line.len(), |(idx, _)| idx)
+}
+
 impl Text {
     pub fn new() -> Self {
         Self { lines: vec![] }
     }
-    pub fn char_at(&mut self, x: usize, y: usize) -> char {
-        if x >= self.lines.len() || self.lines[x].len() == 0 || y >= self.lines[x].len() {
-            return 0 as char;
+    // index of line `x`, or of the last line past the end. A text without
+    // any line, as a new one, gets an empty line first
+    fn clamp_line(&mut self, x: usize) -> usize {
+        if self.lines.is_empty() {
+            self.lines.push(String::new());
         }
-        self.lines[x].chars().nth(y).unwrap()
+        x.min(self.lines.len() - 1)
+    }
+    pub fn char_at(&mut self, x: usize, y: usize) -> char {
+        self.lines
+            .get(x)
+            .and_then(|line| line.chars().nth(y))
+            .unwrap_or(0 as char)
     }
     pub fn insert_at(&mut self, x: usize, y: usize, c: char) {
-        let x = x.min(self.lines.len() - 1);
-        let y = y.min(self.lines[x].len());
+        let x = self.clamp_line(x);
         #[cfg(test)]
         println!("insert c={c} at x={x}, y={y}");
-        self.lines[x].insert(y, c)
+        let idx = byte_index(&self.lines[x], y);
+        self.lines[x].insert(idx, c)
     }
 
     pub fn len(&self) -> usize {
@@ -27,58 +69,92 @@ impl Text {
     }
 
     pub fn delete_line_at(&mut self, x: usize) -> String {
+        if self.lines.is_empty() {
+            return String::new();
+        }
         let x = x.min(self.lines.len() - 1);
         self.lines.remove(x)
     }
     pub fn append_str_at(&mut self, x: usize, y: usize, s: String) {
-        let x = x.min(self.lines.len() - 1);
-        let y = y.min(self.lines[x].len());
-        self.lines[x].insert_str(y, &s);
+        let x = self.clamp_line(x);
+        let idx = byte_index(&self.lines[x], y);
+        self.lines[x].insert_str(idx, &s);
     }
-    pub fn delete_at(&mut self, x: usize, y: usize) -> Option<char> {
-        let x = x.min(self.lines.len() - 1);
-        let y = y.min(self.lines[x].len());
-        let y = 0.max(y - 1);
-        if self.lines[x].len() > 0 {
-            return Some(self.lines[x].remove(y));
+    // insert `s` at char column `y`, padding the line with spaces first
+    // when it is shorter than `y` (virtual editing past the end of line)
+    pub fn insert_str_padded(&mut self, x: usize, y: usize, s: &str) {
+        let x = self.clamp_line(x);
+        let len = self.lines[x].chars().count();
+        if y > len {
+            self.lines[x].push_str(&" ".repeat(y - len));
         }
-        None
+        let idx = byte_index(&self.lines[x], y);
+        self.lines[x].insert_str(idx, s);
     }
+    // remove `len` chars starting at char column `y`, returning them
+    pub fn remove_str_at(&mut self, x: usize, y: usize, len: usize) -> String {
+        let line = &mut self.lines[x];
+        let start = byte_index(line, y);
+        let end = start + byte_index(&line[start..], len);
+        line.drain(start..end).collect()
+    }
+    // cut line `x` at char column `col`, returning the removed tail
+    pub fn truncate_line_at(&mut self, x: usize, col: usize) -> String {
+        let idx = byte_index(&self.lines[x], col);
+        self.lines[x].split_off(idx)
+    }
+    // remove the char at column `y` (from 0) of line `x` and return it, None
+    // when the line is shorter than that
+    pub fn delete_at(&mut self, x: usize, y: usize) -> Option<char> {
+        let x = x.min(self.lines.len().checked_sub(1)?);
+        let (idx, _) = self.lines[x].char_indices().nth(y)?;
+        Some(self.lines[x].remove(idx))
+    }
+    // remove the chars from `start` to `end`, both included, as
+    // `text_in_range` reads them. An end past its line stops at the last
+    // char, so lines are only removed by joining them. Deleting whole lines
+    // is up to the caller
     pub fn delete_range(&mut self, start: Coordinates, end: Coordinates) -> String {
-        let former: String;
-        let latter: String;
-        let mut deleted: String = String::new();
-        if start.x == end.x {
-            assert!(start.y <= end.y);
-            let text = self.lines[start.x].clone();
-            former = text[0..start.y].to_string();
-            latter = text[end.y + 1..].to_string();
-            deleted = text[start.y..end.y + 1].to_string();
-        } else {
-            assert!(start.x < end.x);
-            for i in (start.x + 1..end.x).into_iter().rev() {
-                deleted.push_str(&self.delete_line_at(i));
-                deleted.push('\n');
-            }
-            former = (&self.lines[start.x].clone()[0..start.y]).to_string();
-            latter = (&self.lines[start.x + 1].clone()[end.y + 1..]).to_string();
-            deleted.push_str(&self.lines[start.x][start.y..]);
-            deleted.push('\n');
-            deleted.push_str(&self.lines[start.x + 1][..end.y + 1]);
-            self.delete_line_at(start.x + 1);
+        assert!(start.x < end.x || start.x == end.x && start.y <= end.y);
+        if start.x >= self.lines.len() {
+            return String::new();
         }
-        if former.len() == 0 && latter.len() == 0 {
-            self.delete_line_at(start.x);
-            deleted.push('\n');
-        } else {
-            self.lines[start.x] = former + &latter;
+        let end_x = end.x.min(self.lines.len() - 1);
+        if start == end {
+            return self
+                .delete_at(start.x, start.y)
+                .map_or(String::new(), String::from);
+        }
+        let deleted = self.text_in_range(start, end);
+        let latter = self.truncate_line_at(end_x, end.y + 1);
+        for _ in start.x..end_x {
+            self.delete_line_at(start.x + 1);
         }
+        self.truncate_line_at(start.x, start.y);
+        self.lines[start.x].push_str(&latter);
         deleted
     }
 
+    pub fn text_in_range(&self, start: Coordinates, end: Coordinates) -> String {
+        let mut text = String::new();
+        for x in start.x..=end.x.min(self.lines.len() - 1) {
+            let from = if x == start.x { start.y } else { 0 };
+            let chars = self.lines[x].chars().skip(from);
+            if x == end.x {
+                text.extend(chars.take((end.y + 1).saturating_sub(from)));
+            } else {
+                text.extend(chars);
+                text.push('\n');
+            }
+        }
+        text
+    }
+
     pub fn len_of_line_at(&self, line: usize) -> usize {
-        let line = line.min(self.lines.len() - 1);
-        self.lines[line].len()
+        let Some(last) = self.lines.len().checked_sub(1) else {
+            return 0;
+        };
+        self.lines[line.min(last)].chars().count()
     }
     pub fn line_at(&self, line: usize) -> String {
         if line >= self.lines.len() {
@@ -88,10 +164,8 @@ impl Text {
     }
 
     pub fn new_line_at(&mut self, x: usize, index: usize) {
-        let x = x.min(self.lines.len() - 1);
-        let index = index.min(self.lines[x].len());
-        let latter = self.lines[x][index..].to_string();
-        self.lines[x].truncate(index);
+        let x = self.clamp_line(x);
+        let latter = self.truncate_line_at(x, index);
         self.add_line_before(x + 1, latter);
     }
 
@@ -99,20 +173,148 @@ impl Text {
         self.lines.push(content);
     }
 
-    pub fn to_string(&self) -> String {
-        self.lines.join("\n")
-    }
-
     pub fn pop_char_at_line(&mut self, line: usize) -> Option<char> {
         self.lines[line].pop()
     }
     pub fn push_char_at_line(&mut self, line: usize, c: char) {
-        if self.lines.len() == 0 {
-            self.lines.push("".to_string());
-        }
+        let line = self.clamp_line(line);
         self.lines[line].push(c)
     }
 
+    // first match of `pattern` at or after `from` (x is the line, y is the
+    // char column); with `wrap` the search continues from the top of the
+    // text and ends right before `from`
+    pub fn find(
+        &self,
+        from: Coordinates,
+        pattern: &str,
+        case_insensitive: bool,
+        wrap: bool,
+    ) -> Option<Coordinates> {
+        let n = self.lines.len();
+        if pattern.is_empty() || from.x >= n {
+            return None;
+        }
+        let steps = if wrap { n + 1 } else { n - from.x };
+        for i in 0..steps {
+            let line = (from.x + i) % n;
+            let col = self
+                .matches_in_line(line, pattern, case_insensitive)
+                .into_iter()
+                .find(|&col| match i {
+                    0 => col >= from.y,
+                    _ if i == n => col < from.y,
+                    _ => true,
+                });
+            if let Some(col) = col {
+                return Some(Coordinates { x: line, y: col });
+            }
+        }
+        None
+    }
+
+    // last match of `pattern` at or before `from`, see `find`
+    pub fn rfind(
+        &self,
+        from: Coordinates,
+        pattern: &str,
+        case_insensitive: bool,
+        wrap: bool,
+    ) -> Option<Coordinates> {
+        let n = self.lines.len();
+        if pattern.is_empty() || from.x >= n {
+            return None;
+        }
+        let steps = if wrap { n + 1 } else { from.x + 1 };
+        for i in 0..steps {
+            let line = (from.x + n - i) % n;
+            let col = self
+                .matches_in_line(line, pattern, case_insensitive)
+                .into_iter()
+                .rev()
+                .find(|&col| match i {
+                    0 => col <= from.y,
+                    _ if i == n => col > from.y,
+                    _ => true,
+                });
+            if let Some(col) = col {
+                return Some(Coordinates { x: line, y: col });
+            }
+        }
+        None
+    }
+
+    // `(` and `)`: the start of the sentence before or after `from`. A
+    // sentence ends at `.`, `!` or `?`, maybe followed by closing brackets
+    // and quotes, and then by a blank or the end of the line. Empty lines are
+    // sentences of their own
+    pub fn sentence_boundary(&self, from: Coordinates, forward: bool) -> Option<Coordinates> {
+        let from = (from.x, from.y);
+        let mut found = None;
+        // the next char that is not blank starts a sentence
+        let mut starts = true;
+        // right after the end of a sentence, which the next blank confirms
+        let mut ended = false;
+        'lines: for (x, line) in self.lines.iter().enumerate() {
+            let mut at = vec![];
+            if line.is_empty() {
+                at.push(0);
+                starts = true;
+            }
+            for (y, c) in line.chars().enumerate() {
+                if c == ' ' || c == '\t' {
+                    starts |= ended;
+                    ended = false;
+                    continue;
+                }
+                if starts {
+                    at.push(y);
+                    starts = false;
+                }
+                ended =
+                    matches!(c, '.' | '!' | '?') || ended && matches!(c, ')' | ']' | '"' | '\'');
+            }
+            starts |= ended;
+            ended = false;
+            for y in at {
+                match forward {
+                    true if (x, y) > from => {
+                        found = Some(Coordinates { x, y });
+                        break 'lines;
+                    }
+                    false if (x, y) < from => found = Some(Coordinates { x, y }),
+                    false => break 'lines,
+                    true => (),
+                }
+            }
+        }
+        found
+    }
+
+    // char columns of all (possibly overlapping) matches in the line
+    fn matches_in_line(&self, line: usize, pattern: &str, case_insensitive: bool) -> Vec<usize> {
+        let eq = |a: &char, b: &char| {
+            if case_insensitive {
+                a.to_lowercase().eq(b.to_lowercase())
+            } else {
+                a == b
+            }
+        };
+        let chars: Vec<char> = self.lines[line].chars().collect();
+        let pattern: Vec<char> = pattern.chars().collect();
+        if pattern.len() > chars.len() {
+            return vec![];
+        }
+        (0..=chars.len() - pattern.len())
+            .filter(|&i| {
+                chars[i..i + pattern.len()]
+                    .iter()
+                    .zip(&pattern)
+                    .all(|(a, b)| eq(a, b))
+            })
+            .collect()
+    }
+
     // idx start from 0
     pub fn add_line_before(&mut self, idx: usize, content: String) {
         if idx > self.lines.len() {
@@ -122,10 +324,113 @@ impl Text {
     }
 }
 
+impl Text {
+    // the contents of a file, with a line ending after the last line too
+    // when `eol`
+    pub fn to_file_contents(&self, ending: LineEnding, eol: bool) -> String {
+        let mut contents = self.lines.join(ending.as_str());
+        if eol {
+            contents.push_str(ending.as_str());
+        }
+        contents
+    }
+}
+
+impl std::fmt::Display for Text {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.lines.join("\n"))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn line_ending() {
+        assert_eq!(LineEnding::detect("a\r\nb\r\nc\n"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\nb\nc\r\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a"), LineEnding::Lf);
+        let mut text = Text::new();
+        text.push_line("a".to_string());
+        text.push_line("b".to_string());
+        assert_eq!(text.to_file_contents(LineEnding::Crlf, true), "a\r\nb\r\n");
+        assert_eq!(text.to_file_contents(LineEnding::Lf, false), "a\nb");
+    }
+
+    #[test]
+    fn sentence_boundary() {
+        let mut text = Text::new();
+        for line in [
+            "One. Two! Three?",
+            "  (Four.) Five",
+            "six... seven.",
+            "",
+            "Eight",
+        ] {
+            text.push_line(line.to_string());
+        }
+        let at = |x, y| Coordinates { x, y };
+        let mut starts = vec![];
+        let mut from = at(0, 0);
+        while let Some(next) = text.sentence_boundary(from, true) {
+            starts.push((next.x, next.y));
+            from = next;
+        }
+        assert_eq!(
+            starts,
+            vec![(0, 5), (0, 10), (1, 2), (1, 10), (2, 7), (3, 0), (4, 0)]
+        );
+        assert_eq!(text.sentence_boundary(at(1, 12), false), Some(at(1, 10)));
+        assert_eq!(text.sentence_boundary(at(1, 10), false), Some(at(1, 2)));
+        assert_eq!(text.sentence_boundary(at(0, 3), false), Some(at(0, 0)));
+        assert_eq!(text.sentence_boundary(at(0, 0), false), None);
+    }
+
+    #[test]
+    fn empty_text() {
+        let mut text = Text::new();
+        assert_eq!(text.len_of_line_at(0), 0);
+        assert_eq!(text.delete_at(0, 0), None);
+        assert_eq!(text.delete_line_at(0), "");
+        assert_eq!(text.len(), 0);
+        text.insert_at(0, 0, 'a');
+        assert_eq!(text.len(), 1);
+        assert_eq!(text.line_at(0), "a");
+        assert_eq!(text.len_of_line_at(0), 1);
+
+        let mut text = Text::new();
+        text.new_line_at(0, 0);
+        assert_eq!(text.len(), 2);
+        assert_eq!(
+            (text.line_at(0), text.line_at(1)),
+            ("".to_string(), "".to_string())
+        );
+
+        let mut text = Text::new();
+        text.append_str_at(3, 2, "ab".to_string());
+        text.insert_str_padded(0, 3, "c");
+        assert_eq!(text.line_at(0), "ab c");
+    }
+
+    #[test]
+    fn delete_at() {
+        let mut text = Text {
+            lines: vec!["abcd".to_string(), "".to_string(), "aéb".to_string()],
+        };
+        assert_eq!(text.delete_at(0, 0), Some('a'));
+        assert_eq!(text.line_at(0), "bcd");
+        assert_eq!(text.delete_at(0, 1), Some('c'));
+        assert_eq!(text.line_at(0), "bd");
+        assert_eq!(text.delete_at(0, 1), Some('d'));
+        assert_eq!(text.line_at(0), "b");
+        assert_eq!(text.delete_at(0, 1), None);
+        assert_eq!(text.line_at(0), "b");
+        assert_eq!(text.delete_at(1, 0), None);
+        assert_eq!(text.delete_at(2, 1), Some('é'));
+        assert_eq!(text.line_at(2), "ab");
+    }
+
     #[test]
     fn insert_basic() {
         let lines = vec!["hello".to_string(), "world".to_string()];
@@ -138,8 +443,8 @@ mod tests {
         }
         assert_eq!(text.line_at(0), "Annya hello".to_string());
         assert_eq!(text.line_at(1), "world and happy every day!".to_string());
-        for i in 0..21 {
-            text.delete_at(1, 6 + i);
+        for _ in 0..21 {
+            text.delete_at(1, 5);
         }
         assert_eq!(text.line_at(1), "world".to_string());
         text.append_str_at(1, 5, " and happy everyday!".to_string());
@@ -149,6 +454,18 @@ mod tests {
         assert_eq!(text.char_at(1, 0), 0 as char);
     }
 
+    #[test]
+    fn insert_padded() {
+        let lines = vec!["hello".to_string(), "hi".to_string()];
+        let mut text = Text { lines };
+        text.insert_str_padded(0, 4, "X");
+        assert_eq!(text.line_at(0), "hellXo");
+        text.insert_str_padded(1, 4, "X");
+        assert_eq!(text.line_at(1), "hi  X");
+        text.insert_str_padded(1, 0, "");
+        assert_eq!(text.line_at(1), "hi  X");
+    }
+
     #[test]
     fn new_line() {
         let lines = vec!["hello".to_string(), "world".to_string()];
@@ -157,4 +474,94 @@ mod tests {
         assert_eq!(text.line_at(1), "wo");
         assert_eq!(text.line_at(2), "rld");
     }
+
+    #[test]
+    fn delete_range() {
+        let at = |x, y| Coordinates { x, y };
+        let mut text = Text {
+            lines: vec!["héllo".to_string(), "big".to_string(), "wörld".to_string()],
+        };
+        assert_eq!(text.delete_range(at(0, 1), at(0, 3)), "éll");
+        assert_eq!(text.to_string(), "ho\nbig\nwörld");
+        assert_eq!(text.delete_range(at(0, 1), at(2, 1)), "o\nbig\nwö");
+        assert_eq!(text.to_string(), "hrld");
+        // an end past the line stops at its last char
+        assert_eq!(text.delete_range(at(0, 2), at(0, 9)), "ld");
+        assert_eq!(text.to_string(), "hr");
+        // the last char alone
+        assert_eq!(text.delete_range(at(0, 1), at(0, 1)), "r");
+        assert_eq!(text.to_string(), "h");
+
+        let mut text = Text {
+            lines: vec!["añb".to_string(), "ü".to_string()],
+        };
+        assert_eq!(text.delete_range(at(0, 1), at(0, 1)), "ñ");
+        assert_eq!(text.delete_range(at(0, 1), at(1, 0)), "b\nü");
+        assert_eq!(text.to_string(), "a");
+
+        let mut text = Text {
+            lines: vec!["a".to_string(), "".to_string(), "b".to_string()],
+        };
+        // nothing to delete in an empty line
+        assert_eq!(text.delete_range(at(1, 0), at(1, 0)), "");
+        assert_eq!(text.to_string(), "a\n\nb");
+        assert_eq!(text.delete_range(at(0, 0), at(1, 0)), "a\n");
+        assert_eq!(text.to_string(), "\nb");
+        // a whole line leaves an empty one
+        assert_eq!(text.delete_range(at(1, 0), at(1, 0)), "b");
+        assert_eq!(text.delete_range(at(0, 0), at(1, 0)), "\n");
+        assert_eq!(text.to_string(), "");
+        assert_eq!(text.len(), 1);
+    }
+
+    #[test]
+    fn text_in_range() {
+        let lines = vec!["hello".to_string(), "big".to_string(), "world".to_string()];
+        let text = Text { lines };
+        let at = |x, y| Coordinates { x, y };
+        assert_eq!(text.text_in_range(at(0, 1), at(0, 3)), "ell");
+        assert_eq!(text.text_in_range(at(0, 3), at(2, 1)), "lo\nbig\nwo");
+        assert_eq!(text.text_in_range(at(1, 1), at(1, 5)), "ig");
+        assert_eq!(text.text_in_range(at(1, 4), at(1, 5)), "");
+    }
+
+    #[test]
+    fn find() {
+        let lines = vec!["foo bar".to_string(), "".to_string(), "Bar foo".to_string()];
+        let text = Text { lines };
+        let at = |x, y| Coordinates { x, y };
+
+        assert_eq!(text.find(at(0, 0), "foo", false, false), Some(at(0, 0)));
+        assert_eq!(text.find(at(0, 1), "bar", false, false), Some(at(0, 4)));
+        // continues on the following lines
+        assert_eq!(text.find(at(0, 1), "foo", false, false), Some(at(2, 4)));
+        assert_eq!(text.find(at(0, 5), "bar", false, false), None);
+        assert_eq!(text.find(at(0, 5), "bar", true, false), Some(at(2, 0)));
+        assert_eq!(text.find(at(2, 5), "foo", false, false), None);
+        assert_eq!(text.find(at(2, 5), "foo", false, true), Some(at(0, 0)));
+        assert_eq!(text.find(at(0, 0), "", false, true), None);
+    }
+
+    #[test]
+    fn rfind() {
+        let lines = vec!["foo bar".to_string(), "".to_string(), "Bar foo".to_string()];
+        let text = Text { lines };
+        let at = |x, y| Coordinates { x, y };
+
+        assert_eq!(text.rfind(at(2, 4), "foo", false, false), Some(at(2, 4)));
+        assert_eq!(text.rfind(at(2, 3), "foo", false, false), Some(at(0, 0)));
+        assert_eq!(text.rfind(at(2, 6), "bar", false, false), Some(at(0, 4)));
+        assert_eq!(text.rfind(at(2, 6), "bar", true, false), Some(at(2, 0)));
+        assert_eq!(text.rfind(at(0, 3), "bar", false, false), None);
+        assert_eq!(text.rfind(at(0, 3), "bar", false, true), Some(at(0, 4)));
+    }
+
+    #[test]
+    fn find_multibyte() {
+        let lines = vec!["héllo héllo".to_string()];
+        let text = Text { lines };
+        let at = |x, y| Coordinates { x, y };
+        assert_eq!(text.find(at(0, 1), "héllo", false, false), Some(at(0, 6)));
+        assert_eq!(text.find(at(0, 0), "HÉ", true, false), Some(at(0, 0)));
+    }
 }